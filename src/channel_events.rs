@@ -1,33 +1,220 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use std::sync::mpsc::Sender;
+use serde::{Deserialize, Serialize};
 
-use crate::protocol::Packet;
-use crate::shared::RoutingTableEntry;
+use crate::presence::Presence;
+use crate::protocol::broadcast_packet::BroadcastPacket;
+use crate::protocol::{Packet, RoutingType};
+use crate::rtt::RttEstimate;
+use crate::shared::{RoutingTableEntry, Tx};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Commands {
     Connect(SocketAddr),
     Contacts,
     Message(SocketAddr, String),
+    MessageByNick(String, String), //nickname, message
     Quit,
     Help,
     Unknown(String),
     SetOwnNick(String),
-    Broadcast(String)
+    Broadcast(String),
+    JoinRoom(String),
+    LeaveRoom(String),
+    Say(String, String), //room, message
+    SendFile(SocketAddr, PathBuf),
+    AcceptFile(u64),
+    DeclineFile(u64),
+    SetPresence(Presence),
+    /// We are typing a message to this node
+    Typing(SocketAddr),
+    Trace(SocketAddr),
+    Ping(SocketAddr),
 }
 
-#[derive(Debug, Clone)]
+/// Only the events meant for a user interface can be serialized, the others never leave the process.
+#[derive(Debug, Clone, Serialize)]
 pub enum ChannelEvent {
     Join(String), //current thoughts: Terminal output for Join and Leave only in console(if not when initially receiving the message)
     Leave(String),
-    Message(String, SocketAddr), //message, destination
-    Routing(u8),                 //type id
+    #[serde(skip)]
+    Message(String, SocketAddr, u64, Option<String>), //message, destination, id, room
+    #[serde(skip)]
+    Routing(RoutingType),
+    /// Send a STU unless the neighbour already has the routes it would advertise
+    #[serde(skip)]
+    TriggeredUpdate,
+    #[serde(skip)]
     Forward(Packet),
+    /// Pass a broadcast on to the neighbour
+    #[serde(skip)]
+    Flood(BroadcastPacket),
     Command(Commands),
     Contacts(HashMap<SocketAddr, RoutingTableEntry>),
-    CommandReceiver(Sender<ChannelEvent>),
-    MessageToTUI(String, String, SocketAddr), //message, sender, destination
+    Keys(String, HashMap<SocketAddr, String>), //own fingerprint, fingerprints of the contacts
+    Nicknames(HashMap<SocketAddr, String>),
+    Presences(Presence, HashMap<SocketAddr, Presence>), //own presence, presences of the contacts
+    #[serde(skip)]
+    CommandReceiver(Tx),
+    MessageToTUI(String, String, SocketAddr, bool), //message, sender, source, encrypted
+    RoomMessage(String, String, String, SocketAddr, bool), //room, message, sender, source, encrypted
+    Broadcast(String, String, SocketAddr),                 //message, sender, origin
+    Typing(String, SocketAddr),                            //sender, source
+    Trace(u64, SocketAddr, Vec<TraceHop>, Duration),       //id, destination, hops, round trip
+    Ping(SocketAddr, Result<Duration, String>), //destination, round trip or why there is none
+    Rtts(HashMap<SocketAddr, RttEstimate>),
+    MessageSent(u64, SocketAddr, String, bool), //id, destination, message, encrypted
+    Delivery(u64, DeliveryStatus),
+    FileOffer(u64, String, u64, SocketAddr), //id, name, size, source
+    FileProgress(u64, String, u64, u64, SocketAddr), //id, name, bytes, size, peer
+    FileDone(u64, String, FileStatus, SocketAddr), //id, name, status, peer
     LogToTerminal(String),
 }
+
+/// Events a `Node` publishes to its subscribers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeEvent {
+    /// A direct peer connected to us.
+    Join(SocketAddr),
+    /// A direct peer disconnected.
+    Leave(SocketAddr),
+    /// A chat message addressed to us arrived.
+    Message {
+        source: SocketAddr,
+        nickname: String,
+        message: String,
+        /// It was encrypted to us
+        encrypted: bool,
+        /// The room it was said in, `None` if it was sent to us alone
+        room: Option<String>,
+    },
+    /// Someone is typing a message to us, sent every few seconds while they are at it.
+    Typing {
+        source: SocketAddr,
+        nickname: String,
+    },
+    /// A broadcast reached us, it's sent to every node and never acknowledged.
+    Broadcast {
+        origin: SocketAddr,
+        nickname: String,
+        message: String,
+    },
+    /// We sent a chat message, its delivery is pending until it's acknowledged.
+    Sent {
+        id: u64,
+        dest: SocketAddr,
+        message: String,
+        /// We know the key of the destination, so it's encrypted
+        encrypted: bool,
+        /// The room it was said in
+        room: Option<String>,
+    },
+    /// A chat message we sent was acknowledged or given up on.
+    Delivery {
+        id: u64,
+        dest: SocketAddr,
+        status: DeliveryStatus,
+    },
+    /// The answer to a ping of ours arrived, see `Node::ping`.
+    Pong { source: SocketAddr, id: u64 },
+    /// The answer to a traceroute probe of ours, see `Node::trace`.
+    Trace {
+        dest: SocketAddr,
+        id: u64,
+        /// From us to the destination, both included
+        hops: Vec<TraceHop>,
+        round_trip: Duration,
+    },
+    /// Someone would like to send us a file, see `Node::accept_file`.
+    FileOffer {
+        source: SocketAddr,
+        id: u64,
+        name: String,
+        size: u64,
+    },
+    /// More of a file made it to the receiver, `peer` is the other end of the transfer.
+    FileProgress {
+        peer: SocketAddr,
+        id: u64,
+        name: String,
+        bytes: u64,
+        size: u64,
+    },
+    /// A file transfer is over.
+    FileDone {
+        peer: SocketAddr,
+        id: u64,
+        name: String,
+        status: FileStatus,
+    },
+    /// Something noteworthy happened that a user interface might want to display.
+    Log(String),
+}
+
+/// What became of a chat message we sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryStatus {
+    /// The destination acknowledged it
+    Delivered,
+    /// No ACK arrived, even after every retransmission
+    Failed,
+}
+
+/// A node on the path of a traceroute probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceHop {
+    pub addr: SocketAddr,
+    /// Time since the hop before, estimated from the clocks of both nodes
+    pub latency: Duration,
+}
+
+/// What became of a file transfer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileStatus {
+    /// The receiver has the whole file
+    Sent,
+    /// We have the whole file, stored at this path
+    Received(PathBuf),
+    Failed(String),
+}
+
+#[test]
+fn test_commands_json() {
+    let cmd: Commands =
+        serde_json::from_str(r#"{"Message":["127.0.0.1:6143","hello there"]}"#).unwrap();
+    assert_eq!(
+        cmd,
+        Commands::Message(
+            "127.0.0.1:6143".parse::<SocketAddr>().unwrap(),
+            "hello there".to_string()
+        )
+    );
+    let cmd: Commands = serde_json::from_str(r#""Contacts""#).unwrap();
+    assert_eq!(cmd, Commands::Contacts);
+}
+
+#[test]
+fn test_ui_events_json() {
+    let addr = "127.0.0.1:6143".parse::<SocketAddr>().unwrap();
+    let mut contacts = HashMap::new();
+    contacts.insert(addr, RoutingTableEntry::new(addr, 1));
+    assert_eq!(
+        serde_json::to_string(&ChannelEvent::Contacts(contacts)).unwrap(),
+        r#"{"Contacts":{"127.0.0.1:6143":{"next":"127.0.0.1:6143","hop_count":1,"dont_expire":false}}}"#
+    );
+    assert_eq!(
+        serde_json::to_string(&ChannelEvent::MessageToTUI(
+            "hi".to_string(),
+            "alice".to_string(),
+            addr,
+            true
+        ))
+        .unwrap(),
+        r#"{"MessageToTUI":["hi","alice","127.0.0.1:6143",true]}"#
+    );
+    // Internal events can't be written out
+    assert!(serde_json::to_string(&ChannelEvent::Routing(RoutingType::SCC)).is_err());
+}
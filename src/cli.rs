@@ -0,0 +1,352 @@
+use clap::Parser;
+use rnp2::crypto::SignaturePolicy;
+use rnp2::queue::OverflowPolicy;
+use rnp2::shared::SplitHorizon;
+use rnp2::NodeConfig;
+use serde::Deserialize;
+use tracing::Level;
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Morganite, a chat client for the HAW-RN pseudo chat protocol
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Addresses to listen on, e.g. one IPv4 and one IPv6 address [default: 127.0.0.1:6142]
+    pub bind: Vec<String>,
+    /// TOML configuration file, flags given here override its values
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Nickname attached to outgoing messages
+    #[arg(short, long)]
+    pub nickname: Option<String>,
+    /// Peer to connect to on startup, can be given multiple times
+    #[arg(short, long = "peer")]
+    pub peers: Vec<SocketAddr>,
+    /// Directory the log files are written to [default: logs]
+    #[arg(long)]
+    pub log_dir: Option<PathBuf>,
+    /// Maximum level of log messages [default: DEBUG]
+    #[arg(long)]
+    pub log_level: Option<Level>,
+    /// Seconds neighbours get to answer a SCC
+    #[arg(long)]
+    pub scc_timeout: Option<u64>,
+    /// Seconds between two STU rounds
+    #[arg(long)]
+    pub stu_interval: Option<u64>,
+    /// Seconds a route lives without being confirmed before it is poisoned, more than the STU interval
+    #[arg(long)]
+    pub route_timeout: Option<u64>,
+    /// Seconds an unreachable route stays in the routing table
+    #[arg(long)]
+    pub route_gc: Option<u64>,
+    /// Least milliseconds between two routing updates triggered by a changed route
+    #[arg(long)]
+    pub update_damping: Option<u64>,
+    /// TTL of packets created by this node
+    #[arg(long)]
+    pub ttl: Option<u8>,
+    /// Hop count announcing an unreachable target
+    #[arg(long)]
+    pub unreachable_metric: Option<i32>,
+    /// Chat items queued per peer before the queue policy applies
+    #[arg(long)]
+    pub queue_depth: Option<usize>,
+    /// What to do when a peer queue is full: drop_oldest, drop_newest or disconnect
+    #[arg(long)]
+    pub queue_policy: Option<OverflowPolicy>,
+    /// Seconds the fragments of a large message may take to arrive
+    #[arg(long)]
+    pub reassembly_timeout: Option<u64>,
+    /// Seconds to wait for the ACK of a message before sending it again
+    #[arg(long)]
+    pub ack_timeout: Option<u64>,
+    /// Retransmissions of an unacknowledged message before it is given up on
+    #[arg(long)]
+    pub max_retransmits: Option<u32>,
+    /// File holding the secret key of this node, created if it doesn't exist
+    #[arg(long)]
+    pub key_file: Option<PathBuf>,
+    /// Send every message in plain text, even if the key of the destination is known
+    #[arg(long)]
+    pub no_encryption: bool,
+    /// Which routing updates are accepted: off, permissive or strict
+    #[arg(long)]
+    pub route_signatures: Option<SignaturePolicy>,
+    /// How routes through a neighbour are advertised to it: off, simple or poison_reverse
+    #[arg(long)]
+    pub split_horizon: Option<SplitHorizon>,
+    /// Directory accepted files are written to [default: downloads]
+    #[arg(long)]
+    pub download_dir: Option<PathBuf>,
+    /// Run without the TUI, reading JSON commands from stdin and writing JSON events to stdout
+    #[arg(long)]
+    pub headless: bool,
+    /// Serve a JSON-RPC control socket at this path
+    #[arg(long)]
+    pub control: Option<PathBuf>,
+}
+
+/// Layout of the configuration file
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub bind: Bind,
+    pub peers: Vec<SocketAddr>,
+    pub log_dir: Option<PathBuf>,
+    pub log_level: Option<String>,
+    pub control: Option<PathBuf>,
+    pub node: NodeConfig,
+}
+
+/// One or several addresses to listen on
+#[derive(Deserialize, Debug, Default)]
+#[serde(untagged)]
+pub enum Bind {
+    #[default]
+    None,
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Bind {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Bind::None => Vec::new(),
+            Bind::One(addr) => vec![addr],
+            Bind::Many(addrs) => addrs,
+        }
+    }
+}
+
+/// Everything needed to start the application, after merging defaults, file and flags
+#[derive(Debug, PartialEq)]
+pub struct Settings {
+    pub bind: Vec<String>,
+    pub peers: Vec<SocketAddr>,
+    pub log_dir: PathBuf,
+    pub log_level: Level,
+    pub headless: bool,
+    pub control: Option<PathBuf>,
+    pub node: NodeConfig,
+}
+
+impl Settings {
+    /// Read the configuration file, if any, and apply the command line on top of it.
+    pub fn load(cli: Cli) -> Result<Settings, Box<dyn Error>> {
+        let file = match &cli.config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+                toml::from_str(&content)
+                    .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?
+            }
+            None => FileConfig::default(),
+        };
+        Settings::merge(file, cli)
+    }
+
+    fn merge(file: FileConfig, cli: Cli) -> Result<Settings, Box<dyn Error>> {
+        let log_level = match (cli.log_level, file.log_level) {
+            (Some(level), _) => level,
+            (None, Some(level)) => level
+                .parse()
+                .map_err(|_| format!("Invalid log level: {}", level))?,
+            (None, None) => Level::DEBUG,
+        };
+
+        let mut node = file.node;
+        if let Some(nickname) = cli.nickname {
+            node.nickname = nickname;
+        }
+        if let Some(scc_timeout) = cli.scc_timeout {
+            node.scc_timeout_secs = scc_timeout;
+        }
+        if let Some(stu_interval) = cli.stu_interval {
+            node.stu_interval_secs = stu_interval;
+        }
+        if let Some(route_timeout) = cli.route_timeout {
+            node.route_timeout_secs = route_timeout;
+        }
+        if let Some(route_gc) = cli.route_gc {
+            node.route_gc_secs = route_gc;
+        }
+        if let Some(update_damping) = cli.update_damping {
+            node.update_damping_ms = update_damping;
+        }
+        if let Some(ttl) = cli.ttl {
+            node.default_ttl = ttl;
+        }
+        if let Some(unreachable_metric) = cli.unreachable_metric {
+            node.unreachable_metric = unreachable_metric;
+        }
+        if let Some(queue_depth) = cli.queue_depth {
+            node.queue_depth = queue_depth;
+        }
+        if let Some(queue_policy) = cli.queue_policy {
+            node.queue_policy = queue_policy;
+        }
+        if let Some(reassembly_timeout) = cli.reassembly_timeout {
+            node.reassembly_timeout_secs = reassembly_timeout;
+        }
+        if let Some(ack_timeout) = cli.ack_timeout {
+            node.ack_timeout_secs = ack_timeout;
+        }
+        if let Some(max_retransmits) = cli.max_retransmits {
+            node.max_retransmits = max_retransmits;
+        }
+        if let Some(key_file) = cli.key_file {
+            node.key_file = Some(key_file);
+        }
+        if cli.no_encryption {
+            node.encryption = false;
+        }
+        if let Some(route_signatures) = cli.route_signatures {
+            node.route_signatures = route_signatures;
+        }
+        if let Some(split_horizon) = cli.split_horizon {
+            node.split_horizon = split_horizon;
+        }
+        if let Some(download_dir) = cli.download_dir {
+            node.download_dir = download_dir;
+        }
+
+        node.validate()?;
+
+        // Peers from both sources are connected to
+        let mut peers = file.peers;
+        peers.extend(cli.peers);
+
+        // Listen addresses given on the command line replace the ones from the file
+        let mut bind = cli.bind;
+        if bind.is_empty() {
+            bind = file.bind.into_vec();
+        }
+        if bind.is_empty() {
+            bind.push("127.0.0.1:6142".to_string());
+        }
+
+        Ok(Settings {
+            bind,
+            peers,
+            log_dir: cli
+                .log_dir
+                .or(file.log_dir)
+                .unwrap_or_else(|| PathBuf::from("logs")),
+            log_level,
+            headless: cli.headless,
+            control: cli.control.or(file.control),
+            node,
+        })
+    }
+}
+
+#[test]
+fn test_cli_overrides_file() {
+    let file: FileConfig = toml::from_str(
+        r#"
+        bind = "127.0.0.1:7000"
+        peers = ["127.0.0.1:7001"]
+        log_level = "info"
+
+        [node]
+        nickname = "from-file"
+        default_ttl = 8
+        queue_policy = "disconnect"
+        split_horizon = "off"
+        "#,
+    )
+    .unwrap();
+    let cli = Cli::parse_from([
+        "rnp2",
+        "127.0.0.1:7100",
+        "[::1]:7100",
+        "--nickname",
+        "from-cli",
+        "--peer",
+        "127.0.0.1:7002",
+        "--stu-interval",
+        "5",
+        "--queue-depth",
+        "16",
+        "--route-timeout",
+        "20",
+        "--update-damping",
+        "250",
+        "--split-horizon",
+        "poison_reverse",
+    ]);
+
+    let settings = Settings::merge(file, cli).unwrap();
+    assert_eq!(
+        settings,
+        Settings {
+            bind: vec!["127.0.0.1:7100".to_string(), "[::1]:7100".to_string()],
+            peers: vec![
+                "127.0.0.1:7001".parse().unwrap(),
+                "127.0.0.1:7002".parse().unwrap()
+            ],
+            log_dir: PathBuf::from("logs"),
+            log_level: Level::INFO,
+            headless: false,
+            control: None,
+            node: NodeConfig {
+                nickname: "from-cli".to_string(),
+                scc_timeout_secs: 1,
+                stu_interval_secs: 5,
+                default_ttl: 8,
+                unreachable_metric: 32,
+                queue_depth: 16,
+                queue_policy: OverflowPolicy::Disconnect,
+                reassembly_timeout_secs: 30,
+                ack_timeout_secs: 2,
+                max_retransmits: 4,
+                encryption: true,
+                key_file: None,
+                route_signatures: SignaturePolicy::Permissive,
+                download_dir: PathBuf::from("downloads"),
+                route_timeout_secs: 20,
+                route_gc_secs: 60,
+                update_damping_ms: 250,
+                split_horizon: SplitHorizon::PoisonReverse,
+            },
+        }
+    );
+}
+
+#[test]
+fn test_defaults_without_file() {
+    let settings = Settings::merge(FileConfig::default(), Cli::parse_from(["rnp2"])).unwrap();
+    assert_eq!(settings.bind, vec!["127.0.0.1:6142"]);
+    assert_eq!(settings.log_level, Level::DEBUG);
+    assert_eq!(settings.node, NodeConfig::default());
+}
+
+#[test]
+fn test_route_timeout_longer_than_stu_interval() {
+    let file: FileConfig = toml::from_str(
+        r#"
+        [node]
+        stu_interval_secs = 30
+        "#,
+    )
+    .unwrap();
+    let error = Settings::merge(file, Cli::parse_from(["rnp2"])).unwrap_err();
+    assert!(error.to_string().contains("route timeout"), "{}", error);
+    let settings = Settings::merge(
+        FileConfig::default(),
+        Cli::parse_from(["rnp2", "--stu-interval", "30", "--route-timeout", "90"]),
+    )
+    .unwrap();
+    assert_eq!(settings.node.route_timeout_secs, 90);
+}
+
+#[test]
+fn test_bind_list_in_file() {
+    let file: FileConfig = toml::from_str(r#"bind = ["0.0.0.0:7000", "[::]:7001"]"#).unwrap();
+    let settings = Settings::merge(file, Cli::parse_from(["rnp2"])).unwrap();
+    assert_eq!(settings.bind, vec!["0.0.0.0:7000", "[::]:7001"]);
+}
@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::crypto::SignaturePolicy;
+use crate::queue::OverflowPolicy;
+use crate::shared::SplitHorizon;
+
+/// Tunables of a single node.
+///
+/// Every field has a default, so a configuration file only needs to list what it changes.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    /// Nickname attached to outgoing messages
+    pub nickname: String,
+    /// Seconds our neighbours get to answer a SCC before their routes are poisoned
+    pub scc_timeout_secs: u64,
+    /// Seconds between sending the STU and starting the next SCC round
+    pub stu_interval_secs: u64,
+    /// TTL of packets we create
+    pub default_ttl: u8,
+    /// Hop count used to announce that a target can't be reached anymore
+    pub unreachable_metric: i32,
+    /// Chat items that may wait in the outbound queue of a single peer
+    pub queue_depth: usize,
+    /// What to do when the queue of a peer is full
+    pub queue_policy: OverflowPolicy,
+    /// Seconds the fragments of a message may take to arrive before they are dropped
+    pub reassembly_timeout_secs: u64,
+    /// Seconds to wait for the ACK of a message before sending it again, doubled on every retry
+    pub ack_timeout_secs: u64,
+    /// Retransmissions of an unacknowledged message before it is given up on
+    pub max_retransmits: u32,
+    /// Encrypt messages to destinations whose key we know
+    pub encryption: bool,
+    /// File holding our secret key, a new key is created on every start without it
+    pub key_file: Option<PathBuf>,
+    /// Which routing updates are accepted, depending on their signature
+    pub route_signatures: SignaturePolicy,
+    /// Directory accepted files are written to
+    pub download_dir: PathBuf,
+    /// Seconds a route lives without being confirmed by its next hop before it is poisoned
+    pub route_timeout_secs: u64,
+    /// Seconds a route stays unreachable before it is removed from the routing table
+    pub route_gc_secs: u64,
+    /// Least milliseconds between two routing updates triggered by a change of the routing table
+    pub update_damping_ms: u64,
+    /// How the routes through a neighbour are advertised to it
+    pub split_horizon: SplitHorizon,
+}
+
+impl NodeConfig {
+    pub fn scc_timeout(&self) -> Duration {
+        Duration::from_secs(self.scc_timeout_secs)
+    }
+
+    pub fn stu_interval(&self) -> Duration {
+        Duration::from_secs(self.stu_interval_secs)
+    }
+
+    pub fn reassembly_timeout(&self) -> Duration {
+        Duration::from_secs(self.reassembly_timeout_secs)
+    }
+
+    pub fn ack_timeout(&self) -> Duration {
+        Duration::from_secs(self.ack_timeout_secs)
+    }
+
+    pub fn route_timeout(&self) -> Duration {
+        Duration::from_secs(self.route_timeout_secs)
+    }
+
+    pub fn route_gc(&self) -> Duration {
+        Duration::from_secs(self.route_gc_secs)
+    }
+
+    pub fn update_damping(&self) -> Duration {
+        Duration::from_millis(self.update_damping_ms)
+    }
+
+    /// Check that the settings work together, the node refuses to start otherwise.
+    ///
+    /// A route has to outlive a whole heartbeat round, the SCC timeout and the STU interval
+    /// after it, or it expires before the next STU confirms it.
+    pub fn validate(&self) -> Result<(), String> {
+        let round = self.scc_timeout_secs.saturating_add(self.stu_interval_secs);
+        if round >= self.route_timeout_secs {
+            return Err(format!(
+                "Invalid route timeout: {}s, it has to be longer than the SCC timeout and the STU interval together, {}s",
+                self.route_timeout_secs, round
+            ));
+        }
+        Ok(())
+    }
+
+    /// Time from sending a message until it is given up on, if no ACK arrives
+    ///
+    /// Saturates at `Duration::MAX` for retransmits or timeouts too large to add up.
+    pub fn retransmit_window(&self) -> Duration {
+        self.max_retransmits
+            .checked_add(1)
+            .and_then(|waits| 2u32.checked_pow(waits))
+            .and_then(|factor| self.ack_timeout().checked_mul(factor - 1))
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            nickname: "Morganite".to_string(),
+            scc_timeout_secs: 1,
+            stu_interval_secs: 10,
+            default_ttl: 16,
+            unreachable_metric: 32,
+            queue_depth: 256,
+            queue_policy: OverflowPolicy::DropOldest,
+            reassembly_timeout_secs: 30,
+            ack_timeout_secs: 2,
+            max_retransmits: 4,
+            encryption: true,
+            key_file: None,
+            route_signatures: SignaturePolicy::Permissive,
+            download_dir: PathBuf::from("downloads"),
+            route_timeout_secs: 30,
+            route_gc_secs: 60,
+            update_damping_ms: 1000,
+            split_horizon: SplitHorizon::Simple,
+        }
+    }
+}
+
+#[test]
+fn test_retransmit_window() {
+    let config = NodeConfig::default();
+    // 2 + 4 + 8 + 16 + 32 seconds
+    assert_eq!(config.retransmit_window(), Duration::from_secs(62));
+    for (ack_timeout_secs, max_retransmits) in [(2, u32::MAX), (2, 31), (u64::MAX, 1)] {
+        let config = NodeConfig {
+            ack_timeout_secs,
+            max_retransmits,
+            ..NodeConfig::default()
+        };
+        assert_eq!(config.retransmit_window(), Duration::MAX);
+    }
+}
+
+#[test]
+fn test_validate() {
+    NodeConfig::default().validate().unwrap();
+    // The STU of a round goes out only after the SCC timeout
+    for (scc_timeout_secs, stu_interval_secs) in [(1, 29), (0, 30), (20, 20), (u64::MAX, 1)] {
+        let config = NodeConfig {
+            scc_timeout_secs,
+            stu_interval_secs,
+            ..NodeConfig::default()
+        };
+        let error = config.validate().unwrap_err();
+        assert!(error.contains("route timeout"), "{}", error);
+    }
+    let config = NodeConfig {
+        scc_timeout_secs: 1,
+        stu_interval_secs: 28,
+        ..NodeConfig::default()
+    };
+    config.validate().unwrap();
+}
@@ -1,266 +1,288 @@
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc, Mutex};
+use rnp2::channel_events::{ChannelEvent, Commands, NodeEvent};
+use rnp2::shared::Tx;
+use rnp2::Node;
 
-use crate::channel_events::{ChannelEvent, Commands};
-use crate::process::process;
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 
+use std::collections::HashMap;
 use std::error::Error;
 
-use std::net::SocketAddr;
-use std::sync::Arc;
-
-use crate::shared::{RoutingTableEntry, Shared};
-
 ///TUI handling the users console inputs
-pub async fn handle_console(state: Arc<Mutex<Shared>>) -> Result<(), Box<dyn Error>> {
-    let addr = "127.0.0.1:4444".parse::<SocketAddr>()?;
-    let client_addr = state
-        .lock()
-        .await
-        .listener_addr
-        .clone()
-        .parse::<SocketAddr>()
-        .unwrap();
-
-    // Create a channel for this peer
-    let (tx, mut rx) = mpsc::unbounded_channel();
-
-    // Add an entry for this `Peer` in the shared state map.
-    state.lock().await.peers.insert(addr, tx);
+///
+/// Returns once `shutdown` is cancelled, either by the quit command or from the outside.
+pub async fn handle_console(
+    node: Node,
+    console_input_sender: Tx,
+    shutdown: CancellationToken,
+) -> Result<(), Box<dyn Error>> {
+    let mut events = node.events();
 
     // Create a channel for the console input
-    let (command_sender, command_receiver) = std::sync::mpsc::channel::<ChannelEvent>();
+    let (command_sender, mut command_receiver) = tokio::sync::mpsc::unbounded_channel();
 
-    // Sleep for 5 seconds to allow the server to start up
-    tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
     // Send the command receiver to the TUI
-    match state
-        .lock()
-        .await
-        .console_input_sender
-        .send(ChannelEvent::CommandReceiver(command_sender))
-    {
+    match console_input_sender.send(ChannelEvent::CommandReceiver(command_sender)) {
         Ok(_) => {
             tracing::info!("Sent command receiver to TUI");
         }
         Err(e) => {
             tracing::error!("Error sending command receiver to TUI: {:?}", e);
-            // Exit the application
-            std::process::exit(1);
+            return Err(e.into());
         }
     }
 
     loop {
-        // We're locking the cmd receiver, possibly for a long time but this is actually fine
-        // Since this is the only place where we're reading from the cmd receiver
-        // Still required a Mutex<> since the compiler doesn't know that
-
-        //tracing::debug!("Waiting for console input");
-
-        // Create timer for tokio::select to prevent blocking
-        let timer = tokio::time::sleep(tokio::time::Duration::from_millis(100));
-
         tokio::select! {
-            // Check for new peer events
-            Some(event) = rx.recv() => {
+            // Somebody asked us to quit
+            _ = shutdown.cancelled() => return Ok(()),
+            // Check for new node events
+            Some(event) = events.next() => {
                 tracing::debug!("Received event: {:?}", event);
 
                 // Send the event to TUI
-                if let Err(e) = state.lock().await.console_input_sender.send(event) {
+                if let Err(e) = console_input_sender.send(to_tui_event(event)) {
                     tracing::error!("Error sending event to TUI: {:?}", e);
                 }
             },
-            // Check for new console commands
-            _ = timer => {
-                if let Ok(event) = command_receiver.try_recv() {
-                    // If the channel event is type routing, ignore it
-                    tracing::debug!("Received event: {:?}", event);
-
-                    match event {
-                        ChannelEvent::MessageToTUI(message, name, addr) => {
-                            // Send message to TUI
-                            tracing::debug!("Sending message to TUI");
-
-                            // Send the message to the channel
-                            if let Err(e) = state.lock().await.console_input_sender.send(ChannelEvent::MessageToTUI(message, name, addr)) {
-                                tracing::info!("Error sending your message. error = {:?}", e);
-                            }
-                        },
-                        ChannelEvent::Command(cmd) => {
-                            tracing::debug!("Received command: {:?}", cmd);
-
-                            match cmd {
-                                Commands::Quit => {
-                                    // Quit the application
-                                    tracing::debug!("Quitting application");
-                                    // Quit all tokio tasks
-                                    // @TODO: Gracefully announce to all peers that we are quitting?
-                                    {
-                                        let mut lock = state.lock().await;
-                                        for entry in lock.routing_table.values_mut() {
-                                            entry.hop_count = 32;
-                                        }
-                                        for entry in lock.peers.values_mut() {
-                                            
-                                            if let Err(e) = entry.send(ChannelEvent::Routing(6)) {
-                                                tracing::error!("Error sending STU to quit gracefully: {:?}", e);
-                                            }
-                                        }
-
-                                    }
-                                    std::process::exit(0);
-                                },
-                                Commands::Contacts => {
-                                    // Display the routing table
-                                    //tracing::debug!("Displaying routing table");
-                                        let state_lock = state.lock().await;
-                                        let routing_table = state_lock.routing_table.clone();
-
-                                        //tracing::debug!("Sent routing table to TUI");
-                                        if let Err(e) = state_lock.console_input_sender.send(ChannelEvent::Contacts(routing_table)) {
-                                            tracing::error!("Error sending routing table to TUI: {:?}", e);
-                                        }
-                                },
-                                Commands::SetOwnNick(nickname) => {
-                                    // Set the nickname
-                                    tracing::debug!("Setting nickname to: {}", nickname);
-                                    let mut state_lock = state.lock().await;
-                                    state_lock.nickname = nickname;
-                                },
-                                Commands::Connect(addr) => {
-                                    //check wether there is already a direct connection to the target client:
-                                    let already_connected: bool;
-                                    {
-                                        let lock = state.lock().await;
-                                        match lock.routing_table.get(&addr) {
-                                            Some(direct) => {
-                                                already_connected = direct.hop_count == 1;
-                                            },
-                                            None => {
-                                                already_connected = false;
-                                            },
-                                        };
-                                    }
-                                    if !already_connected {
-                                        // Connect to specified client
-                                        tracing::debug!("Connecting to: {}", addr);
-
-                                        // Create TCP stream
-                                        let stream = match TcpStream::connect(addr).await {
-                                            Ok(stream) => stream,
-                                            Err(e) => {
-                                                tracing::error!("Failed to connect to {}: {}", addr, e);
-                                                continue;
-                                            }
-                                        };
-
-                                        // Clone a handle to the `Shared` state for the new connection.
-                                        let proccess_state = Arc::clone(&state);
-
-                                        //add new connection to routing table
-                                        {
-                                        let mut lock = state.lock().await;
-                                        lock.routing_table.insert(addr, RoutingTableEntry {next:addr, hop_count: 1, ttl: true});
-                                        }
-                                        // Spawn asynchronous handler
-                                        tokio::spawn(async move {
-                                            tracing::info!("Connected to: {}", addr);
-                                            if let Err(e) = process(proccess_state, stream, addr, true).await {
-                                                tracing::info!("An error occurred; error = {:?}", e);
-                                            }
-                                        });
-                                    }
-                                },
-                                Commands::Broadcast(message) => {
-                                    // Broadcast message to all clients
-                                    tracing::debug!("Broadcasting message: {}", message);
-
-                                    // Get the list of all entries in the routing table
-                                    let routing_table = {
-                                        let lock = state.lock().await;
-                                        lock.routing_table.clone()
-                                    };
-
-                                    // Send the message to all clients via next hop
-                                    for (addr, routing_table_entry) in routing_table.iter() {
-                                        // Get the channel to the next client/destination on the route
-                                        let target = if routing_table_entry.next == client_addr {
-                                            addr.clone()
-                                        } else {
-                                            routing_table_entry.next
-                                        };
-
-                                        let peer = {
-                                            let lock = state.lock().await;
-                                            match lock.peers.get(&target) {
-                                                Some(peer) => peer.clone(),
-                                                None => {
-                                                    tracing::error!("No channel to destination: {} available", target);
-                                                    continue;
-                                                }
-                                            }
-                                        };
-
-                                        // Send the message to the channel
-                                        if let Err(e) = peer.send(ChannelEvent::Message(message.clone(), addr.clone())) {
-                                            tracing::info!("Error sending your message. error = {:?}", e);
-                                        }
-                                    }
-                                },
-
-                                Commands::Message(addr, message) => {
-                                    // Send message to specified client
-                                    tracing::debug!("Sending message to: {}", addr);
-
-                                    // Get the routing table entry for the destination
-                                    let routing_table_entry = {
-                                        let lock = state.lock().await;
-                                        match lock.routing_table.get(&addr) {
-                                            Some(routing_table_entry) => routing_table_entry.clone(),
-                                            None => {
-                                                tracing::error!("No route to destination: {} available", addr);
-                                                continue;
-                                            }
-                                        }
-                                    };
-
-                                    // Get the channel to the next client/destination on the route
-                                    let target = if routing_table_entry.next == client_addr {
-                                         addr
-                                    } else {
-                                         routing_table_entry.next
-                                    };
-
-                                    let peer = {
-                                        let lock = state.lock().await;
-                                        match lock.peers.get(&target) {
-                                            Some(peer) => peer.clone(),
-                                            None => {
-                                                tracing::error!("No channel to destination: {} available", target);
-                                                continue;
-                                            }
-                                        }
-                                    };
+            // Handle console commands as soon as they arrive
+            Some(event) = command_receiver.recv() => {
+                tracing::debug!("Received event: {:?}", event);
 
-                                    // Send the message to the channel
-                                    if let Err(e) = peer.send(ChannelEvent::Message(message, addr)) {
-                                        tracing::info!("Error sending your message. error = {:?}", e);
-                                    }
-                                },
-                                _ => {
-                                    tracing::error!("Unknown command: {:#?}", cmd);
-                                }
-                            }
-                        },
-                        _ => {
-                            tracing::error!("Received non-command event in console middleware");
-                        },
-                    }
+                match event {
+                    ChannelEvent::MessageToTUI(message, name, addr, encrypted) => {
+                        // Send message to TUI
+                        tracing::debug!("Sending message to TUI");
+
+                        // Send the message to the channel
+                        if let Err(e) = console_input_sender.send(ChannelEvent::MessageToTUI(message, name, addr, encrypted)) {
+                            tracing::info!("Error sending your message. error = {:?}", e);
+                        }
+                    },
+                    ChannelEvent::Command(cmd) => {
+                        tracing::debug!("Received command: {:?}", cmd);
+                        handle_command(&node, &console_input_sender, &shutdown, cmd).await;
+                    },
+                    _ => {
+                        tracing::error!("Received non-command event in console middleware");
+                    },
                 }
             },
-
         }
 
         //tracing::debug!("Finished processing command");
     }
 }
+
+/// Execute a single command issued by the user against the node
+async fn handle_command(
+    node: &Node,
+    console_input_sender: &Tx,
+    shutdown: &CancellationToken,
+    cmd: Commands,
+) {
+    match cmd {
+        Commands::Quit => {
+            // Quit the application, main announces it to the peers and stops the node
+            tracing::debug!("Quitting application");
+            shutdown.cancel();
+        }
+        Commands::Contacts => {
+            // Display the routing table
+            let routing_table = node.routing_table().await;
+            if let Err(e) = console_input_sender.send(ChannelEvent::Contacts(routing_table)) {
+                tracing::error!("Error sending routing table to TUI: {:?}", e);
+            }
+
+            // Along with the keys of the contacts messages are encrypted to
+            let keys = match node.config().encryption {
+                true => node.keys().await,
+                false => HashMap::new(),
+            };
+            let fingerprints = keys
+                .iter()
+                .map(|(addr, key)| (*addr, key.fingerprint()))
+                .collect();
+            if let Err(e) =
+                console_input_sender.send(ChannelEvent::Keys(node.fingerprint(), fingerprints))
+            {
+                tracing::error!("Error sending keys to TUI: {:?}", e);
+            }
+
+            // And the nicknames of the contacts
+            if let Err(e) =
+                console_input_sender.send(ChannelEvent::Nicknames(node.nicknames().await))
+            {
+                tracing::error!("Error sending nicknames to TUI: {:?}", e);
+            }
+
+            // And who of them is around
+            let presences = ChannelEvent::Presences(node.presence().await, node.presences().await);
+            if let Err(e) = console_input_sender.send(presences) {
+                tracing::error!("Error sending presences to TUI: {:?}", e);
+            }
+
+            // And how fast the links to our neighbours are
+            if let Err(e) = console_input_sender.send(ChannelEvent::Rtts(node.rtts().await)) {
+                tracing::error!("Error sending round-trip times to TUI: {:?}", e);
+            }
+        }
+        Commands::SetOwnNick(nickname) => {
+            // Set the nickname
+            node.set_nickname(nickname).await;
+        }
+        Commands::SetPresence(presence) => node.set_presence(presence).await,
+        Commands::Typing(addr) => {
+            // Rate limited by the node, most keystrokes don't send anything
+            node.typing(addr).await;
+        }
+        Commands::Trace(addr) => {
+            // The TUI shows the path once it comes back
+            node.trace(addr).await;
+        }
+        Commands::Ping(addr) => {
+            // Waiting for the answer here would hold up every other command
+            let node = node.clone();
+            let console_input_sender = console_input_sender.clone();
+            tokio::spawn(async move {
+                let result = node.ping(addr).await.map_err(|e| e.to_string());
+                if let Err(e) = console_input_sender.send(ChannelEvent::Ping(addr, result)) {
+                    tracing::error!("Error sending ping result to TUI: {:?}", e);
+                }
+            });
+        }
+        Commands::Connect(addr) => {
+            if let Err(e) = node.connect(addr).await {
+                tracing::error!("Failed to connect to {}: {}", addr, e);
+            }
+        }
+        Commands::Broadcast(message) => {
+            // Broadcast message to all clients
+            node.broadcast(message).await;
+        }
+        Commands::JoinRoom(room) => node.join_room(room).await,
+        Commands::LeaveRoom(room) => node.leave_room(room).await,
+        Commands::Say(room, message) => {
+            // Every member acknowledges its own copy, the TUI already shows the message in the room
+            let ids = node.say(room.clone(), message).await;
+            if ids.is_empty() {
+                let msg = format!("Nobody else is in {} yet", room);
+                if let Err(e) = console_input_sender.send(ChannelEvent::LogToTerminal(msg)) {
+                    tracing::error!("Error sending log to TUI: {:?}", e);
+                }
+            }
+        }
+        Commands::MessageByNick(nickname, message) => {
+            // Only sent if the nickname is unambiguous
+            if let Err(e) = node.message_by_nick(&nickname, message).await {
+                if let Err(e) =
+                    console_input_sender.send(ChannelEvent::LogToTerminal(e.to_string()))
+                {
+                    tracing::error!("Error sending log to TUI: {:?}", e);
+                }
+            }
+        }
+        Commands::SendFile(addr, path) => {
+            // The TUI learns about it through the progress events
+            if let Err(e) = node.send_file(addr, &path).await {
+                let msg = format!("Could not send {}: {}", path.display(), e);
+                if let Err(e) = console_input_sender.send(ChannelEvent::LogToTerminal(msg)) {
+                    tracing::error!("Error sending log to TUI: {:?}", e);
+                }
+            }
+        }
+        Commands::AcceptFile(id) | Commands::DeclineFile(id) => {
+            let result = match cmd {
+                Commands::AcceptFile(_) => node.accept_file(id).await,
+                _ => node.decline_file(id).await,
+            };
+            if let Err(e) = result {
+                if let Err(e) =
+                    console_input_sender.send(ChannelEvent::LogToTerminal(e.to_string()))
+                {
+                    tracing::error!("Error sending log to TUI: {:?}", e);
+                }
+            }
+        }
+        Commands::Message(addr, message) => {
+            // Send message to specified client
+            tracing::debug!("Sending message to: {}", addr);
+            // The TUI learns about it through the `Sent` event
+            node.send_message(addr, message).await;
+        }
+        _ => {
+            tracing::error!("Unknown command: {:#?}", cmd);
+        }
+    }
+}
+
+/// Translate an event of the node into the event the TUI displays
+fn to_tui_event(event: NodeEvent) -> ChannelEvent {
+    match event {
+        NodeEvent::Join(addr) => ChannelEvent::Join(addr.to_string()),
+        NodeEvent::Leave(addr) => ChannelEvent::Leave(addr.to_string()),
+        NodeEvent::Message {
+            source,
+            nickname,
+            message,
+            encrypted,
+            room: Some(room),
+        } => ChannelEvent::RoomMessage(room, message, nickname, source, encrypted),
+        NodeEvent::Message {
+            source,
+            nickname,
+            message,
+            encrypted,
+            ..
+        } => ChannelEvent::MessageToTUI(message, nickname, source, encrypted),
+        NodeEvent::Typing { source, nickname } => ChannelEvent::Typing(nickname, source),
+        NodeEvent::Broadcast {
+            origin,
+            nickname,
+            message,
+        } => ChannelEvent::Broadcast(message, nickname, origin),
+        NodeEvent::Trace {
+            dest,
+            id,
+            hops,
+            round_trip,
+        } => ChannelEvent::Trace(id, dest, hops, round_trip),
+        NodeEvent::Pong { source, id } => {
+            ChannelEvent::LogToTerminal(format!("Ping {} answered by {}", id, source))
+        }
+        NodeEvent::FileOffer {
+            source,
+            id,
+            name,
+            size,
+        } => ChannelEvent::FileOffer(id, name, size, source),
+        NodeEvent::FileProgress {
+            peer,
+            id,
+            name,
+            bytes,
+            size,
+        } => ChannelEvent::FileProgress(id, name, bytes, size, peer),
+        NodeEvent::FileDone {
+            peer,
+            id,
+            name,
+            status,
+        } => ChannelEvent::FileDone(id, name, status, peer),
+        NodeEvent::Sent {
+            id,
+            dest,
+            room: Some(room),
+            ..
+        } => ChannelEvent::LogToTerminal(format!("Sent message {} to {} in {}", id, dest, room)),
+        NodeEvent::Sent {
+            id,
+            dest,
+            message,
+            encrypted,
+            ..
+        } => ChannelEvent::MessageSent(id, dest, message, encrypted),
+        NodeEvent::Delivery { id, status, .. } => ChannelEvent::Delivery(id, status),
+        NodeEvent::Log(msg) => ChannelEvent::LogToTerminal(msg),
+    }
+}
@@ -0,0 +1,393 @@
+use rnp2::presence::Presence;
+use rnp2::Node;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio_util::sync::CancellationToken;
+
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const COMMAND_FAILED: i64 = -32000;
+
+/// A JSON-RPC 2.0 request, one per line
+#[derive(Deserialize, Debug)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    id: Option<Value>,
+}
+
+#[derive(Serialize, Debug)]
+struct Response {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+#[derive(Serialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AddrParams {
+    addr: SocketAddr,
+}
+
+#[derive(Deserialize)]
+struct MessageParams {
+    addr: SocketAddr,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct NickMessageParams {
+    nickname: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct BroadcastParams {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct RoomParams {
+    room: String,
+}
+
+#[derive(Deserialize)]
+struct SayParams {
+    room: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct SendFileParams {
+    addr: SocketAddr,
+    path: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct TransferParams {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct NicknameParams {
+    nickname: String,
+}
+
+/// Serve JSON-RPC calls for the node on a Unix domain socket at `path`.
+///
+/// Every connection may send any number of requests, one JSON object per line.
+/// The `quit` method cancels `shutdown`, stopping the node is left to its owner.
+pub async fn serve(path: &Path, node: Node, shutdown: CancellationToken) -> io::Result<()> {
+    // A socket file left over from a previous run would make the bind fail
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    tracing::info!("control socket listening on {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let node = node.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, node, shutdown).await {
+                tracing::info!("control connection closed with error; error = {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    node: Node,
+    shutdown: CancellationToken,
+) -> Result<(), Box<dyn Error>> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        tracing::debug!("control request: {}", line);
+
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, e.to_string());
+                write_response(&mut writer, Value::Null, Err(error)).await?;
+                continue;
+            }
+        };
+
+        let result = if request.jsonrpc != "2.0" {
+            Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""))
+        } else {
+            call(&node, &request.method, request.params).await
+        };
+        let quit = request.method == "quit" && result.is_ok();
+
+        // Requests without an id are notifications and don't get an answer
+        if let Some(id) = request.id {
+            write_response(&mut writer, id, result).await?;
+        }
+
+        if quit {
+            // Answer first, the node is gone once the token is cancelled
+            shutdown.cancel();
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+async fn write_response(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    id: Value,
+    result: Result<Value, RpcError>,
+) -> io::Result<()> {
+    let response = match result {
+        Ok(result) => Response {
+            jsonrpc: "2.0",
+            result: Some(result),
+            error: None,
+            id,
+        },
+        Err(error) => Response {
+            jsonrpc: "2.0",
+            result: None,
+            error: Some(error),
+            id,
+        },
+    };
+    let mut line = serde_json::to_string(&response)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+/// Execute a single call against the node
+async fn call(node: &Node, method: &str, raw_params: Value) -> Result<Value, RpcError> {
+    match method {
+        // One method for every command the TUI knows
+        "connect" => {
+            let AddrParams { addr } = params(raw_params)?;
+            node.connect(addr)
+                .await
+                .map_err(|e| RpcError::new(COMMAND_FAILED, e.to_string()))?;
+            Ok(Value::Null)
+        }
+        "contacts" | "routing_table" => Ok(json!(node.routing_table().await)),
+        "message" => {
+            let MessageParams { addr, message } = params(raw_params)?;
+            let id = node.send_message(addr, message).await;
+            Ok(json!({ "id": id }))
+        }
+        "message_by_nick" => {
+            let NickMessageParams { nickname, message } = params(raw_params)?;
+            let id = node
+                .message_by_nick(&nickname, message)
+                .await
+                .map_err(|e| RpcError::new(COMMAND_FAILED, e.to_string()))?;
+            Ok(json!({ "id": id }))
+        }
+        "broadcast" => {
+            let BroadcastParams { message } = params(raw_params)?;
+            let id = node.broadcast(message).await;
+            Ok(json!({ "id": id }))
+        }
+        "join_room" => {
+            let RoomParams { room } = params(raw_params)?;
+            node.join_room(room).await;
+            Ok(Value::Null)
+        }
+        "leave_room" => {
+            let RoomParams { room } = params(raw_params)?;
+            node.leave_room(room).await;
+            Ok(Value::Null)
+        }
+        "say" => {
+            let SayParams { room, message } = params(raw_params)?;
+            let ids = node.say(room, message).await;
+            Ok(json!({ "ids": ids }))
+        }
+        "send_file" => {
+            let SendFileParams { addr, path } = params(raw_params)?;
+            let id = node
+                .send_file(addr, path)
+                .await
+                .map_err(|e| RpcError::new(COMMAND_FAILED, e.to_string()))?;
+            Ok(json!({ "id": id }))
+        }
+        "accept_file" | "decline_file" => {
+            let TransferParams { id } = params(raw_params)?;
+            let result = match method {
+                "accept_file" => node.accept_file(id).await,
+                _ => node.decline_file(id).await,
+            };
+            result.map_err(|e| RpcError::new(COMMAND_FAILED, e.to_string()))?;
+            Ok(Value::Null)
+        }
+        "set_presence" => {
+            let presence: Presence = params(raw_params)?;
+            node.set_presence(presence).await;
+            Ok(Value::Null)
+        }
+        "typing" => {
+            let AddrParams { addr } = params(raw_params)?;
+            Ok(json!({ "sent": node.typing(addr).await }))
+        }
+        "trace" => {
+            let AddrParams { addr } = params(raw_params)?;
+            Ok(json!({ "id": node.trace(addr).await }))
+        }
+        "ping" => {
+            let AddrParams { addr } = params(raw_params)?;
+            let rtt = node
+                .ping(addr)
+                .await
+                .map_err(|e| RpcError::new(COMMAND_FAILED, e.to_string()))?;
+            Ok(json!({ "rtt_ms": rtt.as_secs_f64() * 1000.0 }))
+        }
+        "set_own_nick" => {
+            let NicknameParams { nickname } = params(raw_params)?;
+            node.set_nickname(nickname).await;
+            Ok(Value::Null)
+        }
+        // Handled by the connection after answering
+        "quit" => Ok(Value::Null),
+        "help" => Ok(json!([
+            "connect {addr}",
+            "contacts",
+            "message {addr, message} -> {id}",
+            "message_by_nick {nickname, message} -> {id}",
+            "broadcast {message} -> {id}",
+            "join_room {room}",
+            "leave_room {room}",
+            "say {room, message} -> {ids}",
+            "send_file {addr, path} -> {id}",
+            "accept_file {id}",
+            "decline_file {id}",
+            "set_presence {state, text} with state online, away or busy",
+            "typing {addr} -> {sent}",
+            "trace {addr} -> {id}",
+            "ping {addr} -> {rtt_ms}",
+            "set_own_nick {nickname}",
+            "quit",
+            "help",
+            "routing_table",
+            "peers",
+            "queue_stats",
+            "nickname",
+            "nicknames",
+            "presence",
+            "presences",
+            "rtts",
+            "rooms"
+        ])),
+        // Queries
+        "peers" => Ok(json!(node.peers().await)),
+        "queue_stats" => Ok(json!(node.queue_stats().await)),
+        "nickname" => Ok(json!(node.nickname().await)),
+        "nicknames" => Ok(json!(node.nicknames().await)),
+        "presence" => Ok(json!(node.presence().await)),
+        "presences" => Ok(json!(node.presences().await)),
+        "rtts" => Ok(json!(node.rtts().await)),
+        "rooms" => Ok(json!(node.rooms().await)),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method: {}", method),
+        )),
+    }
+}
+
+#[tokio::test]
+async fn test_control_socket() {
+    let node = Node::bind("127.0.0.1:0").await.unwrap();
+    let path =
+        std::env::temp_dir().join(format!("morganite-test-{}.sock", node.local_addr().port()));
+
+    let server_path = path.clone();
+    let server_node = node.clone();
+    let shutdown = CancellationToken::new();
+    let server_shutdown = shutdown.clone();
+    tokio::spawn(async move { serve(&server_path, server_node, server_shutdown).await });
+
+    // Wait for the socket to show up
+    let stream = loop {
+        if let Ok(stream) = UnixStream::connect(&path).await {
+            break stream;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let requests = [
+        r#"{"jsonrpc":"2.0","method":"set_own_nick","params":{"nickname":"alice"},"id":1}"#,
+        r#"{"jsonrpc":"2.0","method":"set_own_nick","params":{"nickname":"bob"}}"#,
+        r#"{"jsonrpc":"2.0","method":"nickname","id":2}"#,
+        r#"{"jsonrpc":"2.0","method":"fly","id":3}"#,
+        r#"{"jsonrpc":"2.0","method":"message","params":{"addr":"nope"},"id":4}"#,
+        r#"{"jsonrpc":"2.0","method":"message_by_nick","params":{"nickname":"carol","message":"hi"},"id":6}"#,
+        r#"not json"#,
+        r#"{"jsonrpc":"2.0","method":"quit","id":5}"#,
+    ];
+    for request in requests {
+        writer.write_all(request.as_bytes()).await.unwrap();
+        writer.write_all(b"\n").await.unwrap();
+    }
+
+    let mut responses = Vec::new();
+    for _ in 0..7 {
+        let line = lines.next_line().await.unwrap().unwrap();
+        responses.push(serde_json::from_str::<Value>(&line).unwrap());
+    }
+
+    assert_eq!(responses[0], json!({"jsonrpc":"2.0","result":null,"id":1}));
+    assert_eq!(responses[1], json!({"jsonrpc":"2.0","result":"bob","id":2}));
+    assert_eq!(responses[2]["error"]["code"], METHOD_NOT_FOUND);
+    assert_eq!(responses[3]["error"]["code"], INVALID_PARAMS);
+    assert_eq!(responses[4]["error"]["code"], COMMAND_FAILED);
+    assert_eq!(
+        responses[4]["error"]["message"],
+        "Nobody called carol is in reach"
+    );
+    assert_eq!(responses[5]["error"]["code"], PARSE_ERROR);
+    assert_eq!(responses[6], json!({"jsonrpc":"2.0","result":null,"id":5}));
+    assert!(shutdown.is_cancelled());
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,433 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::protocol::routed_packet::RoutedPacket;
+
+const KEY_CONTEXT: &[u8] = b"morganite e2e v1";
+const SIGNING_CONTEXT: &[u8] = b"morganite signing v1";
+
+/// Public key of a node, sent as base64.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeKey([u8; 32]);
+
+impl NodeKey {
+    /// Short hash of the key for comparing it out of band, e.g. `3f2a 9c41 07d2 e8b5`
+    pub fn fingerprint(&self) -> String {
+        let hash = Sha256::digest(self.0);
+        hash[..8]
+            .chunks(2)
+            .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl fmt::Display for NodeKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", STANDARD.encode(self.0))
+    }
+}
+
+impl fmt::Debug for NodeKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeKey({})", self.fingerprint())
+    }
+}
+
+impl FromStr for NodeKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        STANDARD
+            .decode(s)
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .map(NodeKey)
+            .ok_or_else(|| Error::InvalidKey(s.to_string()))
+    }
+}
+
+impl Serialize for NodeKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        key.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// What the destination needs besides its own key to open a sealed message
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Sealed {
+    /// Long-term key of the source
+    pub key: NodeKey,
+    /// base64 of the nonce, never used twice with the same key
+    pub nonce: String,
+}
+
+/// Signature of a routing update, along with the sequence number it covers
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Signed {
+    /// Signing key of the source
+    pub key: NodeKey,
+    /// Grows with every update of the source, an older one is a replay
+    pub seq: u64,
+    /// base64 of the ed25519 signature
+    pub signature: String,
+}
+
+impl Signed {
+    /// Check that the owner of `key` signed `data` with this sequence number.
+    pub fn verify(&self, data: &[u8]) -> Result<(), Error> {
+        let key = VerifyingKey::from_bytes(&self.key.0)
+            .map_err(|_| Error::InvalidKey(self.key.to_string()))?;
+        let signature = STANDARD
+            .decode(&self.signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(Error::BadSignature)?;
+        key.verify_strict(&signed_data(data, self.seq), &signature)
+            .map_err(|_| Error::BadSignature)
+    }
+}
+
+/// How strictly the signatures of routing updates are checked
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SignaturePolicy {
+    /// Accept every update, signed or not
+    Off,
+    /// Accept unsigned updates, e.g. of older nodes, but reject invalid signatures and replays
+    #[default]
+    Permissive,
+    /// Only accept updates with a valid signature
+    Strict,
+}
+
+impl FromStr for SignaturePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(SignaturePolicy::Off),
+            "permissive" => Ok(SignaturePolicy::Permissive),
+            "strict" => Ok(SignaturePolicy::Strict),
+            _ => Err(format!(
+                "Unknown signature policy: {} (off, permissive or strict)",
+                s
+            )),
+        }
+    }
+}
+
+/// Long-term keypair of a node.
+///
+/// The signing key is derived from the secret, so the key file holds just one key.
+pub struct Identity {
+    secret: StaticSecret,
+    public: NodeKey,
+    signing: SigningKey,
+    /// Sequence number of the next signed update
+    seq: AtomicU64,
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({})", self.public.fingerprint())
+    }
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Identity::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// Load the secret key from `path`, creating it on first use.
+    ///
+    /// A new key file is only readable by its owner. If another start created it in the
+    /// meantime, its key is loaded instead of being overwritten.
+    pub fn load_or_generate(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(encoded) => {
+                let secret = STANDARD
+                    .decode(encoded.trim())
+                    .ok()
+                    .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} does not contain a key", path.display()),
+                        )
+                    })?;
+                Ok(Identity::from_secret(StaticSecret::from(secret)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Identity::generate();
+                let mut file = match OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)
+                {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        return Identity::load_or_generate(path)
+                    }
+                    Err(e) => return Err(e),
+                };
+                file.write_all(STANDARD.encode(identity.secret.to_bytes()).as_bytes())?;
+                tracing::info!("Created a new key in {}", path.display());
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = NodeKey(PublicKey::from(&secret).to_bytes());
+        let mut hasher = Sha256::new();
+        hasher.update(SIGNING_CONTEXT);
+        hasher.update(secret.as_bytes());
+        let signing = SigningKey::from_bytes(&hasher.finalize().into());
+        // Start above the sequence numbers an earlier run of this node has used
+        let seq = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or_default();
+        Identity {
+            secret,
+            public,
+            signing,
+            seq: AtomicU64::new(seq),
+        }
+    }
+
+    pub fn public_key(&self) -> NodeKey {
+        self.public
+    }
+
+    /// Key others verify our signatures with
+    pub fn signing_key(&self) -> NodeKey {
+        NodeKey(self.signing.verifying_key().to_bytes())
+    }
+
+    /// Sign `data` with the next sequence number.
+    pub fn sign(&self, data: &[u8]) -> Signed {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let signature = self.signing.sign(&signed_data(data, seq));
+        Signed {
+            key: self.signing_key(),
+            seq,
+            signature: STANDARD.encode(signature.to_bytes()),
+        }
+    }
+
+    /// Cipher shared by us and the owner of `key`, both ends derive the same one.
+    fn cipher(&self, key: &NodeKey) -> Result<ChaCha20Poly1305, Error> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(key.0));
+        if !shared.was_contributory() {
+            return Err(Error::InvalidKey(key.to_string()));
+        }
+
+        let (low, high) = if self.public.0 < key.0 {
+            (self.public.0, key.0)
+        } else {
+            (key.0, self.public.0)
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(KEY_CONTEXT);
+        hasher.update(shared.as_bytes());
+        hasher.update(low);
+        hasher.update(high);
+        Ok(ChaCha20Poly1305::new(&hasher.finalize()))
+    }
+
+    /// Encrypt the message of `packet` to the owner of `key`, replacing it with the base64 ciphertext.
+    ///
+    /// The addresses, the message id and the room are authenticated as well, so the
+    /// ciphertext can't be replayed as another message.
+    pub fn seal(&self, key: &NodeKey, packet: &mut RoutedPacket) -> Result<(), Error> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(packet);
+        let ciphertext = self
+            .cipher(key)?
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: packet.message.as_bytes(),
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| Error::PayloadTooLarge(packet.message.len()))?;
+
+        packet.message = STANDARD.encode(ciphertext);
+        packet.sealed = Some(Sealed {
+            key: self.public,
+            nonce: STANDARD.encode(nonce),
+        });
+        Ok(())
+    }
+
+    /// Decrypt a message sealed by the owner of `sealed.key` for us.
+    pub fn open(&self, sealed: &Sealed, packet: &RoutedPacket) -> Result<String, Error> {
+        let nonce = STANDARD
+            .decode(&sealed.nonce)
+            .ok()
+            .filter(|nonce| nonce.len() == 12)
+            .ok_or(Error::Decrypt)?;
+        let ciphertext = STANDARD
+            .decode(&packet.message)
+            .map_err(|_| Error::Decrypt)?;
+        let aad = associated_data(packet);
+        let plaintext = self
+            .cipher(&sealed.key)?
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| Error::Decrypt)?;
+        String::from_utf8(plaintext).map_err(|_| Error::Decrypt)
+    }
+}
+
+fn signed_data(data: &[u8], seq: u64) -> Vec<u8> {
+    let mut signed = seq.to_be_bytes().to_vec();
+    signed.extend_from_slice(data);
+    signed
+}
+
+fn associated_data(packet: &RoutedPacket) -> Vec<u8> {
+    let header = packet.header;
+    let id = packet.id.unwrap_or_default();
+    let room = packet.room.as_deref().unwrap_or_default();
+    format!("{}|{}|{}|{}", header.source, header.dest, id, room).into_bytes()
+}
+
+#[test]
+fn test_seal_and_open() {
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let mut packet = RoutedPacket {
+        header: crate::protocol::shared_header::SharedHeader {
+            source: "127.0.0.1:6142".parse().unwrap(),
+            dest: "[::1]:6143".parse().unwrap(),
+            ttl: 16,
+        },
+        nickname: "alice".to_string(),
+        message: "hello".to_string(),
+        id: Some(1),
+        sealed: None,
+        room: Some("#ops".to_string()),
+        fragment: None,
+        typing: false,
+        trace: None,
+        ping: None,
+    };
+
+    alice.seal(&bob.public_key(), &mut packet).unwrap();
+    let sealed = packet.sealed.clone().unwrap();
+    assert_eq!(sealed.key, alice.public_key());
+    assert!(!packet.message.contains("hello"));
+    assert_eq!(bob.open(&sealed, &packet).unwrap(), "hello");
+
+    // Someone else's key, another message id or room or a changed ciphertext don't open
+    let eve = Identity::generate();
+    assert!(matches!(eve.open(&sealed, &packet), Err(Error::Decrypt)));
+    let other_id = RoutedPacket {
+        id: Some(2),
+        ..packet.clone()
+    };
+    assert!(matches!(bob.open(&sealed, &other_id), Err(Error::Decrypt)));
+    let other_room = RoutedPacket {
+        room: Some("#dev".to_string()),
+        ..packet.clone()
+    };
+    assert!(matches!(
+        bob.open(&sealed, &other_room),
+        Err(Error::Decrypt)
+    ));
+    let mut tampered = STANDARD.decode(&packet.message).unwrap();
+    tampered[0] ^= 1;
+    packet.message = STANDARD.encode(tampered);
+    assert!(matches!(bob.open(&sealed, &packet), Err(Error::Decrypt)));
+}
+
+#[test]
+fn test_sign_and_verify() {
+    let alice = Identity::generate();
+    let signed = alice.sign(b"table");
+    assert_eq!(signed.key, alice.signing_key());
+    assert_ne!(signed.key, alice.public_key());
+    signed.verify(b"table").unwrap();
+    assert!(alice.sign(b"table").seq > signed.seq);
+
+    // Other data, another sequence number or someone else's key don't verify
+    assert!(matches!(signed.verify(b"tables"), Err(Error::BadSignature)));
+    let replayed = Signed {
+        seq: signed.seq + 1,
+        ..signed.clone()
+    };
+    assert!(matches!(
+        replayed.verify(b"table"),
+        Err(Error::BadSignature)
+    ));
+    let forged = Signed {
+        key: Identity::generate().signing_key(),
+        ..signed
+    };
+    assert!(matches!(forged.verify(b"table"), Err(Error::BadSignature)));
+}
+
+#[test]
+fn test_node_key_encoding() {
+    let key = Identity::generate().public_key();
+    let json = serde_json::to_string(&key).unwrap();
+    assert_eq!(serde_json::from_str::<NodeKey>(&json).unwrap(), key);
+    assert_eq!(key.fingerprint().len(), 19);
+    assert!(matches!(
+        "not a key".parse::<NodeKey>(),
+        Err(Error::InvalidKey(_))
+    ));
+    assert!(matches!(
+        STANDARD.encode([0u8; 16]).parse::<NodeKey>(),
+        Err(Error::InvalidKey(_))
+    ));
+}
+
+#[test]
+fn test_identity_is_persisted() {
+    let path = std::env::temp_dir().join(format!("morganite-key-{}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let created = Identity::load_or_generate(&path).unwrap();
+    let loaded = Identity::load_or_generate(&path).unwrap();
+    assert_eq!(created.public_key(), loaded.public_key());
+    assert_eq!(created.signing_key(), loaded.signing_key());
+    // Nobody else gets to read it
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::channel_events::ChannelEvent;
+use crate::state::StateHandle;
+
+/// How often the retransmit task looks for messages whose ACK is overdue
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long the ids of broadcasts are remembered, every copy arrives long before
+pub const BROADCAST_WINDOW: Duration = Duration::from_secs(60);
+
+/// Longest wait for an ACK, an `Instant` can't be moved arbitrarily far into the future
+const MAX_ACK_WAIT: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+/// `ack_timeout` doubled for every retransmission so far, capped at `MAX_ACK_WAIT`
+fn ack_wait(ack_timeout: Duration, retransmits: u32) -> Duration {
+    2u32.checked_pow(retransmits)
+        .and_then(|factor| ack_timeout.checked_mul(factor))
+        .map_or(MAX_ACK_WAIT, |wait| wait.min(MAX_ACK_WAIT))
+}
+
+/// A message waiting for its ACK
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outgoing {
+    pub dest: SocketAddr,
+    /// Our address the message was sent from first, the retransmissions take the same route
+    pub local: SocketAddr,
+    pub message: String,
+    /// Room the message was said in
+    pub room: Option<String>,
+    retransmits: u32,
+    deadline: Instant,
+}
+
+/// Messages whose ACK is overdue, by id
+#[derive(Debug, Default)]
+pub struct Due {
+    /// Still worth another try
+    pub retransmit: Vec<(u64, Outgoing)>,
+    /// Out of retransmissions
+    pub failed: Vec<(u64, Outgoing)>,
+}
+
+/// Messages we sent that haven't been acknowledged yet.
+///
+/// The wait for an ACK doubles with every retransmission, after `max_retransmits`
+/// the message is given up on.
+#[derive(Debug)]
+pub struct Outbox {
+    ack_timeout: Duration,
+    max_retransmits: u32,
+    next_id: u64,
+    pending: HashMap<u64, Outgoing>,
+}
+
+impl Outbox {
+    pub fn new(ack_timeout: Duration, max_retransmits: u32) -> Self {
+        // Start where the ids of an earlier run of this node can't be in flight anymore
+        let next_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or_default();
+        Outbox {
+            ack_timeout,
+            max_retransmits,
+            next_id,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Start waiting for the ACK of a new message sent from `local`, returns its id.
+    pub fn track(
+        &mut self,
+        dest: SocketAddr,
+        local: SocketAddr,
+        message: String,
+        room: Option<String>,
+        now: Instant,
+    ) -> u64 {
+        let id = self.next_id();
+        self.pending.insert(
+            id,
+            Outgoing {
+                dest,
+                local,
+                message,
+                room,
+                retransmits: 0,
+                deadline: now + ack_wait(self.ack_timeout, 0),
+            },
+        );
+        id
+    }
+
+    /// A fresh id, also used for broadcasts which aren't acknowledged.
+    pub fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        id
+    }
+
+    /// `source` acknowledged the message `id`, returns it unless it was someone else's to acknowledge.
+    pub fn acknowledge(&mut self, source: SocketAddr, id: u64) -> Option<Outgoing> {
+        match self.pending.get(&id) {
+            Some(outgoing) if outgoing.dest == source => self.pending.remove(&id),
+            _ => None,
+        }
+    }
+
+    /// Messages whose ACK is overdue, the ones out of retransmissions are forgotten.
+    pub fn due(&mut self, now: Instant) -> Due {
+        let mut due = Due::default();
+        let mut failed = Vec::new();
+        for (id, outgoing) in self.pending.iter_mut() {
+            if outgoing.deadline > now {
+                continue;
+            }
+            if outgoing.retransmits < self.max_retransmits {
+                outgoing.retransmits += 1;
+                outgoing.deadline = now + ack_wait(self.ack_timeout, outgoing.retransmits);
+                due.retransmit.push((*id, outgoing.clone()));
+            } else {
+                failed.push(*id);
+            }
+        }
+
+        for id in failed {
+            if let Some(outgoing) = self.pending.remove(&id) {
+                due.failed.push((id, outgoing));
+            }
+        }
+        due
+    }
+
+    /// Number of messages waiting for their ACK
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Ids of the messages we received recently, a lost ACK makes the source send them again.
+///
+/// They are forgotten by `expire`, oldest first.
+#[derive(Debug)]
+pub struct Inbox {
+    window: Duration,
+    seen: HashSet<(SocketAddr, u64)>,
+    /// The ids in `seen` in the order they arrived
+    arrivals: VecDeque<(Instant, (SocketAddr, u64))>,
+}
+
+impl Inbox {
+    /// Remember ids for `window`, the time a source keeps retransmitting
+    pub fn new(window: Duration) -> Self {
+        Inbox {
+            window,
+            seen: HashSet::new(),
+            arrivals: VecDeque::new(),
+        }
+    }
+
+    /// Whether this is the first time the message `id` of `source` arrived
+    pub fn first_delivery(&mut self, source: SocketAddr, id: u64, now: Instant) -> bool {
+        let first = self.seen.insert((source, id));
+        if first {
+            self.arrivals.push_back((now, (source, id)));
+        }
+        first
+    }
+
+    /// Forget the ids that arrived longer than the window ago
+    pub fn expire(&mut self, now: Instant) {
+        while let Some((received, key)) = self.arrivals.front() {
+            if now.duration_since(*received) < self.window {
+                break;
+            }
+            self.seen.remove(key);
+            self.arrivals.pop_front();
+        }
+    }
+
+    /// Number of ids remembered
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+/// Loop sending the messages again whose ACK didn't arrive in time
+pub(crate) async fn retransmit(state: StateHandle) {
+    let mut interval = tokio::time::interval(RETRANSMIT_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        state.expire_deliveries();
+        for (id, outgoing) in state.due_retransmits().await {
+            tracing::info!("Retransmitting message {} to {}", id, outgoing.dest);
+            let event = ChannelEvent::Message(outgoing.message, outgoing.dest, id, outgoing.room);
+            if let Err(e) = state.route(outgoing.dest, outgoing.local, event) {
+                tracing::info!("Error retransmitting message {}: {}", id, e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_outbox_backoff() {
+    let dest = "127.0.0.1:6143".parse().unwrap();
+    let local = "127.0.0.1:6142".parse().unwrap();
+    let now = Instant::now();
+    let mut outbox = Outbox::new(Duration::from_secs(2), 2);
+    let id = outbox.track(dest, local, "hello".to_string(), None, now);
+    assert_ne!(
+        outbox.track(dest, local, "again".to_string(), None, now),
+        id
+    );
+
+    let due = outbox.due(now + Duration::from_secs(1));
+    assert!(due.retransmit.is_empty() && due.failed.is_empty());
+
+    // Retransmitted after 2, 2 + 4 seconds, given up on 2 + 4 + 8 seconds after sending
+    let mut elapsed = 0;
+    for wait in [2, 4] {
+        elapsed += wait;
+        let due = outbox.due(now + Duration::from_secs(elapsed));
+        assert_eq!(due.retransmit.len(), 2);
+        // Sent again the way they went first
+        assert!(due
+            .retransmit
+            .iter()
+            .all(|(_, outgoing)| outgoing.local == local));
+        assert!(due.failed.is_empty());
+        let due = outbox.due(now + Duration::from_secs(elapsed + wait));
+        assert!(due.retransmit.is_empty());
+    }
+    let due = outbox.due(now + Duration::from_secs(elapsed + 8));
+    assert!(due.retransmit.is_empty());
+    assert_eq!(due.failed.len(), 2);
+    assert!(outbox.is_empty());
+}
+
+#[test]
+fn test_outbox_huge_config() {
+    let dest = "127.0.0.1:6143".parse().unwrap();
+    let local = "127.0.0.1:6142".parse().unwrap();
+    let now = Instant::now();
+    let mut outbox = Outbox::new(Duration::from_secs(u64::MAX), u32::MAX);
+    outbox.track(dest, local, "hello".to_string(), None, now);
+    // Nothing overflows, the message just waits a long time for its ACK
+    assert!(outbox
+        .due(now + Duration::from_secs(60))
+        .retransmit
+        .is_empty());
+    let later = now + MAX_ACK_WAIT;
+    assert_eq!(outbox.due(later).retransmit.len(), 1);
+    assert_eq!(outbox.due(later + MAX_ACK_WAIT).retransmit.len(), 1);
+}
+
+#[test]
+fn test_outbox_acknowledge() {
+    let dest = "[::1]:6143".parse().unwrap();
+    let local = "[::1]:6142".parse().unwrap();
+    let other = "127.0.0.1:6144".parse().unwrap();
+    let now = Instant::now();
+    let mut outbox = Outbox::new(Duration::from_secs(2), 2);
+    let id = outbox.track(dest, local, "hello".to_string(), None, now);
+
+    // Only the destination can acknowledge its message
+    assert!(outbox.acknowledge(other, id).is_none());
+    assert!(outbox.acknowledge(dest, id + 1).is_none());
+    assert_eq!(outbox.len(), 1);
+    assert_eq!(outbox.acknowledge(dest, id).unwrap().message, "hello");
+    assert!(outbox.acknowledge(dest, id).is_none());
+    assert!(outbox.due(now + Duration::from_secs(60)).failed.is_empty());
+}
+
+#[test]
+fn test_inbox_drops_duplicates() {
+    let source = "127.0.0.1:6142".parse().unwrap();
+    let now = Instant::now();
+    let mut inbox = Inbox::new(Duration::from_secs(30));
+    assert!(inbox.first_delivery(source, 1, now));
+    assert!(!inbox.first_delivery(source, 1, now + Duration::from_secs(10)));
+    assert!(inbox.first_delivery(source, 2, now));
+    assert!(inbox.first_delivery("127.0.0.1:6143".parse().unwrap(), 1, now));
+    assert!(!inbox.first_delivery(source, 2, now + Duration::from_secs(20)));
+
+    // Forgotten once the source stopped retransmitting, the ones that came later stay
+    assert!(inbox.first_delivery(source, 3, now + Duration::from_secs(20)));
+    inbox.expire(now + Duration::from_secs(30));
+    assert_eq!(inbox.len(), 1);
+    assert!(inbox.first_delivery(source, 1, now + Duration::from_secs(31)));
+    assert!(!inbox.first_delivery(source, 3, now + Duration::from_secs(31)));
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::queue::QueueError;
+
+/// Everything that can go wrong handling the packets of a peer.
+///
+/// Malformed input ends up here instead of in a panic, the caller logs and rejects it.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the connection failed
+    Io(io::Error),
+
+    // Codec
+    /// More bytes are buffered than we accept for a single packet
+    FrameTooLarge(usize),
+    /// The common header is not JSON or one of its fields is not a number
+    InvalidHeader(String),
+    /// The payload doesn't match the checksum of its common header
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// The payload is not valid JSON for its packet type
+    InvalidPayload(serde_json::Error),
+    /// A packet we created could not be serialized
+    Serialize(serde_json::Error),
+    /// A packet we created doesn't fit the length field and can't be fragmented
+    PayloadTooLarge(usize),
+
+    // Protocol
+    /// The common header announces a packet type we don't know
+    UnknownPacketType(u8),
+    /// An address in a header or routing entry is not an IP address
+    InvalidAddress(String),
+    /// A public key is not 32 bytes of base64 or can't be used for a key exchange
+    InvalidKey(String),
+
+    // Encryption and signatures
+    /// A sealed message didn't decrypt, it was changed or isn't meant for us
+    Decrypt,
+    /// A node sent a key that differs from the one we pinned for it
+    KeyMismatch(SocketAddr),
+    /// A message arrived in plain text although we pinned the key of its source
+    Unsealed(SocketAddr),
+    /// A routing update has no signature, but the policy requires one
+    Unsigned,
+    /// The signature of a routing update doesn't match its content or key
+    BadSignature,
+    /// A routing update is not newer than the last one of its source
+    Replayed { seq: u64, last: u64 },
+
+    // Routing
+    /// There is no route to the destination
+    NoRoute(SocketAddr),
+    /// The destination is in the routing table, but its next hop is not connected
+    NoChannel(SocketAddr),
+    /// The queue of the next hop didn't take the packet
+    Queue(QueueError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::FrameTooLarge(len) => write!(f, "Buffer too large: {} bytes", len),
+            Error::InvalidHeader(reason) => write!(f, "Invalid common header: {}", reason),
+            Error::ChecksumMismatch { expected, actual } => {
+                write!(f, "Checksum mismatch: {} != {}", actual, expected)
+            }
+            Error::InvalidPayload(e) => write!(f, "Invalid payload: {}", e),
+            Error::Serialize(e) => write!(f, "Error serializing packet: {}", e),
+            Error::PayloadTooLarge(len) => {
+                write!(f, "Payload too large: {} bytes, at most {}", len, u16::MAX)
+            }
+            Error::UnknownPacketType(type_id) => write!(f, "Unknown packet type: {}", type_id),
+            Error::InvalidAddress(ip) => write!(f, "Invalid IP address: {:?}", ip),
+            Error::InvalidKey(key) => write!(f, "Invalid public key: {:?}", key),
+            Error::Decrypt => write!(f, "Message could not be decrypted"),
+            Error::KeyMismatch(addr) => {
+                write!(f, "Key of {} differs from the pinned one", addr)
+            }
+            Error::Unsealed(addr) => {
+                write!(
+                    f,
+                    "Message of {} is not encrypted, although its key is known",
+                    addr
+                )
+            }
+            Error::Unsigned => write!(f, "Routing update is not signed"),
+            Error::BadSignature => write!(f, "Invalid signature"),
+            Error::Replayed { seq, last } => {
+                write!(
+                    f,
+                    "Replayed routing update: sequence number {} after {}",
+                    seq, last
+                )
+            }
+            Error::NoRoute(dest) => write!(f, "No route to destination: {} available", dest),
+            Error::NoChannel(dest) => write!(f, "No channel to destination: {} available", dest),
+            Error::Queue(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::InvalidPayload(e) | Error::Serialize(e) => Some(e),
+            Error::Queue(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<QueueError> for Error {
+    fn from(e: QueueError) -> Self {
+        Error::Queue(e)
+    }
+}
@@ -0,0 +1,415 @@
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::protocol::routed_packet::{Fragment, RoutedPacket};
+
+/// Bytes of the message carried by a single fragment.
+///
+/// Small enough that the fragment still fits the u16 length field if JSON
+/// escaping blows every byte up to six.
+pub const FRAGMENT_SIZE: usize = 8 * 1024;
+
+/// Fragments a message may be split into, larger messages are refused
+pub const MAX_FRAGMENTS: u16 = 128;
+
+/// Messages of a single source that may wait for fragments at once
+pub const MAX_PENDING_PER_SOURCE: usize = 8;
+
+/// Messages that may wait for fragments at once, over all sources
+pub const MAX_PENDING: usize = 64;
+
+/// Bytes of fragments that may be buffered at once, room for a few of the largest messages
+pub const MAX_PENDING_BYTES: usize = 4 * FRAGMENT_SIZE * MAX_FRAGMENTS as usize;
+
+/// Ids for the messages a node splits into fragments
+#[derive(Debug)]
+pub struct FragmentIds(AtomicU32);
+
+impl FragmentIds {
+    /// Start at a random id, so a node that restarts doesn't add its fragments to the
+    /// messages of its previous run a neighbour is still reassembling
+    pub fn new() -> Self {
+        FragmentIds(AtomicU32::new(OsRng.next_u32()))
+    }
+
+    fn next(&self) -> u32 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl Default for FragmentIds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Split the message of `packet` into fragments of at most `FRAGMENT_SIZE` bytes, with the
+/// next id of `ids`.
+///
+/// Returns `None` if the message would need more than `MAX_FRAGMENTS` fragments.
+pub fn split(packet: RoutedPacket, ids: &FragmentIds) -> Option<Vec<RoutedPacket>> {
+    let mut parts = Vec::new();
+    let mut rest = packet.message.as_str();
+    while !rest.is_empty() {
+        let mut end = rest.len().min(FRAGMENT_SIZE);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (part, tail) = rest.split_at(end);
+        parts.push(part);
+        rest = tail;
+    }
+
+    let count = u16::try_from(parts.len())
+        .ok()
+        .filter(|count| *count <= MAX_FRAGMENTS)?;
+    let id = ids.next();
+    Some(
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(index, part)| RoutedPacket {
+                header: packet.header,
+                nickname: packet.nickname.clone(),
+                message: part.to_string(),
+                id: packet.id,
+                sealed: packet.sealed.clone(),
+                room: packet.room.clone(),
+                fragment: Some(Fragment {
+                    id,
+                    index: index as u16,
+                    count,
+                }),
+                typing: packet.typing,
+                trace: packet.trace.clone(),
+                ping: packet.ping,
+            })
+            .collect(),
+    )
+}
+
+/// A message of which some fragments arrived
+#[derive(Debug)]
+struct Pending {
+    started: Instant,
+    parts: Vec<Option<String>>,
+    missing: usize,
+    bytes: usize,
+}
+
+/// Fragments waiting for the rest of their message, keyed by source and fragment id
+#[derive(Debug)]
+pub struct Reassembly {
+    timeout: Duration,
+    pending: HashMap<(SocketAddr, u32), Pending>,
+    /// Bytes of all buffered fragments
+    bytes: usize,
+}
+
+impl Reassembly {
+    pub fn new(timeout: Duration) -> Self {
+        Reassembly {
+            timeout,
+            pending: HashMap::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Add a fragment, returns the whole message once its last fragment arrived.
+    ///
+    /// Messages that didn't complete within the timeout are dropped. So are the oldest ones
+    /// once too many are waiting or they take up too much memory, a single node can't hold
+    /// on to more than `MAX_PENDING_PER_SOURCE` of them.
+    pub fn push(&mut self, packet: RoutedPacket, now: Instant) -> Option<RoutedPacket> {
+        self.expire(now);
+
+        let Some(fragment) = packet.fragment else {
+            return Some(packet);
+        };
+        if fragment.count == 0 || fragment.count > MAX_FRAGMENTS || fragment.index >= fragment.count
+        {
+            tracing::warn!(
+                "Dropping fragment {:?} of {}: invalid position",
+                fragment,
+                packet.header.source
+            );
+            return None;
+        }
+
+        let source = packet.header.source;
+        let key = (source, fragment.id);
+        if !self.pending.contains_key(&key) {
+            // Make room for another message, of this source and at all
+            let of_source = self.pending.keys().filter(|(s, _)| *s == source).count();
+            if of_source >= MAX_PENDING_PER_SOURCE {
+                self.evict_oldest(Some(source), key);
+            }
+            if self.pending.len() >= MAX_PENDING {
+                self.evict_oldest(None, key);
+            }
+        }
+        let pending = self.pending.entry(key).or_insert_with(|| Pending {
+            started: now,
+            parts: vec![None; fragment.count as usize],
+            missing: fragment.count as usize,
+            bytes: 0,
+        });
+        if pending.parts.len() != fragment.count as usize {
+            tracing::warn!(
+                "Dropping message {} of {}: fragment count changed",
+                fragment.id,
+                source
+            );
+            self.remove(&key);
+            return None;
+        }
+
+        let part = &mut pending.parts[fragment.index as usize];
+        let replaced = match part.replace(packet.message) {
+            Some(old) => old.len(),
+            None => {
+                pending.missing -= 1;
+                0
+            }
+        };
+        let added = part.as_ref().map_or(0, String::len);
+        pending.bytes = pending.bytes - replaced + added;
+        self.bytes = self.bytes - replaced + added;
+        let missing = pending.missing;
+
+        // The other messages make way for this one, unless it's too large by itself
+        while self.bytes > MAX_PENDING_BYTES {
+            if !self.evict_oldest(None, key) {
+                tracing::warn!(
+                    "Dropping message {} of {}: too large to buffer",
+                    fragment.id,
+                    source
+                );
+                self.remove(&key);
+                return None;
+            }
+        }
+        if missing > 0 {
+            return None;
+        }
+
+        let pending = self.remove(&key)?;
+        Some(RoutedPacket {
+            header: packet.header,
+            nickname: packet.nickname,
+            message: pending.parts.into_iter().flatten().collect(),
+            id: packet.id,
+            sealed: packet.sealed,
+            room: packet.room,
+            fragment: None,
+            typing: packet.typing,
+            trace: packet.trace,
+            ping: packet.ping,
+        })
+    }
+
+    /// Drop every message that has been waiting for longer than the timeout
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let bytes = &mut self.bytes;
+        self.pending.retain(|(source, id), pending| {
+            let alive = now.duration_since(pending.started) < timeout;
+            if !alive {
+                tracing::info!("Reassembly of message {} from {} timed out", id, source);
+                *bytes -= pending.bytes;
+            }
+            alive
+        });
+    }
+
+    /// Drop the message waiting the longest, only of `source` if given and never `keep`.
+    ///
+    /// Returns false if there was none to drop.
+    fn evict_oldest(&mut self, source: Option<SocketAddr>, keep: (SocketAddr, u32)) -> bool {
+        let oldest = self
+            .pending
+            .iter()
+            .filter(|(key, _)| **key != keep && source.is_none_or(|source| key.0 == source))
+            .min_by_key(|(_, pending)| pending.started)
+            .map(|(key, _)| *key);
+        let Some((source, id)) = oldest else {
+            return false;
+        };
+        tracing::warn!("Dropping message {} of {} to make room", id, source);
+        self.remove(&(source, id));
+        true
+    }
+
+    fn remove(&mut self, key: &(SocketAddr, u32)) -> Option<Pending> {
+        let pending = self.pending.remove(key)?;
+        self.bytes -= pending.bytes;
+        Some(pending)
+    }
+
+    /// Number of messages waiting for fragments
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Bytes of all buffered fragments
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+use crate::protocol::shared_header::SharedHeader;
+
+#[cfg(test)]
+fn packet(message: String) -> RoutedPacket {
+    RoutedPacket {
+        header: SharedHeader {
+            source: "127.0.0.1:6142".parse().unwrap(),
+            dest: "[::1]:6143".parse().unwrap(),
+            ttl: 16,
+        },
+        nickname: "Test".to_string(),
+        message,
+        id: None,
+        sealed: None,
+        room: None,
+        fragment: None,
+        typing: false,
+        trace: None,
+        ping: None,
+    }
+}
+
+#[test]
+fn test_split_and_reassemble() {
+    // Multi-byte characters right on the fragment boundaries
+    let message = "ä".repeat(FRAGMENT_SIZE) + "tail";
+    let mut fragments = split(packet(message.clone()), &FragmentIds::new()).unwrap();
+    assert_eq!(fragments.len(), 3);
+    assert!(fragments
+        .iter()
+        .all(|fragment| fragment.message.len() <= FRAGMENT_SIZE));
+
+    // Out of order and with a duplicate
+    let now = Instant::now();
+    let mut reassembly = Reassembly::new(Duration::from_secs(30));
+    fragments.reverse();
+    let last = fragments.pop().unwrap();
+    for fragment in fragments.iter().chain(&fragments) {
+        assert!(reassembly.push(fragment.clone(), now).is_none());
+    }
+    assert_eq!(reassembly.len(), 1);
+    assert_eq!(reassembly.push(last, now), Some(packet(message)));
+    assert!(reassembly.is_empty());
+}
+
+#[test]
+fn test_split_refuses_huge_messages() {
+    let message = "x".repeat(FRAGMENT_SIZE * MAX_FRAGMENTS as usize + 1);
+    assert!(split(packet(message), &FragmentIds::new()).is_none());
+}
+
+#[test]
+fn test_reassembly_timeout() {
+    let fragments = split(packet("x".repeat(FRAGMENT_SIZE + 1)), &FragmentIds::new()).unwrap();
+    let now = Instant::now();
+    let mut reassembly = Reassembly::new(Duration::from_secs(30));
+    assert!(reassembly.push(fragments[0].clone(), now).is_none());
+    assert_eq!(reassembly.len(), 1);
+
+    // The first fragment is gone, so the second one starts a new message
+    let later = now + Duration::from_secs(31);
+    assert!(reassembly.push(fragments[1].clone(), later).is_none());
+    assert_eq!(reassembly.len(), 1);
+    reassembly.expire(later + Duration::from_secs(31));
+    assert!(reassembly.is_empty());
+}
+
+#[test]
+fn test_reassembly_rejects_invalid_fragments() {
+    let mut reassembly = Reassembly::new(Duration::from_secs(30));
+    let now = Instant::now();
+    for (index, count) in [(0, 0), (3, 3), (0, MAX_FRAGMENTS + 1)] {
+        let mut fragment = packet("x".to_string());
+        fragment.fragment = Some(Fragment {
+            id: 1,
+            index,
+            count,
+        });
+        assert!(reassembly.push(fragment, now).is_none());
+    }
+    assert!(reassembly.is_empty());
+
+    // Unfragmented packets pass right through
+    let whole = packet("x".to_string());
+    assert_eq!(reassembly.push(whole.clone(), now), Some(whole));
+}
+
+#[test]
+fn test_reassembly_limits() {
+    let now = Instant::now();
+    let mut reassembly = Reassembly::new(Duration::from_secs(30));
+    let first_of = |source: &str, id: u32, message: String| {
+        let mut fragment = packet(message);
+        fragment.header.source = source.parse().unwrap();
+        fragment.fragment = Some(Fragment {
+            id,
+            index: 0,
+            count: MAX_FRAGMENTS,
+        });
+        fragment
+    };
+
+    // A single source only gets a few messages, its oldest one makes way
+    for id in 0..=MAX_PENDING_PER_SOURCE as u32 {
+        let later = now + Duration::from_millis(id.into());
+        reassembly.push(first_of("127.0.0.1:1", id, "x".to_string()), later);
+    }
+    assert_eq!(reassembly.len(), MAX_PENDING_PER_SOURCE);
+    assert!(!reassembly
+        .pending
+        .contains_key(&("127.0.0.1:1".parse().unwrap(), 0)));
+
+    // Neither do all of them together
+    for port in 2..=MAX_PENDING as u16 {
+        reassembly.push(
+            first_of(&format!("127.0.0.1:{port}"), 0, "x".to_string()),
+            now,
+        );
+    }
+    assert_eq!(reassembly.len(), MAX_PENDING);
+
+    // Nor can they buffer more than a few large messages
+    let mut reassembly = Reassembly::new(Duration::from_secs(30));
+    let fragments = MAX_PENDING_BYTES / FRAGMENT_SIZE;
+    for index in 0..=fragments {
+        let mut fragment = first_of("127.0.0.1:1", index as u32 / 100, "x".repeat(FRAGMENT_SIZE));
+        fragment.fragment.as_mut().unwrap().index = (index % 100) as u16;
+        let later = now + Duration::from_millis(index as u64);
+        assert!(reassembly.push(fragment, later).is_none());
+    }
+    assert!(reassembly.bytes() <= MAX_PENDING_BYTES);
+    assert!(!reassembly
+        .pending
+        .contains_key(&("127.0.0.1:1".parse().unwrap(), 0)));
+    reassembly.expire(now + Duration::from_secs(60));
+    assert_eq!(reassembly.bytes(), 0);
+}
+
+#[test]
+fn test_fragment_ids() {
+    // Every node counts on its own, starting somewhere else
+    let (alice, bob) = (FragmentIds::new(), FragmentIds::new());
+    let first = alice.next();
+    assert_eq!(alice.next(), first.wrapping_add(1));
+    assert_ne!(bob.next(), first.wrapping_add(2));
+}
@@ -0,0 +1,102 @@
+use rnp2::channel_events::{ChannelEvent, Commands};
+use rnp2::shared::{Rx, Tx};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use std::error::Error;
+
+/// Headless replacement for the TUI.
+///
+/// Reads one JSON encoded `Commands` per line from stdin and writes every event meant for the
+/// user interface as one JSON object per line to stdout.
+pub async fn headless(mut receiver: Rx, loopback: Tx) -> Result<(), Box<dyn Error>> {
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+
+    // Commands are buffered until the console middleware hands us its receiver
+    let mut sender: Option<Tx> = None;
+    let mut pending: Vec<ChannelEvent> = Vec::new();
+    let mut stdin_open = true;
+
+    loop {
+        tokio::select! {
+            Some(event) = receiver.recv() => {
+                match event {
+                    ChannelEvent::CommandReceiver(tx) => {
+                        for cmd in pending.drain(..) {
+                            let _ = tx.send(cmd);
+                        }
+                        sender = Some(tx);
+                    }
+                    event => {
+                        let mut line = match serde_json::to_string(&event) {
+                            Ok(line) => line,
+                            Err(_) => {
+                                // Not meant for the user interface
+                                tracing::debug!("Not writing event to stdout: {:?}", event);
+                                continue;
+                            }
+                        };
+                        line.push('\n');
+                        stdout.write_all(line.as_bytes()).await?;
+                        stdout.flush().await?;
+                    }
+                }
+            },
+            line = stdin.next_line(), if stdin_open => {
+                let line = match line? {
+                    Some(line) => line,
+                    None => {
+                        // Keep running as a daemon even if nobody is feeding us commands
+                        tracing::info!("stdin closed, no more commands will be read");
+                        stdin_open = false;
+                        continue;
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let event = match serde_json::from_str::<Commands>(&line) {
+                    Ok(Commands::Help) => {
+                        let _ = loopback.send(ChannelEvent::LogToTerminal(help_text()));
+                        continue;
+                    }
+                    Ok(cmd) => ChannelEvent::Command(cmd),
+                    Err(e) => {
+                        let _ = loopback.send(ChannelEvent::LogToTerminal(format!(
+                            "Invalid command {:?}: {}",
+                            line, e
+                        )));
+                        continue;
+                    }
+                };
+
+                match &sender {
+                    Some(tx) => {
+                        if let Err(e) = tx.send(event) {
+                            tracing::error!("Error sending command to console middleware: {:?}", e);
+                        }
+                    }
+                    None => pending.push(event),
+                }
+            },
+        }
+    }
+}
+
+fn help_text() -> String {
+    "Available commands (one JSON value per line): \
+    \"Quit\", \"Help\", \"Contacts\", \
+    {\"Message\":[\"<IP>:<port>\",\"<message>\"]}, \
+    {\"MessageByNick\":[\"<nickname>\",\"<message>\"]}, \
+    {\"Connect\":\"<IP>:<port>\"}, \
+    {\"Broadcast\":\"<message>\"}, \
+    {\"JoinRoom\":\"<room>\"}, {\"LeaveRoom\":\"<room>\"}, \
+    {\"Say\":[\"<room>\",\"<message>\"]}, \
+    {\"SendFile\":[\"<IP>:<port>\",\"<path>\"]}, {\"AcceptFile\":<id>}, {\"DeclineFile\":<id>}, \
+    {\"SetOwnNick\":\"<name>\"}, {\"SetPresence\":{\"state\":\"away\",\"text\":\"<text>\"}}, \
+    {\"Typing\":\"<IP>:<port>\"}, {\"Trace\":\"<IP>:<port>\"}, \
+    {\"Ping\":\"<IP>:<port>\"}"
+        .to_string()
+}
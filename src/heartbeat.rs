@@ -1,49 +1,64 @@
-use std::{sync::Arc, time::Duration};
+use crate::{channel_events::ChannelEvent, protocol::RoutingType, state::StateHandle};
 
-use tokio::sync::{mpsc::error::SendError, Mutex};
+use std::time::Duration;
 
-use crate::{
-    channel_events::ChannelEvent,
-    protocol::{SCC, STU},
-    shared::Shared,
-};
+pub async fn heartbeat(state: StateHandle) {
+    let config = state.config.clone();
 
-pub const POISE_UNREACHABLE: i32 = 32;
-
-pub async fn heartbeat(state: Arc<Mutex<Shared>>) -> Result<(), SendError<ChannelEvent>> {
     loop {
         // Send SCC to all peers
-        {
-            let lock = state.lock().await;
-            for tx in lock.peers.values() {
-                tx.send(ChannelEvent::Routing(SCC))?;
-            }
-        }
+        send_to_peers(&state, RoutingType::SCC);
 
-        // Give them 1 second to respond
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        // Give them some time to respond
+        tokio::time::sleep(config.scc_timeout()).await;
 
-        // Check for ttl flag
-        {
-            let mut lock = state.lock().await;
-            for entry in lock.routing_table.values_mut() {
-                if !entry.ttl {
-                    entry.hop_count = POISE_UNREACHABLE;
-                }
+        // Send STU to all peers
+        send_to_peers(&state, RoutingType::STU);
 
-                entry.ttl = false;
-            }
-        }
+        // Sleep until the next round
+        tokio::time::sleep(config.stu_interval()).await;
+    }
+}
 
-        // Send STU to all peers
-        {
-            let lock = state.lock().await;
-            for tx in lock.peers.values() {
-                tx.send(ChannelEvent::Routing(STU))?;
+/// Expire the routes of the state once a second
+pub async fn expiry(state: StateHandle) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        state.expire_routes();
+    }
+}
+
+/// Send a STU to the affected peers as soon as the routing table changes, at most one
+/// every `update_damping`.
+///
+/// The changes made during the damping leave a single permit of `routes_changed` behind,
+/// so all of them go out together in one update right after it. Only the peers whose
+/// advertisement differs from the one they got last are sent the update.
+pub async fn triggered_updates(state: StateHandle) {
+    let damping = state.config.update_damping();
+    loop {
+        state.routes_changed.notified().await;
+        tracing::debug!("Routing table changed, sending a triggered update");
+        for (addr, tx) in state.snapshot().peers.iter() {
+            if let Err(e) = tx.send(ChannelEvent::TriggeredUpdate) {
+                tracing::info!("Error sending a triggered update to {}: {}", addr, e);
             }
         }
+        tokio::time::sleep(damping).await;
+    }
+}
 
-        // Sleep 10 seconds
-        tokio::time::sleep(Duration::from_secs(10)).await;
+/// Queue a routing packet for every peer, a peer that is going away doesn't stop the others
+pub(crate) fn send_to_peers(state: &StateHandle, routing_type: RoutingType) {
+    for (addr, tx) in state.snapshot().peers.iter() {
+        if let Err(e) = tx.send(ChannelEvent::Routing(routing_type)) {
+            tracing::info!(
+                "Error sending routing packet {:?} to {}: {}",
+                routing_type,
+                addr,
+                e
+            );
+        }
     }
 }
@@ -0,0 +1,29 @@
+//! Morganite is a chat node for the HAW-RN pseudo chat protocol.
+//!
+//! The library contains everything needed to run a node: the SWAG codec, the
+//! distance vector routing and the per-peer connection handling. Embed it by
+//! binding a [`Node`] and subscribing to its [`NodeEvents`].
+
+pub mod channel_events;
+pub mod config;
+pub mod crypto;
+pub mod delivery;
+pub mod error;
+pub mod fragment;
+mod heartbeat;
+pub mod node;
+mod peer;
+pub mod presence;
+mod process;
+pub mod protocol;
+pub mod queue;
+pub mod rtt;
+pub mod shared;
+mod state;
+pub mod swag_coding;
+pub mod transfer;
+
+pub use channel_events::NodeEvent;
+pub use config::NodeConfig;
+pub use error::Error;
+pub use node::{Node, NodeEvents};
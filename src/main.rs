@@ -1,98 +1,116 @@
 use console_middleware::handle_console;
-use shared::Shared;
 
-use tokio::net::TcpListener;
-use tokio::sync::{mpsc, Mutex};
+use rnp2::Node;
 
-use tracing::Level;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
-use std::error::Error;
-use std::{env, thread};
+use clap::Parser;
 
-use crate::process::process;
-use std::sync::Arc;
+use std::error::Error;
+use std::io;
+use std::process::ExitCode;
+use std::thread;
 
 // TUI
 
-mod channel_events;
+mod cli;
 mod console_middleware;
-mod heartbeat;
-mod peer;
-mod process;
-mod protocol;
-mod shared;
-mod swag_coding;
+mod control;
+mod headless;
 mod tui;
 
 /// Use Tokio Runtime, Multi-Threaded with a Thread Pool based on the number of cores available
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let settings = cli::Settings::load(cli::Cli::parse())?;
+
     // Generate timestamp for log file
-    let timestamp = chrono::Local::now().format("%Y-%m-%d_%H-%M-%S-%f").to_string();
+    let timestamp = chrono::Local::now()
+        .format("%Y-%m-%d_%H-%M-%S-%f")
+        .to_string();
     // construct a subscriber that logs formatted traces to file
-    let file_appender = tracing_appender::rolling::never("logs", format!("morganite_{}.log", timestamp));
+    let file_appender =
+        tracing_appender::rolling::never(&settings.log_dir, format!("morganite_{}.log", timestamp));
     // use that subscriber to process traces emitted after this point
     tracing_subscriber::fmt()
         .compact()
         .with_writer(file_appender)
-        .with_max_level(Level::DEBUG)
+        .with_max_level(settings.log_level)
         .init();
 
-    // Create the shared state. This is how all the peers communicate.
-    //
-    // The server task will hold a handle to this. For every new client, the
-    // `state` handle is cloned and passed into the task that processes the
-    // client connection.
-    let (console_input_tx, console_input_rx) = mpsc::unbounded_channel();
-    let state = Arc::new(Mutex::new(Shared::new(console_input_tx)));
-
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:6142".to_string());
-
-    // Bind a TCP listener to the socket address.
-    //
-    // Note that this is the Tokio TcpListener, which is fully async.
-    let listener = TcpListener::bind(&addr).await?;
-    //add listener addr to shared space
-    state.lock().await.listener_addr = addr.clone();
-    tracing::info!("server running on {}", addr);
-
-    // Spawn heartbeat task
-    let heartbeat_state = Arc::clone(&state);
-    tokio::spawn(async move {
-        tracing::debug!("created heartbeat task");
-        if let Err(e) = heartbeat::heartbeat(heartbeat_state).await {
-            tracing::info!("an error occurred; error = {:?}", e);
-        }
-    });
-
-    // Spawn console middleware
-    let console_state = Arc::clone(&state);
-    tokio::spawn(async move {
-        tracing::debug!("created console middleware task");
-        if let Err(e) = handle_console(console_state).await {
-            tracing::info!("an error occurred; error = {:?}", e);
-        }
-    });
+    // Bind the node, this starts accepting connections and the heartbeat
+    let node = Node::bind_all(settings.bind.iter().map(String::as_str), settings.node).await?;
 
-    // Spawn new thread for TUI
-    thread::spawn(move || tui::tui(console_input_rx));
+    // Connect to the bootstrap peers
+    for peer in settings.peers {
+        if let Err(e) = node.connect(peer).await {
+            tracing::error!("Failed to connect to {}: {}", peer, e);
+        }
+    }
 
-    //Loop accepting new connections from other clients creating a task for each of them handling their messages
-    loop {
-        // Asynchronously wait for an inbound TcpStream.
-        let (stream, addr) = listener.accept().await?;
+    // Cancelled by the quit command of any interface
+    let shutdown = CancellationToken::new();
 
-        // Clone a handle to the `Shared` state for the new connection.
-        let state = Arc::clone(&state);
+    // Serve the control socket next to whatever user interface we run
+    if let Some(path) = settings.control {
+        let control_node = node.clone();
+        let control_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control::serve(&path, control_node, control_shutdown).await {
+                tracing::error!("an error occurred on the control socket; error = {:?}", e);
+            }
+        });
+    }
 
-        // Spawn our handler to be run asynchronously.
+    let (console_input_tx, console_input_rx) = mpsc::unbounded_channel();
+    let mut tui_thread = None;
+    if settings.headless {
+        // Spawn the JSON lines interface on stdin/stdout instead of the TUI
+        let loopback = console_input_tx.clone();
         tokio::spawn(async move {
-            tracing::info!("accepted connection to {}", addr);
-            if let Err(e) = process(state, stream, addr, false).await {
-                tracing::info!("an error occurred; error = {:?}", e);
+            if let Err(e) = headless::headless(console_input_rx, loopback).await {
+                tracing::error!("an error occurred in headless mode; error = {:?}", e);
             }
         });
+    } else {
+        // Spawn new thread for TUI
+        tui_thread = Some(thread::spawn(move || tui::tui(console_input_rx)));
+    }
+
+    // The console middleware translates between the user interface and the node until we quit
+    tracing::debug!("created console middleware task");
+    let exit_code = tokio::select! {
+        result = handle_console(node.clone(), console_input_tx, shutdown) => {
+            result?;
+            ExitCode::SUCCESS
+        }
+        exit_code = shutdown_signal() => exit_code?,
+    };
+
+    // Say goodbye to the peers, then let the TUI restore the terminal now that its channel is closed
+    tracing::info!("shutting down");
+    node.shutdown().await;
+    if let Some(tui_thread) = tui_thread {
+        match tui_thread.join() {
+            Ok(Err(e)) => tracing::error!("an error occurred in the TUI; error = {:?}", e),
+            Err(_) => tracing::error!("the TUI panicked"),
+            Ok(Ok(())) => {}
+        }
     }
+
+    Ok(exit_code)
+}
+
+/// Wait for SIGINT or SIGTERM, returning the exit code a shell expects for it
+async fn shutdown_signal() -> io::Result<ExitCode> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let signal = tokio::select! {
+        _ = interrupt.recv() => SignalKind::interrupt(),
+        _ = terminate.recv() => SignalKind::terminate(),
+    };
+    tracing::info!("received signal {}", signal.as_raw_value());
+    Ok(ExitCode::from(128 + signal.as_raw_value() as u8))
 }
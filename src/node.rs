@@ -0,0 +1,1376 @@
+#[cfg(test)]
+use futures::SinkExt;
+#[cfg(test)]
+use sha2::Digest;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::Stream;
+use tokio_stream::StreamExt;
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+use crate::channel_events::FileStatus;
+use crate::channel_events::{ChannelEvent, NodeEvent};
+use crate::config::NodeConfig;
+use crate::crypto::{Identity, NodeKey};
+use crate::delivery;
+use crate::error::Error;
+use crate::heartbeat;
+use crate::presence::Presence;
+use crate::process::{self, spawn_peer};
+use crate::protocol::broadcast_packet::BroadcastPacket;
+use crate::protocol::file_packet::FilePacket;
+use crate::protocol::routed_packet::{Ping, RoutedPacket, Trace};
+use crate::protocol::shared_header::SharedHeader;
+use crate::protocol::{Packet, RoutingType};
+use crate::queue::QueueStats;
+use crate::rtt::RttEstimate;
+use crate::shared::{canonical, RoutingTableEntry, Shared, EVENT_CAPACITY};
+use crate::state::StateHandle;
+use crate::transfer::{self, MAX_FILE_SIZE};
+
+/// Time peers get to receive their final STU before `Node::shutdown` gives up on them.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Time the destination of a ping gets to answer it
+pub const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running Morganite node.
+///
+/// Binding a node starts the listener and the heartbeat in the background.
+/// The handle is cheap to clone, every clone talks to the same node.
+#[derive(Clone)]
+pub struct Node {
+    state: StateHandle,
+    local_addr: SocketAddr,
+}
+
+/// Async stream of the events published by a `Node`.
+///
+/// Events that are missed because the subscriber fell too far behind are skipped.
+pub struct NodeEvents {
+    inner: BroadcastStream<NodeEvent>,
+}
+
+impl Stream for NodeEvents {
+    type Item = NodeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(event))) => return Poll::Ready(Some(event)),
+                Poll::Ready(Some(Err(BroadcastStreamRecvError::Lagged(skipped)))) => {
+                    tracing::warn!("Event subscriber lagged behind, skipped {} events", skipped);
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Node {
+    /// Bind a new node with the default configuration to the given address and start serving it.
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Node> {
+        Node::bind_with_config(addr, NodeConfig::default()).await
+    }
+
+    /// Bind a new node to the given address and start serving it.
+    pub async fn bind_with_config<A: ToSocketAddrs>(
+        addr: A,
+        config: NodeConfig,
+    ) -> io::Result<Node> {
+        Node::bind_all([addr], config).await
+    }
+
+    /// Bind a new node listening on every given address, e.g. one IPv4 and one IPv6 address.
+    ///
+    /// The first address is the primary one, returned by `local_addr`.
+    pub async fn bind_all<A: ToSocketAddrs>(
+        addrs: impl IntoIterator<Item = A>,
+        config: NodeConfig,
+    ) -> io::Result<Node> {
+        config
+            .validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        // Bind a TCP listener to each socket address.
+        //
+        // Note that this is the Tokio TcpListener, which is fully async.
+        let mut listeners = Vec::new();
+        for addr in addrs {
+            let listener = TcpListener::bind(addr).await?;
+            let local_addr = listener.local_addr()?;
+            tracing::info!("server running on {}", local_addr);
+            listeners.push((listener, local_addr));
+        }
+        let local_addr = match listeners.first() {
+            Some((_, local_addr)) => *local_addr,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "No address to listen on",
+                ))
+            }
+        };
+
+        // Create the shared state. This is how all the peers communicate.
+        //
+        // The state is owned by its own task. The server task will hold a handle
+        // to it. For every new client, the handle is cloned and passed into the
+        // task that processes the client connection.
+        let (event_sender, _) = broadcast::channel(EVENT_CAPACITY);
+        let mut shared = Shared::new(event_sender, config);
+        shared.listener_addrs = listeners.iter().map(|(_, addr)| *addr).collect();
+        if let Some(path) = &shared.config.key_file {
+            shared.identity = Arc::new(Identity::load_or_generate(path)?);
+        }
+        tracing::info!(
+            "key fingerprint: {}",
+            shared.identity.public_key().fingerprint()
+        );
+        let state = StateHandle::spawn(shared);
+
+        // Spawn heartbeat task
+        let heartbeat_state = state.clone();
+        state.tasks.spawn(async move {
+            tracing::debug!("created heartbeat task");
+            let shutdown = heartbeat_state.shutdown.clone();
+            tokio::select! {
+                _ = heartbeat::heartbeat(heartbeat_state) => {}
+                _ = shutdown.cancelled() => tracing::debug!("heartbeat stopped"),
+            }
+        });
+
+        // Spawn the task telling the peers about changed routes right away
+        let triggered_state = state.clone();
+        state.tasks.spawn(async move {
+            let shutdown = triggered_state.shutdown.clone();
+            tokio::select! {
+                _ = heartbeat::triggered_updates(triggered_state) => {}
+                _ = shutdown.cancelled() => tracing::debug!("stopped triggered updates"),
+            }
+        });
+
+        // Spawn the task expiring stale routes
+        let expiry_state = state.clone();
+        state.tasks.spawn(async move {
+            let shutdown = expiry_state.shutdown.clone();
+            tokio::select! {
+                _ = heartbeat::expiry(expiry_state) => {}
+                _ = shutdown.cancelled() => tracing::debug!("stopped expiring routes"),
+            }
+        });
+
+        // Spawn the task retransmitting unacknowledged messages
+        let retransmit_state = state.clone();
+        state.tasks.spawn(async move {
+            let shutdown = retransmit_state.shutdown.clone();
+            tokio::select! {
+                _ = delivery::retransmit(retransmit_state) => {}
+                _ = shutdown.cancelled() => tracing::debug!("stopped retransmitting"),
+            }
+        });
+
+        // Spawn the tasks accepting new connections from other clients
+        for (listener, _) in listeners {
+            let accept_state = state.clone();
+            state.tasks.spawn(async move {
+                let shutdown = accept_state.shutdown.clone();
+                tokio::select! {
+                    _ = accept(listener, accept_state) => {}
+                    _ = shutdown.cancelled() => tracing::debug!("stopped accepting connections"),
+                }
+            });
+        }
+
+        Ok(Node { state, local_addr })
+    }
+
+    /// The primary address this node is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The configuration the node was bound with.
+    pub fn config(&self) -> &NodeConfig {
+        &self.state.config
+    }
+
+    /// All addresses this node is listening on.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.state.listener_addrs.to_vec()
+    }
+
+    /// Subscribe to the events of this node.
+    pub fn events(&self) -> NodeEvents {
+        NodeEvents {
+            inner: BroadcastStream::new(self.state.subscribe()),
+        }
+    }
+
+    /// Open a direct connection to another node, unless we already have one.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        let addr = canonical(addr);
+        //check wether there is already a direct connection to the target client:
+        let already_connected = match self.state.snapshot().routing_table.get(&addr) {
+            Some(direct) => direct.hop_count == 1,
+            None => false,
+        };
+        if already_connected {
+            return Ok(());
+        }
+
+        // Connect to specified client
+        tracing::debug!("Connecting to: {}", addr);
+        let stream = TcpStream::connect(addr).await?;
+        tracing::info!("Connected to: {}", addr);
+
+        //add new connection to routing table, we were told about it so it never goes away
+        let mut entry = RoutingTableEntry::new(addr, 1);
+        entry.dont_expire = true;
+        self.state.insert_route(addr, entry);
+
+        // Spawn asynchronous handler
+        spawn_peer(self.state.clone(), stream, addr, true).await
+    }
+
+    /// Send a chat message to the given node along the current route, returns its id.
+    ///
+    /// The message is sent again until the destination acknowledges it, its fate is
+    /// published as a `NodeEvent::Delivery` with this id.
+    pub async fn send_message(&self, dest: SocketAddr, message: String) -> u64 {
+        self.send(canonical(dest), message, None).await
+    }
+
+    async fn send(&self, dest: SocketAddr, message: String, room: Option<String>) -> u64 {
+        let id = self
+            .state
+            .track_message(dest, self.local_addr, message.clone(), room.clone())
+            .await;
+        let encrypted =
+            self.state.config.encryption && self.state.snapshot().keys.contains_key(&dest);
+        self.state.emit(NodeEvent::Sent {
+            id,
+            dest,
+            message: message.clone(),
+            encrypted,
+            room: room.clone(),
+        });
+
+        // Failing to queue it is no different from losing it on the way, it's retransmitted
+        if let Err(e) = self.state.route(
+            dest,
+            self.local_addr,
+            ChannelEvent::Message(message, dest, id, room),
+        ) {
+            tracing::info!("Error sending message {} to {}: {}", id, dest, e);
+        }
+        id
+    }
+
+    /// Become a member of `room`, e.g. `#ops`.
+    ///
+    /// Our neighbours learn it right away, the rest of the network with the next routing updates.
+    pub async fn join_room(&self, room: String) {
+        self.state.join_room(room).await;
+        heartbeat::send_to_peers(&self.state, RoutingType::STU);
+    }
+
+    /// Stop being a member of `room`, its messages are dropped from now on.
+    pub async fn leave_room(&self, room: String) {
+        self.state.leave_room(room).await;
+        heartbeat::send_to_peers(&self.state, RoutingType::STU);
+    }
+
+    /// Send a chat message to every member of `room` we know of, returns the id of every copy.
+    ///
+    /// Each member acknowledges its copy on its own, like a message sent to it alone.
+    pub async fn say(&self, room: String, message: String) -> Vec<u64> {
+        let mut ids = Vec::new();
+        for dest in self.room_members(&room).await {
+            ids.push(self.send(dest, message.clone(), Some(room.clone())).await);
+        }
+        ids
+    }
+
+    /// The rooms we are a member of.
+    pub async fn rooms(&self) -> BTreeSet<String> {
+        self.state.snapshot().rooms.clone()
+    }
+
+    /// The other members of `room` we have a route to.
+    pub async fn room_members(&self, room: &str) -> Vec<SocketAddr> {
+        self.state
+            .snapshot()
+            .room_members(room, self.state.config.unreachable_metric)
+    }
+
+    /// Send a chat message to every node within reach of our TTL, returns its id.
+    ///
+    /// The message is flooded: each node passes it on to its neighbours once, so every link
+    /// carries one copy at most. Broadcasts are neither encrypted nor acknowledged.
+    pub async fn broadcast(&self, message: String) -> u64 {
+        tracing::debug!("Broadcasting message: {}", message);
+        let broadcast = BroadcastPacket {
+            header: SharedHeader {
+                source: self.local_addr,
+                dest: self.local_addr,
+                ttl: self.state.config.default_ttl,
+            },
+            id: self.state.next_id().await,
+            nickname: self.state.snapshot().nickname.clone(),
+            message,
+        };
+        let id = broadcast.id;
+        process::flood(&self.state, broadcast, None);
+        id
+    }
+
+    /// Offer a file to `dest` and send it once it's accepted, returns the id of the transfer.
+    ///
+    /// The file is read into memory, transfers are meant for small files like logs and configs.
+    /// Its progress is published as `NodeEvent::FileProgress`, its end as `NodeEvent::FileDone`.
+    pub async fn send_file(&self, dest: SocketAddr, path: impl AsRef<Path>) -> io::Result<u64> {
+        let path = path.as_ref();
+        let Some(name) = path.file_name() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file", path.display()),
+            ));
+        };
+        let name = name.to_string_lossy().into_owned();
+        if tokio::fs::metadata(path).await?.len() > MAX_FILE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is larger than {} bytes", path.display(), MAX_FILE_SIZE),
+            ));
+        }
+        let content = tokio::fs::read(path).await?;
+
+        let id = self.state.next_id().await;
+        let state = self.state.clone();
+        let (local, dest) = (self.local_addr, canonical(dest));
+        self.state.tasks.spawn(async move {
+            let shutdown = state.shutdown.clone();
+            tokio::select! {
+                _ = transfer::send(state, local, dest, id, name, content) => {}
+                _ = shutdown.cancelled() => tracing::debug!("stopped sending file {}", id),
+            }
+        });
+        Ok(id)
+    }
+
+    /// Accept the file offered as `id` and write it to the download directory.
+    ///
+    /// If an earlier offer of the same file didn't complete, the transfer continues where it stopped.
+    pub async fn accept_file(&self, id: u64) -> io::Result<()> {
+        let offer = self.take_offer(id).await?;
+        let state = self.state.clone();
+        self.state.tasks.spawn(async move {
+            let shutdown = state.shutdown.clone();
+            tokio::select! {
+                _ = transfer::receive(state, offer) => {}
+                _ = shutdown.cancelled() => tracing::debug!("stopped receiving file {}", id),
+            }
+        });
+        Ok(())
+    }
+
+    /// Tell the sender of the file offered as `id` that we don't want it.
+    pub async fn decline_file(&self, id: u64) -> io::Result<()> {
+        let offer = self.take_offer(id).await?;
+        transfer::decline(&self.state, offer);
+        Ok(())
+    }
+
+    async fn take_offer(&self, id: u64) -> io::Result<FilePacket> {
+        self.state
+            .take_offer(id)
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No file offer {}", id)))
+    }
+
+    /// A copy of the current routing table.
+    pub async fn routing_table(&self) -> HashMap<SocketAddr, RoutingTableEntry> {
+        self.state.snapshot().routing_table.clone()
+    }
+
+    /// Addresses of all directly connected peers.
+    pub async fn peers(&self) -> Vec<SocketAddr> {
+        self.state.snapshot().peers.keys().copied().collect()
+    }
+
+    /// Fill level and dropped item counter of the outbound queue of every peer.
+    pub async fn queue_stats(&self) -> HashMap<SocketAddr, QueueStats> {
+        self.state
+            .snapshot()
+            .peers
+            .iter()
+            .map(|(addr, tx)| (*addr, tx.stats()))
+            .collect()
+    }
+
+    /// Fingerprint of our key, to be compared out of band by whoever we chat with.
+    pub fn fingerprint(&self) -> String {
+        self.state.identity.public_key().fingerprint()
+    }
+
+    /// Keys pinned for other nodes, messages to them are encrypted.
+    pub async fn keys(&self) -> HashMap<SocketAddr, NodeKey> {
+        self.state.snapshot().keys.clone()
+    }
+
+    /// The nickname attached to outgoing messages.
+    pub async fn nickname(&self) -> String {
+        self.state.snapshot().nickname.clone()
+    }
+
+    /// Change the nickname attached to outgoing messages.
+    ///
+    /// Our neighbours learn it right away, the rest of the network with the next routing updates.
+    pub async fn set_nickname(&self, nickname: String) {
+        self.state.set_nickname(nickname).await;
+        heartbeat::send_to_peers(&self.state, RoutingType::STU);
+    }
+
+    /// Nicknames of the other nodes, as announced in the routing updates.
+    pub async fn nicknames(&self) -> HashMap<SocketAddr, String> {
+        self.state.snapshot().nicknames.clone()
+    }
+
+    /// The nodes we have a route to going by `nickname`, more than one if it's taken twice.
+    pub async fn lookup(&self, nickname: &str) -> Vec<SocketAddr> {
+        self.state
+            .snapshot()
+            .lookup(nickname, self.state.config.unreachable_metric)
+    }
+
+    /// Send a chat message to the node going by `nickname`, like `send_message`.
+    ///
+    /// Fails if nobody in reach goes by it, or if it's taken by more than one node.
+    pub async fn message_by_nick(&self, nickname: &str, message: String) -> io::Result<u64> {
+        match self.lookup(nickname).await.as_slice() {
+            [addr] => Ok(self.send_message(*addr, message).await),
+            [] => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Nobody called {} is in reach", nickname),
+            )),
+            addrs => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is taken by {} nodes, use one of their addresses: {:?}",
+                    nickname,
+                    addrs.len(),
+                    addrs
+                ),
+            )),
+        }
+    }
+
+    /// Our presence, as announced in our routing updates.
+    pub async fn presence(&self) -> Presence {
+        self.state.snapshot().presence.clone()
+    }
+
+    /// Change our presence, e.g. to busy with a status text.
+    ///
+    /// Our neighbours learn it right away, the rest of the network with the next routing updates.
+    pub async fn set_presence(&self, presence: Presence) {
+        self.state.set_presence(presence).await;
+        heartbeat::send_to_peers(&self.state, RoutingType::STU);
+    }
+
+    /// Presences of the other nodes, as announced in the routing updates.
+    pub async fn presences(&self) -> HashMap<SocketAddr, Presence> {
+        self.state.snapshot().presences.clone()
+    }
+
+    /// Tell `dest` that we are typing a message to it, returns whether the indicator was sent.
+    ///
+    /// Call it on every keystroke, indicators to the same node are sent once per
+    /// `presence::TYPING_INTERVAL` at most. They are neither encrypted nor acknowledged.
+    pub async fn typing(&self, dest: SocketAddr) -> bool {
+        let dest = canonical(dest);
+        if !self.state.allow_typing(dest).await {
+            return false;
+        }
+        let mut packet = self.control_packet(dest);
+        packet.typing = true;
+        if let Err(e) = self.send_control(packet) {
+            tracing::info!("Error sending typing indicator to {}: {}", dest, e);
+            return false;
+        }
+        true
+    }
+
+    /// Send a traceroute probe to `dest`, returns its id.
+    ///
+    /// Every node forwarding it adds its address and the time, the destination sends the
+    /// path back. It's published as `NodeEvent::Trace` with this id, nothing is if the probe is lost.
+    pub async fn trace(&self, dest: SocketAddr) -> u64 {
+        let dest = canonical(dest);
+        let id = self.state.next_id().await;
+        let mut packet = self.control_packet(dest);
+        packet.trace = Some(Trace::new(id, self.local_addr));
+        if let Err(e) = self.send_control(packet) {
+            tracing::info!("Error sending trace {} to {}: {}", id, dest, e);
+        }
+        id
+    }
+
+    /// Send a ping to `dest` and wait for its answer, returns the round-trip time.
+    ///
+    /// Works over any number of hops, unlike the RTT of our neighbours taken from the heartbeat.
+    pub async fn ping(&self, dest: SocketAddr) -> io::Result<Duration> {
+        let dest = canonical(dest);
+        let id = self.state.next_id().await;
+        // Subscribe first, the answer may be quick
+        let mut events = self.events();
+        let mut packet = self.control_packet(dest);
+        packet.ping = Some(Ping { id, reply: false });
+        let start = Instant::now();
+        self.send_control(packet)
+            .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e.to_string()))?;
+
+        let answer = async {
+            while let Some(event) = events.next().await {
+                if let NodeEvent::Pong { source, id: pong } = event {
+                    if source == dest && pong == id {
+                        return Ok(start.elapsed());
+                    }
+                }
+            }
+            Err(io::Error::other("Node shut down"))
+        };
+        tokio::time::timeout(PING_TIMEOUT, answer)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("No answer from {} within {:?}", dest, PING_TIMEOUT),
+                ))
+            })
+    }
+
+    /// Smoothed round-trip times to our neighbours, by the address of their connection.
+    pub async fn rtts(&self) -> HashMap<SocketAddr, RttEstimate> {
+        self.state.snapshot().rtts.clone()
+    }
+
+    /// A packet to `dest` without a message, for typing indicators, traces and pings
+    fn control_packet(&self, dest: SocketAddr) -> RoutedPacket {
+        let header = SharedHeader {
+            source: self.local_addr,
+            dest,
+            ttl: self.state.config.default_ttl,
+        };
+        RoutedPacket::control(header, self.state.snapshot().nickname.clone())
+    }
+
+    fn send_control(&self, packet: RoutedPacket) -> Result<(), Error> {
+        let dest = packet.header.dest;
+        let event = ChannelEvent::Forward(Packet::RoutedPacket(packet));
+        self.state.route(dest, self.local_addr, event)
+    }
+
+    /// Leave the network and stop all background tasks of this node.
+    ///
+    /// Every route through us is poisoned and each peer gets a final STU before its
+    /// connection is closed. Returns once all tasks are done or `SHUTDOWN_TIMEOUT` passed.
+    pub async fn shutdown(&self) {
+        self.state.poison_all().await;
+        self.state.shutdown.cancel();
+        self.state.tasks.close();
+
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, self.state.tasks.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "{} tasks did not finish in time, shutting down anyway",
+                self.state.tasks.len()
+            );
+        }
+    }
+}
+
+/// Parse the ip and port fields of a header or routing entry into a socket address.
+pub fn parse_addr(ip: &str, port: u16) -> Result<SocketAddr, Error> {
+    ip.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, port))
+        .map_err(|_| Error::InvalidAddress(ip.to_string()))
+}
+
+/// Loop accepting new connections from other clients creating a task for each of them handling their messages
+async fn accept(listener: TcpListener, state: StateHandle) {
+    loop {
+        // Asynchronously wait for an inbound TcpStream.
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::error!("Error accepting connection; error = {:?}", e);
+                continue;
+            }
+        };
+
+        tracing::info!("accepted connection to {}", addr);
+        if let Err(e) = spawn_peer(state.clone(), stream, addr, false).await {
+            tracing::info!("an error occurred; error = {:?}", e);
+        }
+    }
+}
+
+/// Frequent routing updates, so what a node announces makes it along a line quickly
+#[cfg(test)]
+fn fast_config() -> NodeConfig {
+    NodeConfig {
+        stu_interval_secs: 1,
+        ..NodeConfig::default()
+    }
+}
+
+/// `N` nodes with `config`, each connected to the next one, once all of them reach each other
+#[cfg(test)]
+async fn line_of_nodes<const N: usize>(config: NodeConfig) -> [Node; N] {
+    let mut nodes = Vec::new();
+    for _ in 0..N {
+        let node = Node::bind_with_config("127.0.0.1:0", config.clone()).await;
+        nodes.push(node.unwrap());
+    }
+    for pair in nodes.windows(2) {
+        pair[0].connect(pair[1].local_addr()).await.unwrap();
+    }
+    let unreachable = config.unreachable_metric;
+    wait_until(|| async {
+        for node in &nodes {
+            let table = node.routing_table().await;
+            let reaches = |other: &Node| {
+                other.local_addr() == node.local_addr()
+                    || table
+                        .get(&other.local_addr())
+                        .is_some_and(|entry| entry.hop_count < unreachable)
+            };
+            if !nodes.iter().all(reaches) {
+                return false;
+            }
+        }
+        true
+    })
+    .await;
+    nodes.try_into().unwrap_or_else(|_| unreachable!())
+}
+
+/// Check `condition` every 50ms until it holds, panics after 10 seconds
+#[cfg(test)]
+async fn wait_until<F, Fut>(mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !condition().await {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("condition didn't hold in time");
+}
+
+/// The first event `pick` returns something for, panics after 10 seconds
+#[cfg(test)]
+async fn wait_for<T>(events: &mut NodeEvents, mut pick: impl FnMut(NodeEvent) -> Option<T>) -> T {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while let Some(event) = events.next().await {
+            if let Some(picked) = pick(event) {
+                return picked;
+            }
+        }
+        panic!("event stream closed");
+    })
+    .await
+    .expect("event didn't arrive in time")
+}
+
+#[tokio::test]
+pub async fn test_message_between_nodes() {
+    // Bob takes plain text even from nodes he knows the key of
+    let config = NodeConfig {
+        encryption: false,
+        ..NodeConfig::default()
+    };
+    let alice = Node::bind_with_config("127.0.0.1:0", config.clone())
+        .await
+        .unwrap();
+    let bob = Node::bind_with_config("127.0.0.1:0", config).await.unwrap();
+    let mut bob_events = bob.events();
+
+    alice.set_nickname("alice".to_string()).await;
+    alice.connect(bob.local_addr()).await.unwrap();
+    alice
+        .send_message(bob.local_addr(), "hello".to_string())
+        .await;
+
+    let received = wait_for(&mut bob_events, |event| match event {
+        NodeEvent::Message { .. } => Some(event),
+        _ => None,
+    })
+    .await;
+    assert_eq!(
+        received,
+        NodeEvent::Message {
+            source: alice.local_addr(),
+            nickname: "alice".to_string(),
+            message: "hello".to_string(),
+            encrypted: false,
+            room: None,
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_invalid_config_is_refused() {
+    let config = NodeConfig {
+        stu_interval_secs: 29,
+        ..NodeConfig::default()
+    };
+    let error = Node::bind_with_config("127.0.0.1:0", config)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn test_shutdown_closes_connections() {
+    let alice = Node::bind("127.0.0.1:0").await.unwrap();
+    let bob = Node::bind("127.0.0.1:0").await.unwrap();
+    let mut bob_events = bob.events();
+
+    alice.connect(bob.local_addr()).await.unwrap();
+    let start = std::time::Instant::now();
+    alice.shutdown().await;
+    assert!(start.elapsed() < SHUTDOWN_TIMEOUT);
+    assert!(alice.state.tasks.is_empty());
+
+    // Bob notices the connection going away
+    wait_for(&mut bob_events, |event| match event {
+        NodeEvent::Leave(_) => Some(()),
+        _ => None,
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_message_across_address_families() {
+    let alice = Node::bind("127.0.0.1:0").await.unwrap();
+    let bob = Node::bind_all(["127.0.0.1:0", "[::1]:0"], NodeConfig::default())
+        .await
+        .unwrap();
+    let carol = Node::bind("[::1]:0").await.unwrap();
+    let mut carol_events = carol.events();
+    let bob_v6 = bob.local_addrs()[1];
+    assert!(bob_v6.is_ipv6());
+
+    alice.connect(bob.local_addr()).await.unwrap();
+    carol.connect(bob_v6).await.unwrap();
+
+    // Wait until the route to carol made it from bob to alice
+    wait_until(|| async {
+        alice
+            .routing_table()
+            .await
+            .contains_key(&carol.local_addr())
+    })
+    .await;
+    alice
+        .send_message(carol.local_addr(), "hello".to_string())
+        .await;
+
+    let received = wait_for(&mut carol_events, |event| match event {
+        NodeEvent::Message {
+            source, message, ..
+        } => Some((source, message)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(received, (alice.local_addr(), "hello".to_string()));
+}
+
+#[tokio::test]
+async fn test_large_message_between_nodes() {
+    let alice = Node::bind("127.0.0.1:0").await.unwrap();
+    let bob = Node::bind("127.0.0.1:0").await.unwrap();
+    let mut bob_events = bob.events();
+
+    // Way beyond the u16 length field of a single packet
+    let message = "large message ".repeat(16 * 1024);
+    alice.connect(bob.local_addr()).await.unwrap();
+    alice.send_message(bob.local_addr(), message.clone()).await;
+
+    let received = wait_for(&mut bob_events, |event| match event {
+        NodeEvent::Message { message, .. } => Some(message),
+        _ => None,
+    })
+    .await;
+    assert_eq!(received, message);
+}
+
+#[tokio::test]
+async fn test_message_is_acknowledged() {
+    let alice = Node::bind("127.0.0.1:0").await.unwrap();
+    let bob = Node::bind("127.0.0.1:0").await.unwrap();
+    let mut alice_events = alice.events();
+    let mut bob_events = bob.events();
+
+    // Sent before the connection exists, so the first attempt has no route
+    let id = alice
+        .send_message(bob.local_addr(), "hello".to_string())
+        .await;
+    alice.connect(bob.local_addr()).await.unwrap();
+
+    let (acked, status) = wait_for(&mut alice_events, |event| match event {
+        NodeEvent::Delivery { id, status, .. } => Some((id, status)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(acked, id);
+    assert_eq!(status, crate::channel_events::DeliveryStatus::Delivered);
+
+    // Exactly one copy reached bob
+    let mut messages = 0;
+    while let Ok(Some(event)) =
+        tokio::time::timeout(std::time::Duration::from_millis(500), bob_events.next()).await
+    {
+        if let NodeEvent::Message { .. } = event {
+            messages += 1;
+        }
+    }
+    assert_eq!(messages, 1);
+}
+
+#[tokio::test]
+async fn test_encrypted_message_between_nodes() {
+    let alice = Node::bind("127.0.0.1:0").await.unwrap();
+    let bob = Node::bind("127.0.0.1:0").await.unwrap();
+    let mut bob_events = bob.events();
+
+    alice.connect(bob.local_addr()).await.unwrap();
+
+    // Wait until bob's key arrived with his routing updates
+    wait_until(|| async { alice.keys().await.contains_key(&bob.local_addr()) }).await;
+    assert_eq!(
+        alice.keys().await[&bob.local_addr()].fingerprint(),
+        bob.fingerprint()
+    );
+    alice
+        .send_message(bob.local_addr(), "secret".to_string())
+        .await;
+
+    let received = wait_for(&mut bob_events, |event| match event {
+        NodeEvent::Message {
+            message, encrypted, ..
+        } => Some((message, encrypted)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(received, ("secret".to_string(), true));
+}
+
+#[tokio::test]
+async fn test_plain_text_from_known_key_is_rejected() {
+    // Alice sends in plain text, just like a node on the way that stripped the seal would
+    let alice = Node::bind_with_config(
+        "127.0.0.1:0",
+        NodeConfig {
+            encryption: false,
+            ..NodeConfig::default()
+        },
+    )
+    .await
+    .unwrap();
+    let bob = Node::bind("127.0.0.1:0").await.unwrap();
+    let mut bob_events = bob.events();
+
+    alice.connect(bob.local_addr()).await.unwrap();
+    wait_until(|| async { bob.keys().await.contains_key(&alice.local_addr()) }).await;
+    alice
+        .send_message(bob.local_addr(), "trust me".to_string())
+        .await;
+
+    let event = wait_for(&mut bob_events, |event| match event {
+        NodeEvent::Log(msg) if msg.contains("not encrypted") => Some(None),
+        NodeEvent::Message { .. } => Some(Some(event)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(event, None);
+}
+
+#[tokio::test]
+async fn test_unsigned_update_is_rejected() {
+    use crate::protocol::routing_packet::{RoutingEntry, RoutingPacket};
+    use crate::protocol::shared_header::SharedHeader;
+    use crate::protocol::{Packet, RoutingType};
+
+    let config = NodeConfig {
+        route_signatures: crate::crypto::SignaturePolicy::Strict,
+        ..NodeConfig::default()
+    };
+    let bob = Node::bind_with_config("127.0.0.1:0", config).await.unwrap();
+    let mut bob_events = bob.events();
+
+    // Someone claiming a direct route to a node that isn't there
+    let stream = TcpStream::connect(bob.local_addr()).await.unwrap();
+    let mut mallory = tokio_util::codec::Framed::new(stream, crate::swag_coding::SwagCoder::new());
+    let fake = "127.0.0.1:1".parse().unwrap();
+    let victim = "10.9.9.9:6142".parse().unwrap();
+    let packet = RoutingPacket {
+        header: SharedHeader {
+            source: fake,
+            dest: bob.local_addr(),
+            ttl: 16,
+        },
+        table: Some(vec![RoutingEntry {
+            target: victim,
+            next: fake,
+            hop_count: 1,
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
+        }]),
+        public_key: None,
+        rooms: Vec::new(),
+        nickname: None,
+        presence: None,
+        signed: None,
+    };
+    mallory
+        .send(Packet::RoutingPacket(packet, RoutingType::CR))
+        .await
+        .unwrap();
+
+    let log = wait_for(&mut bob_events, |event| match event {
+        NodeEvent::Log(msg) if msg.starts_with("Rejected packet") => Some(msg),
+        _ => None,
+    })
+    .await;
+    assert!(log.ends_with("Routing update is not signed"));
+    let routing_table = bob.routing_table().await;
+    assert!(!routing_table.contains_key(&victim));
+    assert!(!routing_table.contains_key(&fake));
+}
+
+#[tokio::test]
+async fn test_unsigned_update_only_speaks_for_its_sender() {
+    use crate::protocol::routing_packet::RoutingPacket;
+
+    let bob = Node::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(bob.local_addr()).await.unwrap();
+    let own = stream.local_addr().unwrap();
+    let mut mallory = tokio_util::codec::Framed::new(stream, crate::swag_coding::SwagCoder::new());
+    let update = |source| RoutingPacket {
+        header: SharedHeader {
+            source,
+            dest: bob.local_addr(),
+            ttl: 16,
+        },
+        table: Some(Vec::new()),
+        public_key: Some(Identity::generate().public_key()),
+        rooms: vec!["lobby".to_string()],
+        nickname: Some("mallory".to_string()),
+        presence: None,
+        signed: None,
+    };
+
+    // Claiming to be someone else, then speaking for itself
+    let victim = "10.9.9.9:6142".parse().unwrap();
+    for source in [victim, own] {
+        mallory
+            .send(Packet::RoutingPacket(update(source), RoutingType::CR))
+            .await
+            .unwrap();
+    }
+
+    // The updates are handled in order, once the second one counted the first one is done
+    wait_until(|| async { bob.nicknames().await.contains_key(&own) }).await;
+    assert!(!bob.nicknames().await.contains_key(&victim));
+    assert!(!bob.keys().await.contains_key(&victim));
+    assert!(bob.keys().await.contains_key(&own));
+    assert_eq!(bob.room_members("lobby").await, vec![own]);
+}
+
+#[tokio::test]
+async fn test_room_message_across_nodes() {
+    let [alice, bob, carol] = line_of_nodes(fast_config()).await;
+    let mut bob_events = bob.events();
+    let mut carol_events = carol.events();
+
+    bob.join_room("#ops".to_string()).await;
+    carol.join_room("#ops".to_string()).await;
+    carol.join_room("#dev".to_string()).await;
+    wait_until(|| async { alice.room_members("#ops").await.len() == 2 }).await;
+    assert_eq!(alice.room_members("#dev").await, vec![carol.local_addr()]);
+    let ids = alice
+        .say("#ops".to_string(), "deploy is done".to_string())
+        .await;
+    assert_eq!(ids.len(), 2);
+
+    for events in [&mut bob_events, &mut carol_events] {
+        let received = wait_for(events, |event| match event {
+            NodeEvent::Message { message, room, .. } => Some((room, message)),
+            _ => None,
+        })
+        .await;
+        assert_eq!(
+            received,
+            (Some("#ops".to_string()), "deploy is done".to_string())
+        );
+    }
+
+    // Leaving the room reaches alice as well
+    bob.leave_room("#ops".to_string()).await;
+    wait_until(|| async { alice.room_members("#ops").await.len() == 1 }).await;
+    assert_eq!(alice.room_members("#ops").await, vec![carol.local_addr()]);
+}
+
+#[tokio::test]
+async fn test_broadcast_is_flooded_once() {
+    // A triangle, so every node gets a second copy from the other side
+    let alice = Node::bind("127.0.0.1:0").await.unwrap();
+    let bob = Node::bind("127.0.0.1:0").await.unwrap();
+    let carol = Node::bind("127.0.0.1:0").await.unwrap();
+    let mut alice_events = alice.events();
+    let mut bob_events = bob.events();
+    let mut carol_events = carol.events();
+
+    alice.set_nickname("alice".to_string()).await;
+    alice.connect(bob.local_addr()).await.unwrap();
+    alice.connect(carol.local_addr()).await.unwrap();
+    bob.connect(carol.local_addr()).await.unwrap();
+    for node in [&alice, &bob, &carol] {
+        wait_until(|| async { node.peers().await.len() == 2 }).await;
+    }
+
+    alice.broadcast("hello everyone".to_string()).await;
+
+    for events in [&mut bob_events, &mut carol_events] {
+        let received = wait_for(events, |event| match event {
+            NodeEvent::Broadcast { .. } => Some(event),
+            _ => None,
+        })
+        .await;
+        assert_eq!(
+            received,
+            NodeEvent::Broadcast {
+                origin: alice.local_addr(),
+                nickname: "alice".to_string(),
+                message: "hello everyone".to_string(),
+            }
+        );
+    }
+
+    // The copies going around the triangle are dropped, also by alice
+    let duplicate = tokio::time::timeout(std::time::Duration::from_millis(500), async {
+        loop {
+            tokio::select! {
+                Some(event) = alice_events.next() => if let NodeEvent::Broadcast { .. } = event { return event },
+                Some(event) = bob_events.next() => if let NodeEvent::Broadcast { .. } = event { return event },
+                Some(event) = carol_events.next() => if let NodeEvent::Broadcast { .. } = event { return event },
+            }
+        }
+    })
+    .await;
+    assert!(duplicate.is_err(), "got a second copy: {:?}", duplicate);
+}
+
+#[tokio::test]
+async fn test_file_transfer_resumes() {
+    // Only carol receives a file, the others never write to the directory
+    let download_dir =
+        std::env::temp_dir().join(format!("morganite-downloads-{}", std::process::id()));
+    let config = NodeConfig {
+        download_dir: download_dir.clone(),
+        ..fast_config()
+    };
+    // Several windows worth of chunks, routed through bob
+    let [alice, _bob, carol] = line_of_nodes(config).await;
+    let mut carol_events = carol.events();
+    let content = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let path = std::env::temp_dir().join(format!("morganite-file-{}", alice.local_addr().port()));
+    std::fs::write(&path, &content).unwrap();
+
+    // An earlier try got the first 20000 bytes across
+    let sha256 = format!("{:x}", sha2::Sha256::digest(&content));
+    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+    std::fs::create_dir_all(&download_dir).unwrap();
+    std::fs::write(
+        download_dir.join(format!(".{}.{}.part", name, &sha256[..16])),
+        &content[..20_000],
+    )
+    .unwrap();
+
+    let id = alice.send_file(carol.local_addr(), &path).await.unwrap();
+    let offered = wait_for(&mut carol_events, |event| match event {
+        NodeEvent::FileOffer { id, .. } => Some(id),
+        _ => None,
+    })
+    .await;
+    assert_eq!(offered, id);
+    carol.accept_file(id).await.unwrap();
+
+    let mut first_progress = None;
+    let saved = wait_for(&mut carol_events, |event| match event {
+        NodeEvent::FileProgress { bytes, .. } => {
+            first_progress.get_or_insert(bytes);
+            None
+        }
+        NodeEvent::FileDone { status, .. } => Some(status),
+        _ => None,
+    })
+    .await;
+    assert_eq!(first_progress, Some(20_000));
+    let FileStatus::Received(saved) = saved else {
+        panic!("transfer failed: {:?}", saved);
+    };
+    assert_eq!(std::fs::read(&saved).unwrap(), content);
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_dir_all(&download_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_nickname_propagates() {
+    let [alice, _bob, carol] = line_of_nodes(fast_config()).await;
+
+    // Learned two hops away, through bob
+    carol.set_nickname("carol".to_string()).await;
+    wait_until(|| async { !alice.lookup("carol").await.is_empty() }).await;
+    assert_eq!(alice.lookup("carol").await, vec![carol.local_addr()]);
+
+    // A new nickname replaces the old one
+    carol.set_nickname("caro".to_string()).await;
+    wait_until(|| async { !alice.lookup("caro").await.is_empty() }).await;
+    assert!(alice.lookup("carol").await.is_empty());
+}
+
+#[tokio::test]
+async fn test_presence_and_typing() {
+    let [alice, _bob, carol] = line_of_nodes(fast_config()).await;
+    let mut carol_events = carol.events();
+
+    let busy = Presence {
+        state: crate::presence::PresenceState::Busy,
+        text: Some("in a meeting".to_string()),
+    };
+    carol.set_presence(busy.clone()).await;
+    assert_eq!(carol.presence().await, busy);
+
+    // Learned two hops away, through bob
+    wait_until(|| async { alice.presences().await.get(&carol.local_addr()) == Some(&busy) }).await;
+
+    // Only the first indicator within the interval is sent
+    assert!(alice.typing(carol.local_addr()).await);
+    assert!(!alice.typing(carol.local_addr()).await);
+
+    let typing = wait_for(&mut carol_events, |event| match event {
+        NodeEvent::Typing { source, .. } => Some(source),
+        _ => None,
+    })
+    .await;
+    assert_eq!(typing, alice.local_addr());
+}
+
+#[tokio::test]
+async fn test_trace_records_every_hop() {
+    let [alice, bob, carol] = line_of_nodes(fast_config()).await;
+    let mut alice_events = alice.events();
+
+    let id = alice.trace(carol.local_addr()).await;
+    let (trace, dest, hops) = wait_for(&mut alice_events, |event| match event {
+        NodeEvent::Trace { id, dest, hops, .. } => Some((id, dest, hops)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(trace, id);
+    assert_eq!(dest, carol.local_addr());
+    let path: Vec<SocketAddr> = hops.iter().map(|hop| hop.addr).collect();
+    assert_eq!(
+        path,
+        vec![alice.local_addr(), bob.local_addr(), carol.local_addr()]
+    );
+    assert_eq!(hops[0].latency, Duration::ZERO);
+}
+
+#[tokio::test]
+async fn test_ping_and_neighbour_rtt() {
+    let [alice, bob, carol] = line_of_nodes(fast_config()).await;
+
+    // Nobody to answer at an address we have no route to
+    assert!(alice.ping("127.0.0.1:1".parse().unwrap()).await.is_err());
+
+    // Two hops away
+    let rtt = alice.ping(carol.local_addr()).await.unwrap();
+    assert!(rtt < PING_TIMEOUT);
+
+    // The heartbeat measures the link to bob
+    wait_until(|| async { alice.rtts().await.contains_key(&bob.local_addr()) }).await;
+    assert!(!alice.rtts().await.contains_key(&carol.local_addr()));
+}
+
+#[tokio::test]
+async fn test_triggered_updates() {
+    // The regular updates are far apart, only triggered ones arrive in time
+    let config = NodeConfig::default();
+    let [alice, bob] = line_of_nodes(config.clone()).await;
+    let carol = Node::bind_with_config("127.0.0.1:0", config).await.unwrap();
+    // Let the first regular update go by
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let hops = |target: SocketAddr| {
+        let alice = alice.clone();
+        async move {
+            alice
+                .routing_table()
+                .await
+                .get(&target)
+                .map(|entry| entry.hop_count)
+        }
+    };
+    let carol_addr = carol.local_addr();
+    carol.connect(bob.local_addr()).await.unwrap();
+    let within = Duration::from_secs(3);
+    tokio::time::timeout(
+        within,
+        wait_until(|| async { hops(carol_addr).await == Some(2) }),
+    )
+    .await
+    .unwrap();
+
+    // Losing carol travels just as fast, bob is the next hop so alice believes him
+    carol.shutdown().await;
+    tokio::time::timeout(
+        within,
+        wait_until(|| async { hops(carol_addr).await == Some(32) }),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_triggered_updates_are_damped() {
+    use crate::protocol::routing_packet::{RoutingEntry, RoutingPacket};
+    use crate::swag_coding::SwagCoder;
+    use tokio_util::codec::Framed;
+
+    /// The STUs arriving until `deadline`, with the time they did
+    async fn updates(
+        framed: &mut Framed<TcpStream, SwagCoder>,
+        deadline: tokio::time::Instant,
+    ) -> Vec<(Instant, Vec<RoutingEntry>)> {
+        let mut updates = Vec::new();
+        while let Ok(Some(packet)) = tokio::time::timeout_at(deadline, framed.next()).await {
+            if let Packet::RoutingPacket(packet, RoutingType::STU) = packet.unwrap() {
+                updates.push((Instant::now(), packet.table.unwrap()));
+            }
+        }
+        updates
+    }
+
+    // No regular updates while the test runs
+    let config = NodeConfig {
+        scc_timeout_secs: 30,
+        stu_interval_secs: 30,
+        route_timeout_secs: 90,
+        update_damping_ms: 500,
+        ..NodeConfig::default()
+    };
+    let damping = config.update_damping();
+    let bob = Node::bind_with_config("127.0.0.1:0", config).await.unwrap();
+    let connect = || async {
+        let stream = TcpStream::connect(bob.local_addr()).await.unwrap();
+        let own = stream.local_addr().unwrap();
+        (own, Framed::new(stream, SwagCoder::new()))
+    };
+    let (_, mut watcher) = connect().await;
+    let (mallory_addr, mut mallory) = connect().await;
+    let update = |table| RoutingPacket {
+        header: SharedHeader {
+            source: mallory_addr,
+            dest: bob.local_addr(),
+            ttl: 16,
+        },
+        table: Some(table),
+        public_key: None,
+        rooms: Vec::new(),
+        nickname: None,
+        presence: None,
+        signed: None,
+    };
+    mallory
+        .send(Packet::RoutingPacket(update(Vec::new()), RoutingType::CR))
+        .await
+        .unwrap();
+    // The watcher hears about mallory, then the damping runs out
+    let settled = tokio::time::Instant::now() + Duration::from_secs(1);
+    assert_eq!(updates(&mut watcher, settled).await.len(), 1);
+
+    // A burst of changes within the damping, watched while it happens
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(2);
+    let burst = async {
+        let mut table = Vec::new();
+        for i in 1..=5 {
+            table.push(RoutingEntry {
+                target: SocketAddr::from(([10, 0, 0, i], 6142)),
+                next: mallory_addr,
+                hop_count: 1,
+                public_key: None,
+                rooms: Vec::new(),
+                nickname: None,
+                presence: None,
+            });
+            mallory
+                .send(Packet::RoutingPacket(
+                    update(table.clone()),
+                    RoutingType::STU,
+                ))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+    let ((), burst) = tokio::join!(burst, updates(&mut watcher, deadline));
+
+    // The first change goes out right away, the rest together once the damping is over
+    assert_eq!(burst.len(), 2);
+    assert!(burst[1].0 - burst[0].0 >= damping - Duration::from_millis(50));
+    assert_eq!(burst[1].1.len(), 6);
+
+    // Mallory's own routes aren't advertised back to it, so it wasn't bothered at all
+    let deadline = tokio::time::Instant::now() + Duration::from_millis(100);
+    assert!(updates(&mut mallory, deadline).await.is_empty());
+}
+
+#[test]
+fn test_parse_addr() {
+    assert_eq!(
+        parse_addr("10.0.0.1", 1234).unwrap(),
+        "10.0.0.1:1234".parse::<SocketAddr>().unwrap()
+    );
+    assert!(matches!(
+        parse_addr("10.0.0.1:1234", 1),
+        Err(Error::InvalidAddress(_))
+    ));
+    assert!(matches!(parse_addr("", 1), Err(Error::InvalidAddress(_))));
+}
@@ -1,14 +1,12 @@
 use swag_coding::SwagCoder;
 use tokio::net::TcpStream;
-use tokio::sync::{mpsc, Mutex};
 
 use tokio_util::codec::Framed;
 
-use std::io;
+use std::net::SocketAddr;
 
-use std::sync::Arc;
-
-use crate::shared::{Rx, Shared};
+use crate::queue::{self, PeerReceiver};
+use crate::state::StateHandle;
 use crate::swag_coding;
 
 /// The state for each connected client.
@@ -20,31 +18,27 @@ pub struct Peer {
     /// raw byte operations.
     pub swag_coder: Framed<TcpStream, SwagCoder>,
 
-    /// Receive half of the message channel.
+    /// Receive half of the outbound queue.
     ///
     /// This is used to receive messages from peers. When a message is received
-    /// off of this `Rx`, it will be written to the socket.
-    pub rx: Rx,
+    /// off of this `PeerReceiver`, it will be written to the socket.
+    pub rx: PeerReceiver,
 }
 
 impl Peer {
     /// Create a new instance of `Peer`.
     pub async fn new(
-        state: Arc<Mutex<Shared>>,
+        state: StateHandle,
         swag_coder: Framed<TcpStream, SwagCoder>,
-    ) -> io::Result<Peer> {
-        // Get the client socket address
-        let addr = swag_coder.get_ref().peer_addr()?;
-        // Create a channel for this peer
-        let (tx, rx) = mpsc::unbounded_channel();
-
-        // Add an entry for this `Peer` in the shared state map and the routing table.
-        {
-            let mut lock = state.lock().await;
-            lock.peers.insert(addr, tx);
-        }
+        addr: SocketAddr,
+    ) -> Peer {
+        // Create a bounded queue for this peer
+        let (tx, rx) = queue::peer_queue(state.config.queue_depth, state.config.queue_policy);
+
+        // Add an entry for this `Peer` in the shared state map.
+        state.add_peer(addr, tx).await;
 
         tracing::info!("added address: {}", addr);
-        Ok(Peer { swag_coder, rx })
+        Peer { swag_coder, rx }
     }
 }
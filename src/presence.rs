@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Least time between two typing indicators to the same node
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Whether a node is around to chat
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    #[default]
+    Online,
+    Away,
+    Busy,
+}
+
+impl fmt::Display for PresenceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresenceState::Online => write!(f, "online"),
+            PresenceState::Away => write!(f, "away"),
+            PresenceState::Busy => write!(f, "busy"),
+        }
+    }
+}
+
+impl FromStr for PresenceState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(PresenceState::Online),
+            "away" => Ok(PresenceState::Away),
+            "busy" => Ok(PresenceState::Busy),
+            _ => Err(format!("Unknown presence {}, use online, away or busy", s)),
+        }
+    }
+}
+
+/// Presence a node announces in its routing updates, e.g. away with "back at 2"
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Presence {
+    pub state: PresenceState,
+    /// Custom status text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.text {
+            Some(text) => write!(f, "{} ({})", self.state, text),
+            None => write!(f, "{}", self.state),
+        }
+    }
+}
+
+/// Typing indicators we sent recently, so every destination gets one per `TYPING_INTERVAL` at most
+#[derive(Debug, Default)]
+pub struct TypingLimiter {
+    last: HashMap<SocketAddr, Instant>,
+}
+
+impl TypingLimiter {
+    /// Whether an indicator may be sent to `dest` now, it counts as sent if so
+    pub fn allow(&mut self, dest: SocketAddr, now: Instant) -> bool {
+        self.last
+            .retain(|_, sent| now.duration_since(*sent) < TYPING_INTERVAL);
+        if self.last.contains_key(&dest) {
+            return false;
+        }
+        self.last.insert(dest, now);
+        true
+    }
+}
+
+#[test]
+fn test_presence_json() {
+    let presence = Presence {
+        state: PresenceState::Away,
+        text: Some("back at 2".to_string()),
+    };
+    let json = serde_json::to_string(&presence).unwrap();
+    assert_eq!(json, r#"{"state":"away","text":"back at 2"}"#);
+    assert_eq!(serde_json::from_str::<Presence>(&json).unwrap(), presence);
+    assert_eq!(presence.to_string(), "away (back at 2)");
+    assert_eq!("busy".parse(), Ok(PresenceState::Busy));
+    assert!("asleep".parse::<PresenceState>().is_err());
+}
+
+#[test]
+fn test_typing_is_rate_limited() {
+    let mut limiter = TypingLimiter::default();
+    let alice = "127.0.0.1:6143".parse().unwrap();
+    let bob = "127.0.0.1:6144".parse().unwrap();
+    let now = Instant::now();
+
+    assert!(limiter.allow(alice, now));
+    assert!(!limiter.allow(alice, now + Duration::from_secs(1)));
+    // Every destination has its own limit
+    assert!(limiter.allow(bob, now + Duration::from_secs(1)));
+    assert!(limiter.allow(alice, now + TYPING_INTERVAL));
+}
@@ -1,129 +1,199 @@
-use channel_events::ChannelEvent;
+use channel_events::{ChannelEvent, NodeEvent};
 
 use swag_coding::SwagCoder;
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
 use tokio_stream::StreamExt;
 use tokio_util::codec::Framed;
 
 use futures::SinkExt;
 
-use std::error::Error;
+use std::io;
 
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::time::Instant;
 
-use crate::heartbeat::POISE_UNREACHABLE;
+use crate::channel_events::TraceHop;
+use crate::error::Error;
 use crate::peer::Peer;
-use crate::protocol::routed_packet::RoutedPacket;
-use crate::protocol::routing_packet::RoutingPacket;
+use crate::protocol::ack_packet::AckPacket;
+use crate::protocol::broadcast_packet::BroadcastPacket;
+use crate::protocol::routed_packet::{Ping, RoutedPacket, Trace};
+use crate::protocol::routing_packet::{RoutingEntry, RoutingPacket};
 use crate::protocol::shared_header::SharedHeader;
 use crate::protocol::Packet;
-use crate::protocol::{CR, CRR, MESSAGE, SCC, SCCR, STU};
-use crate::shared::{RoutingTableEntry, Shared};
+use crate::protocol::RoutingType::{self, CR, CRR, SCC, SCCR, STU};
+use crate::shared::{canonical, RoutingTableEntry};
+use crate::state::StateHandle;
 use crate::{channel_events, swag_coding};
 
-/// Process an individual chat client
-pub async fn process(
-    state: Arc<Mutex<Shared>>,
+/// Register a freshly established connection and spawn the task processing it.
+///
+/// The peer is registered before this returns, so messages can be routed to it right away.
+pub async fn spawn_peer(
+    state: StateHandle,
     stream: TcpStream,
     addr: SocketAddr,
     send_cr: bool,
-) -> Result<(), Box<dyn Error>> {
-    let local_addr = match stream.local_addr() {
-        Ok(local_addr) => local_addr,
-        Err(e) => {
-            tracing::info!("an error occurred; error = {:?}", e);
-            panic!(); //TODO maybe different error handling
-        }
-    };
-    let listener_address = state
-        .lock()
-        .await
-        .listener_addr
-        .clone()
-        .parse::<SocketAddr>()
-        .unwrap();
-    let swag_coder = Framed::new(stream, SwagCoder::new());
+) -> io::Result<()> {
+    // The local address of this connection is what the peer knows us by
+    let local_addr = canonical(stream.local_addr()?);
+    let addr = canonical(addr);
+    let swag_coder = Framed::new(
+        stream,
+        SwagCoder::with_fragment_ids(state.fragment_ids.clone()),
+    );
 
     // Register our peer with state which internally sets up some channels.
-    let mut peer = Peer::new(state.clone(), swag_coder).await?;
+    let peer = Peer::new(state.clone(), swag_coder, addr).await;
+
+    // Spawn our handler to be run asynchronously, shutdown waits for it to say goodbye.
+    let tasks = state.tasks.clone();
+    tasks.spawn(async move {
+        if let Err(e) = process(state.clone(), peer, addr, local_addr, send_cr).await {
+            tracing::info!("an error occurred with {}; error = {}", addr, e);
+        }
+
+        // If this section is reached it means that the client was disconnected!
+        // Let's let everyone still connected know about it.
+        // The state task removes the peer and poisons every route through it
+        state.remove_peer(addr);
+
+        let msg = format!("{} has left the chat", addr);
+        tracing::info!("{}", msg);
+        state.emit(NodeEvent::Leave(addr));
+    });
+    Ok(())
+}
+
+/// Process an individual chat client
+pub async fn process(
+    state: StateHandle,
+    mut peer: Peer,
+    addr: SocketAddr,
+    local_addr: SocketAddr,
+    send_cr: bool,
+) -> Result<(), Error> {
+    // The listener address the peer reaches us at, sent as the source of our packets
+    let listener_address = state.own_addr(local_addr);
+    let config = state.config.clone();
+
     // A client has connected, let's let everyone know.
-    {
-        let mut state = state.lock().await;
-        tracing::info!("{addr} has joined the chat");
-        state
-            .broadcast(addr, &ChannelEvent::Join(addr.to_string()))
-            .await;
-        if send_cr {
-            match state.peers.get(&addr) {
-                Some(entry) => {
-                    if let Err(e) = entry.send(ChannelEvent::Routing(CR)) {
-                        tracing::info!("Error sending the CR. error = {:?}", e);
-                    }
-                }
-                None => {
-                    tracing::error!("Maybe too early for CR?: {}", addr);
+    tracing::info!("{addr} has joined the chat");
+    state.emit(NodeEvent::Join(addr));
+    if send_cr {
+        match state.snapshot().peers.get(&addr) {
+            Some(entry) => {
+                if let Err(e) = entry.send(ChannelEvent::Routing(CR)) {
+                    tracing::info!("Error sending the CR. error = {:?}", e);
                 }
-            };
-        }
+            }
+            None => {
+                tracing::error!("Maybe too early for CR?: {}", addr);
+            }
+        };
     }
 
+    let shutdown = state.shutdown.clone();
+    // When we sent the SCC the peer hasn't answered yet, for its round-trip time
+    let mut scc_sent: Option<Instant> = None;
+    // The routing table we advertised to the peer last, a triggered update only goes out if it changed
+    let mut advertised: Option<Vec<RoutingEntry>> = None;
+
     // Process incoming messages until our stream is exhausted by a disconnect.
     loop {
         tokio::select! {
+            //-----------------the node shuts down-----------------
+            _ = shutdown.cancelled() => {
+                // Our routes are poisoned by now, tell the peer before hanging up
+                let header = header_to(listener_address, addr, config.default_ttl);
+                let table = state.advertisement(addr, local_addr).await;
+                if let Err(e) = peer.swag_coder.send(signed_routing_packet(&state, header, table, STU)).await {
+                    tracing::info!("Error sending the final STU to {}. error = {:?}", addr, e);
+                }
+                if let Err(e) = peer.swag_coder.close().await {
+                    tracing::info!("Error closing the connection to {}. error = {:?}", addr, e);
+                }
+                break;
+            }
             //-----------------send something through this TCP socket-----------------
-            Some(event) = peer.rx.recv() => {
+            event = peer.rx.recv() => {
+                let Some(event) = event else {
+                    // Our queue overflowed and the policy says to let go of slow peers
+                    let msg = format!("{} is not keeping up, disconnecting", addr);
+                    tracing::warn!("{}", msg);
+                    state.emit(NodeEvent::Log(msg));
+                    break;
+                };
                 tracing::info!("Received Event: {:#?}", event);
                 // create packet
-                let mut header = SharedHeader {
-                    //source addr is listener!
-                    source_ip: listener_address.ip().to_string(),
-                    source_port: listener_address.port(),
-                    dest_ip: addr.ip().to_string(),
-                    dest_port: addr.port(),
-                    ttl: 16,
-                };
+                let mut header = header_to(listener_address, addr, config.default_ttl);
                 match event {
-                    ChannelEvent::Message(msg, dest_addr) => {
-                        header.dest_ip = dest_addr.ip().to_string();
-                        header.dest_port = dest_addr.port();
+                    ChannelEvent::Message(msg, dest_addr, id, room) => {
+                        header.dest = dest_addr;
                         // Get the nickname
-                        let nickname = {
-                            let state = state.lock().await;
-                            state.nickname.clone()
-                        };
-                        let routed_packet = RoutedPacket {
+                        let nickname = state.snapshot().nickname.clone();
+                        let mut routed_packet = RoutedPacket {
                             header,
                             nickname,
                             message: msg,
+                            id: Some(id),
+                            sealed: None,
+                            room,
+                            fragment: None,
+                            typing: false,
+                            trace: None,
+                            ping: None,
                         };
-                        peer.swag_coder.send(Packet::RoutedPacket(routed_packet)).await?;
+                        // Encrypt to the destination if we know its key
+                        let key = state.snapshot().keys.get(&dest_addr).copied();
+                        if let (true, Some(key)) = (config.encryption, key) {
+                            state.identity.seal(&key, &mut routed_packet)?;
+                        }
+                        let result = peer.swag_coder.send(Packet::RoutedPacket(routed_packet)).await;
+                        refuse_oversized(&state, result)?;
                     },
                     //we received a message to be forwarded
                     ChannelEvent::Forward(mut packet) => {
                         //decrease ttl and send package
-                        if let Packet::RoutedPacket(routed_packet) = &mut packet {
-                            routed_packet.header.ttl -= 1;
+                        if let Some(header) = packet.routed_header_mut() {
+                            header.ttl = header.ttl.saturating_sub(1);
                         }
 
-                        peer.swag_coder.send(packet).await?;
+                        let result = peer.swag_coder.send(packet).await;
+                        refuse_oversized(&state, result)?;
                     }
-                    ChannelEvent::Routing(type_id) => {
+                    //a broadcast for everyone, this copy is for our neighbour
+                    ChannelEvent::Flood(mut broadcast) => {
+                        broadcast.header.dest = addr;
+                        let result = peer.swag_coder.send(Packet::Broadcast(broadcast)).await;
+                        refuse_oversized(&state, result)?;
+                    }
+                    ChannelEvent::Routing(routing_type) => {
                         //get current routing table
-                        tracing::info!("sending a routing packet. Type: {:?}", type_id);
-                        let rt = if type_id != SCC {
-                            let mut lock = state.lock().await;
-                            lock.get_routing_table(addr,local_addr).await
+                        tracing::info!("sending a routing packet. Type: {:?}", routing_type);
+                        let rt = if routing_type != SCC {
+                            state.advertisement(addr, local_addr).await
                         } else {
                             Vec::new()
                         };
 
-                        let routing_packet = RoutingPacket {
-                            header,
-                            table: Some(rt),
-                        };
-                        peer.swag_coder.send(Packet::RoutingPacket(routing_packet,type_id)).await?;
+                        if routing_type == SCC {
+                            scc_sent = Some(Instant::now());
+                        } else {
+                            advertised = Some(rt.clone());
+                        }
+                        let result = peer.swag_coder.send(signed_routing_packet(&state, header, rt, routing_type)).await;
+                        refuse_oversized(&state, result)?;
+                    }
+                    ChannelEvent::TriggeredUpdate => {
+                        let rt = state.advertisement(addr, local_addr).await;
+                        if advertised.as_ref() == Some(&rt) {
+                            tracing::debug!("Not sending a triggered update to {}, nothing changed for it", addr);
+                            continue;
+                        }
+                        advertised = Some(rt.clone());
+                        let result = peer.swag_coder.send(signed_routing_packet(&state, header, rt, STU)).await;
+                        refuse_oversized(&state, result)?;
                     }
                     _ => tracing::error!("Received Event: {:#?} is not implemented!", event),
                 }
@@ -134,163 +204,362 @@ pub async fn process(
             //-----------------received something through this TCP socket-----------------
             result = peer.swag_coder.next() => match result {
                 Some(Ok(packet)) => {
-                    {
-                        let state = state.lock().await;
-                        tracing::info!("New Packet from {}: {:#?}", addr, packet);
-                        state.console_input_sender.send(ChannelEvent::LogToTerminal(format!("New Packet from {}: {:?}", addr, packet))).unwrap();
-                    }
+                    tracing::info!("New Packet from {}: {:#?}", addr, packet);
+                    state.emit(NodeEvent::Log(format!("New Packet from {}: {:?}", addr, packet)));
 
                     //assess what kind of packet we received:
                     match &packet {
                         Packet::RoutedPacket(routed_packet) => {
                             //we received a message, check who's the destination:
-                            let packet_destination = format!("{}:{}",routed_packet.header.dest_ip,routed_packet.header.dest_port);
-                            match  packet_destination == listener_address.to_string() {
+                            let destination_addr = routed_packet.header.dest;
+                            match state.is_own_addr(destination_addr) {
                                 true => {
-                                    //message is for us, display message
-                                    tracing::info!("{}: {}",routed_packet.nickname, routed_packet.message);
-                                    // Send a broadcast to inform everyone about the message
-                                    {
-                                        let mut state = state.lock().await;
-                                        state.broadcast(addr, &ChannelEvent::MessageToTUI(routed_packet.message.clone(), routed_packet.nickname.clone(), addr)).await;
+                                    // Wait for the rest of the message if this is just a fragment
+                                    let routed_packet = if routed_packet.fragment.is_some() {
+                                        match state.reassemble(routed_packet.clone()).await {
+                                            Some(routed_packet) => routed_packet,
+                                            None => continue,
+                                        }
+                                    } else {
+                                        routed_packet.clone()
+                                    };
+                                    let source = routed_packet.header.source;
+                                    // A ping, or the answer to one of ours
+                                    if let Some(ping) = routed_packet.ping {
+                                        match ping.reply {
+                                            true => state.emit(NodeEvent::Pong { source, id: ping.id }),
+                                            false => answer_ping(&state, destination_addr, source, ping, listener_address),
+                                        }
+                                        continue;
                                     }
-                                },
-                                //message is for someone else, try forwarding it:
-                                false => {
-                                    if routed_packet.header.ttl > 0 {
-                                        //parse destination to SocketAddr
-                                        let destination_addr = match packet_destination.parse::<SocketAddr>() {
-                                            Ok(socket) => socket,
-                                            Err(e) => {
-                                                tracing::error!("Error parsing destination to forward to: {}",e);
-                                                continue;
+                                    // A traceroute probe, or the answer to one of ours
+                                    if let Some(trace) = routed_packet.trace {
+                                        match trace.reply {
+                                            true => {
+                                                let hops = trace.hops.iter().zip(trace.latencies()).map(|(hop, latency)| TraceHop { addr: hop.addr, latency }).collect();
+                                                state.emit(NodeEvent::Trace { dest: source, id: trace.id, hops, round_trip: trace.round_trip() });
                                             }
+                                            false => answer_trace(&state, destination_addr, source, trace, listener_address),
+                                        }
+                                        continue;
+                                    }
+                                    // Just a typing indicator, there is nothing to decrypt or acknowledge
+                                    if routed_packet.typing {
+                                        state.emit(NodeEvent::Typing { source, nickname: routed_packet.nickname });
+                                        continue;
+                                    }
+                                    // Only we can decrypt it, with the key pinned for the source
+                                    let encrypted = routed_packet.sealed.is_some();
+                                    let message = match open(&state, &routed_packet).await {
+                                        Ok(message) => message,
+                                        Err(e) => {
+                                            reject(&state, addr, e);
+                                            continue;
+                                        }
+                                    };
+                                    if let Some(id) = routed_packet.id {
+                                        // Acknowledge every copy, the ACK of the first one may have been lost
+                                        let ack = AckPacket {
+                                            header: SharedHeader { source: destination_addr, dest: source, ttl: config.default_ttl },
+                                            id,
                                         };
-                                        { //check routing table for the specified destination
-                                            let lock = state.lock().await;
-                                            //get next destination on route
-                                            let routing_entry = match lock.routing_table.get(&destination_addr) {
-                                                Some(routing_entry) => routing_entry,
-                                                None => {
-                                                    tracing::error!("Forwarding: No route to destination available: {}",destination_addr);
-                                                    continue;
-                                                }
-                                            };
-                                            //get channel to next on route
-                                            let peer = match lock.peers.get(&routing_entry.next) {
-                                                Some(peer) => peer,
-                                                None => {
-                                                    tracing::error!("Forwarding: No channel to destination available: {}",routing_entry.next);
-                                                    continue;
-                                                }
-                                            };
-                                            //internal message to forward the packet as is
-                                            let new_event = ChannelEvent::Forward(packet);
-                                            if let Err(e) = peer.send(new_event) {
-                                                tracing::info!("Error sending your message. error = {:?}", e);
-                                            }
+                                        if let Err(e) = state.route(source, listener_address, ChannelEvent::Forward(Packet::Ack(ack))) {
+                                            tracing::info!("Error acknowledging message {} of {}: {}", id, source, e);
+                                        }
+                                        if !state.first_delivery(source, id).await {
+                                            tracing::debug!("Dropping retransmitted message {} of {}", id, source);
+                                            continue;
                                         }
                                     }
+                                    // The source didn't learn yet that we left the room
+                                    if let Some(room) = &routed_packet.room {
+                                        if !state.snapshot().rooms.contains(room) {
+                                            tracing::debug!("Dropping message of {} to {}, we left it", source, room);
+                                            continue;
+                                        }
+                                    }
+                                    //message is for us, display message
+                                    tracing::info!("{}: {}",routed_packet.nickname, message);
+                                    // Inform everyone subscribed to this node about the message
+                                    state.emit(NodeEvent::Message {
+                                        source,
+                                        nickname: routed_packet.nickname,
+                                        message,
+                                        encrypted,
+                                        room: routed_packet.room,
+                                    });
+                                },
+                                //message is for someone else, try forwarding it:
+                                false => {
+                                    // A traceroute probe notes every node it passes
+                                    let mut packet = packet.clone();
+                                    if let Packet::RoutedPacket(RoutedPacket { trace: Some(trace), .. }) = &mut packet {
+                                        trace.record(listener_address);
+                                    }
+                                    forward(&state, packet, destination_addr, listener_address)
                                 }
                             }
                         }
-                        Packet::RoutingPacket(routing_packet, type_id) => {
+                        Packet::Ack(ack) => {
+                            match state.is_own_addr(ack.header.dest) {
+                                true => state.acknowledge(ack.header.source, ack.id),
+                                false => forward(&state, packet.clone(), ack.header.dest, listener_address),
+                            }
+                        }
+                        Packet::File(file) => {
+                            match state.is_own_addr(file.header.dest) {
+                                true => state.file_packet(file.clone()),
+                                false => forward(&state, packet.clone(), file.header.dest, listener_address),
+                            }
+                        }
+                        Packet::Broadcast(broadcast) => {
+                            let origin = broadcast.header.source;
+                            // Our own broadcast came back around, or we passed this one on already
+                            if state.is_own_addr(origin) || !state.first_broadcast(origin, broadcast.id).await {
+                                tracing::debug!("Dropping copy of broadcast {} of {}", broadcast.id, origin);
+                                continue;
+                            }
+                            tracing::info!("{} to everyone: {}", broadcast.nickname, broadcast.message);
+                            state.emit(NodeEvent::Broadcast {
+                                origin,
+                                nickname: broadcast.nickname.clone(),
+                                message: broadcast.message.clone(),
+                            });
+                            // Pass it on to every other neighbour while its TTL lasts
+                            if broadcast.header.ttl > 0 {
+                                let mut broadcast = broadcast.clone();
+                                broadcast.header.ttl -= 1;
+                                flood(&state, broadcast, Some(addr));
+                            }
+                        }
+                        Packet::RoutingPacket(routing_packet, routing_type) => {
                             tracing::info!("received a routing packet.");
                             //we received a routing packet, check which one and handle it:
-                            let reply_header = SharedHeader {
-                                source_ip: listener_address.ip().to_string(),
-                                source_port: listener_address.port(),
-                                dest_ip: addr.ip().to_string(),
-                                dest_port: addr.port(),
-                                ttl: 16,
+                            let reply_header = header_to(listener_address, addr, config.default_ttl);
+
+                            // Only a signed update we haven't seen before may change our routes
+                            let authentic = match state.verify_update(routing_packet.clone(), *routing_type, addr).await {
+                                Ok(authentic) => authentic,
+                                Err(e) => {
+                                    reject(&state, addr, e);
+                                    continue;
+                                }
                             };
+                            // Anyone can put another node's address in an unsigned update, only believe
+                            // what it says about its source if it comes from the other end of the connection
+                            let source = routing_packet.header.source;
+                            if authentic || source == addr {
+                                if let Some(key) = routing_packet.public_key {
+                                    state.pin_key(source, key).await;
+                                }
+                                state.set_rooms(source, routing_packet.rooms.clone());
+                                state.set_nickname_of(source, routing_packet.nickname.clone());
+                                state.set_presence_of(source, routing_packet.presence.clone());
+                            } else {
+                                tracing::debug!("Ignoring what the unsigned update of {} says about {}", addr, source);
+                            }
 
                             let routingtable = match &routing_packet.table {
                                 Some(table) => table.clone(),
                                 None => Vec::new(),
                             };
 
-                            match *type_id {
-                                //routing packet type_ids:
+                            match *routing_type {
                                 CR | STU => {
-                                    if *type_id == CR {
+                                    if *routing_type == CR {
                                         //Add connection to routing table with source ip + port as target and stream address as next
-                                        let target_address: SocketAddr = (routing_packet.header.source_ip.clone() + ":" + &routing_packet.header.source_port.to_string()).parse::<SocketAddr>().unwrap();
-                                        let mut lock = state.lock().await;
-                                        lock.routing_table.insert(target_address, RoutingTableEntry {next:addr, hop_count: 1, ttl: true});
+                                        state.insert_route(routing_packet.header.source, RoutingTableEntry::new(addr, 1));
                                     }
                                     //need to send a reply containing the routing table:
-                                    let reply_table;
-                                    {
-                                        let mut lock = state.lock().await;
-                                        lock.update_routing_table(routingtable,addr).await;
-                                        reply_table = lock.get_routing_table(addr, local_addr).await;
-                                    }
-                                    let reply_routing_packet: RoutingPacket = RoutingPacket{header: reply_header.clone(), table: Some(reply_table)};
-                                    if *type_id == CR || *type_id == SCC{
-                                        let id = type_id + 1;
-                                        //send CRR or SCCR
-                                        tracing::info!("replying to CR or SCC: {type_id} with {id} to {:?}.", reply_header);
-                                        peer.swag_coder.send(Packet::RoutingPacket(reply_routing_packet, id)).await?;
+                                    state.update_routing_table(routingtable, addr).await;
+                                    if let Some(reply_type) = routing_type.reply() {
+                                        let reply_table = state.advertisement(addr, local_addr).await;
+                                        advertised = Some(reply_table.clone());
+                                        //send CRR
+                                        tracing::info!("replying to {:?} with {:?} to {:?}.", routing_type, reply_type, reply_header);
+                                        peer.swag_coder.send(signed_routing_packet(&state, reply_header, reply_table, reply_type)).await?;
                                     }
                                 },
                                 CRR => {
                                     //update routing table based on received information:
-                                    let mut lock = state.lock().await;
-                                    lock.update_routing_table(routingtable, addr).await;
+                                    state.update_routing_table(routingtable, addr).await;
                                 },
                                 SCC => {
                                     // Send a SCCR to the sender
                                     tracing::info!("replying to SCC with SCCR to {:?}.", reply_header);
-                                    let reply_routing_packet: RoutingPacket = RoutingPacket{header: reply_header.clone(), table: Some(Vec::new())};
-                                    peer.swag_coder.send(Packet::RoutingPacket(reply_routing_packet, SCCR)).await?;
+                                    peer.swag_coder.send(signed_routing_packet(&state, reply_header, Vec::new(), SCCR)).await?;
                                 }
                                 SCCR => {
                                     // Mark the sender as responding:
-                                    let mut lock = state.lock().await;
-                                    let target_address: SocketAddr = (routing_packet.header.source_ip.clone() + ":" + &routing_packet.header.source_port.to_string()).parse::<SocketAddr>().unwrap();
-                                    lock.routing_table.entry(target_address).and_modify(|rt_entry| rt_entry.ttl = true);
+                                    state.mark_alive(routing_packet.header.source);
+                                    if let Some(sent) = scc_sent.take() {
+                                        state.record_rtt(addr, sent.elapsed());
+                                    }
                                 },
-                                //undefined type_id:
-                                MESSAGE => tracing::error!("Routing packet with type_id of Message detected!"),
-                                _ => tracing::error!("Type ID not implemented or expected!"),
                             }
                         }
                     }
                 }
                 // An error occurred.
-                Some(Err(e)) => {
-                    tracing::error!(
-                        "an error occurred while processing messages for {}; error = {:?}",
-                        addr,
-                        e
-                    );
-                }
+                Some(Err(e)) => reject(&state, addr, e),
                 // The stream has been exhausted.
                 None => break,
             },
         }
     }
 
-    // If this section is reached it means that the client was disconnected!
-    // Let's let everyone still connected know about it.
-    {
-        let mut state = state.lock().await;
-        state.peers.remove(&addr);
+    Ok(())
+}
 
-        // Poise reverse routing table
-        for (_dest, rt_entry) in state.routing_table.iter_mut() {
-            if rt_entry.next == addr {
-                rt_entry.hop_count = POISE_UNREACHABLE;
-            }
+/// The plain text of a message for us, decrypting it if it's sealed
+///
+/// Once we know the key of the source it has to encrypt, otherwise any node on the way
+/// could strip the seal and put its own text in.
+async fn open(state: &StateHandle, routed_packet: &RoutedPacket) -> Result<String, Error> {
+    let header = routed_packet.header;
+    let Some(sealed) = &routed_packet.sealed else {
+        if state.config.encryption && state.snapshot().keys.contains_key(&header.source) {
+            return Err(Error::Unsealed(header.source));
         }
+        return Ok(routed_packet.message.clone());
+    };
+    if !state.pin_key(header.source, sealed.key).await {
+        return Err(Error::KeyMismatch(header.source));
+    }
+    state.identity.open(sealed, routed_packet)
+}
 
-        let msg = format!("{} has left the chat", addr);
-        tracing::info!("{}", msg);
-        state
-            .broadcast(addr, &ChannelEvent::Leave(addr.to_string()))
-            .await;
+/// Log a packet of `addr` we can't make sense of and tell the subscribers about it.
+fn reject(state: &StateHandle, addr: SocketAddr, e: Error) {
+    let msg = format!("Rejected packet from {}: {}", addr, e);
+    tracing::warn!("{}", msg);
+    state.emit(NodeEvent::Log(msg));
+}
+
+/// Hand a packet for someone else to the next hop on its route, unless its TTL ran out
+fn forward(
+    state: &StateHandle,
+    mut packet: Packet,
+    dest: SocketAddr,
+    listener_address: SocketAddr,
+) {
+    let Some(header) = packet.routed_header_mut() else {
+        return;
+    };
+    if header.ttl == 0 {
+        tracing::info!("Forwarding: TTL of packet to {} ran out", dest);
+        return;
     }
 
-    Ok(())
+    //internal message to forward the packet as is
+    if let Err(e) = state.route(dest, listener_address, ChannelEvent::Forward(packet)) {
+        tracing::error!("Forwarding: {}", e);
+    }
+}
+
+/// Add ourselves to a traceroute probe that reached us and send its hops back to the source
+fn answer_trace(
+    state: &StateHandle,
+    own: SocketAddr,
+    source: SocketAddr,
+    mut trace: Trace,
+    listener_address: SocketAddr,
+) {
+    trace.record(own);
+    trace.reply = true;
+    let id = trace.id;
+    let header = SharedHeader {
+        source: own,
+        dest: source,
+        ttl: state.config.default_ttl,
+    };
+    let mut reply = RoutedPacket::control(header, state.snapshot().nickname.clone());
+    reply.trace = Some(trace);
+    let event = ChannelEvent::Forward(Packet::RoutedPacket(reply));
+    if let Err(e) = state.route(source, listener_address, event) {
+        tracing::info!("Error answering trace {} of {}: {}", id, source, e);
+    }
+}
+
+/// Send a ping that reached us back to its source
+fn answer_ping(
+    state: &StateHandle,
+    own: SocketAddr,
+    source: SocketAddr,
+    ping: Ping,
+    listener_address: SocketAddr,
+) {
+    let header = SharedHeader {
+        source: own,
+        dest: source,
+        ttl: state.config.default_ttl,
+    };
+    let mut reply = RoutedPacket::control(header, state.snapshot().nickname.clone());
+    reply.ping = Some(Ping {
+        reply: true,
+        ..ping
+    });
+    let event = ChannelEvent::Forward(Packet::RoutedPacket(reply));
+    if let Err(e) = state.route(source, listener_address, event) {
+        tracing::info!("Error answering ping {} of {}: {}", ping.id, source, e);
+    }
+}
+
+/// Queue a broadcast for every neighbour except the one it came from
+pub(crate) fn flood(state: &StateHandle, broadcast: BroadcastPacket, except: Option<SocketAddr>) {
+    for (addr, tx) in state.snapshot().peers.iter() {
+        if Some(*addr) == except {
+            continue;
+        }
+        if let Err(e) = tx.send(ChannelEvent::Flood(broadcast.clone())) {
+            tracing::info!(
+                "Error passing broadcast {} on to {}: {}",
+                broadcast.id,
+                addr,
+                e
+            );
+        }
+    }
+}
+
+/// Keep the connection if a packet was too large to be sent, nothing of it has been written.
+fn refuse_oversized(state: &StateHandle, result: Result<(), Error>) -> Result<(), Error> {
+    match result {
+        Err(Error::PayloadTooLarge(len)) => {
+            let msg = format!("Not sending a packet of {} bytes, it is too large", len);
+            tracing::warn!("{}", msg);
+            state.emit(NodeEvent::Log(msg));
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// A routing packet to a neighbour, signed by us and carrying our key, rooms, nickname and presence so the whole network learns them
+fn signed_routing_packet(
+    state: &StateHandle,
+    header: SharedHeader,
+    table: Vec<RoutingEntry>,
+    routing_type: RoutingType,
+) -> Packet {
+    let mut packet = RoutingPacket {
+        header,
+        table: Some(table),
+        public_key: Some(state.identity.public_key()),
+        rooms: state.snapshot().rooms.iter().cloned().collect(),
+        nickname: Some(state.snapshot().nickname.clone()),
+        presence: Some(state.snapshot().presence.clone()),
+        signed: None,
+    };
+    packet.sign(routing_type, &state.identity);
+    Packet::RoutingPacket(packet, routing_type)
+}
+
+/// Header of a packet we send to the neighbour `addr`, the source is always our listener
+fn header_to(listener_address: SocketAddr, addr: SocketAddr, ttl: u8) -> SharedHeader {
+    SharedHeader {
+        source: listener_address,
+        dest: addr,
+        ttl,
+    }
 }
@@ -1,20 +1,140 @@
+use ack_packet::AckPacket;
+use broadcast_packet::BroadcastPacket;
+use file_packet::FilePacket;
 use routed_packet::RoutedPacket;
 use routing_packet::RoutingPacket;
 
+use crate::error::Error;
+use shared_header::SharedHeader;
+
+pub mod ack_packet;
+pub mod broadcast_packet;
 pub mod common_header;
+pub mod file_packet;
 pub mod routed_packet;
 pub mod routing_packet;
 pub mod shared_header;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Packet {
     RoutedPacket(RoutedPacket),
-    RoutingPacket(RoutingPacket, u8),
+    RoutingPacket(RoutingPacket, RoutingType),
+    Ack(AckPacket),
+    Broadcast(BroadcastPacket),
+    File(FilePacket),
+}
+
+impl Packet {
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Packet::RoutedPacket(_) => PacketType::Message,
+            Packet::RoutingPacket(_, routing_type) => PacketType::Routing(*routing_type),
+            Packet::Ack(_) => PacketType::Ack,
+            Packet::Broadcast(_) => PacketType::Broadcast,
+            Packet::File(_) => PacketType::File,
+        }
+    }
+
+    /// Header of a packet that is routed to its destination, routing packets and broadcasts only go to neighbours
+    pub fn routed_header_mut(&mut self) -> Option<&mut SharedHeader> {
+        match self {
+            Packet::RoutedPacket(packet) => Some(&mut packet.header),
+            Packet::Ack(packet) => Some(&mut packet.header),
+            Packet::File(packet) => Some(&mut packet.header),
+            Packet::RoutingPacket(..) | Packet::Broadcast(_) => None,
+        }
+    }
+}
+
+/// Type of a packet, sent as `type_id` in the common header
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PacketType {
+    Message,
+    Routing(RoutingType),
+    /// Delivery confirmation of a message
+    Ack,
+    /// Message flooded to every node
+    Broadcast,
+    /// Part of a file transfer
+    File,
+}
+
+/// Types of the packets carrying a routing table
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RoutingType {
+    /// Connection request, sent by the node opening a connection
+    CR,
+    /// Answer to a CR
+    CRR,
+    /// Check whether a neighbour is still alive
+    SCC,
+    /// Answer to a SCC
+    SCCR,
+    /// Regular routing table update
+    STU,
 }
 
-pub const MESSAGE: u8 = 1;
-pub const CR: u8 = 2;
-pub const CRR: u8 = 3;
-pub const SCC: u8 = 4;
-pub const SCCR: u8 = 5;
-pub const STU: u8 = 6;
+impl RoutingType {
+    /// The packet type answering this one, if any
+    pub fn reply(self) -> Option<RoutingType> {
+        match self {
+            RoutingType::CR => Some(RoutingType::CRR),
+            RoutingType::SCC => Some(RoutingType::SCCR),
+            _ => None,
+        }
+    }
+}
+
+impl From<PacketType> for u8 {
+    fn from(packet_type: PacketType) -> u8 {
+        match packet_type {
+            PacketType::Message => 1,
+            PacketType::Routing(RoutingType::CR) => 2,
+            PacketType::Routing(RoutingType::CRR) => 3,
+            PacketType::Routing(RoutingType::SCC) => 4,
+            PacketType::Routing(RoutingType::SCCR) => 5,
+            PacketType::Routing(RoutingType::STU) => 6,
+            PacketType::Ack => 7,
+            PacketType::Broadcast => 8,
+            PacketType::File => 9,
+        }
+    }
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = Error;
+
+    fn try_from(type_id: u8) -> Result<Self, Self::Error> {
+        match type_id {
+            1 => Ok(PacketType::Message),
+            2 => Ok(PacketType::Routing(RoutingType::CR)),
+            3 => Ok(PacketType::Routing(RoutingType::CRR)),
+            4 => Ok(PacketType::Routing(RoutingType::SCC)),
+            5 => Ok(PacketType::Routing(RoutingType::SCCR)),
+            6 => Ok(PacketType::Routing(RoutingType::STU)),
+            7 => Ok(PacketType::Ack),
+            8 => Ok(PacketType::Broadcast),
+            9 => Ok(PacketType::File),
+            _ => Err(Error::UnknownPacketType(type_id)),
+        }
+    }
+}
+
+#[test]
+fn test_packet_type_ids() {
+    for type_id in 1..=9 {
+        let packet_type = PacketType::try_from(type_id).unwrap();
+        assert_eq!(u8::from(packet_type), type_id);
+    }
+    assert!(matches!(
+        PacketType::try_from(0),
+        Err(Error::UnknownPacketType(0))
+    ));
+    assert!(matches!(
+        PacketType::try_from(10),
+        Err(Error::UnknownPacketType(10))
+    ));
+    assert_eq!(RoutingType::CR.reply(), Some(RoutingType::CRR));
+    assert_eq!(RoutingType::STU.reply(), None);
+}
@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+use super::shared_header::SharedHeader;
+
+/// Confirms that the message `id` reached its destination, routed back to its source.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AckPacket {
+    pub header: SharedHeader,
+    pub id: u64,
+}
+
+#[test]
+fn test_ack_packet_round_trip() {
+    let json = r#"{"header":{"source_ip":"::1","source_port":6143,"dest_ip":"127.0.0.1","dest_port":6142,"ttl":16},"id":42}"#;
+    let packet: AckPacket = serde_json::from_str(json).unwrap();
+    assert_eq!(packet.id, 42);
+    assert_eq!(packet.header.source, "[::1]:6143".parse().unwrap());
+    assert_eq!(serde_json::to_string(&packet).unwrap(), json);
+}
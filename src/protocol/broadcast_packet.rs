@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+use super::shared_header::SharedHeader;
+
+/// A chat message for every node, flooded from neighbour to neighbour.
+///
+/// The source of the header is the node that said it, the destination is the neighbour
+/// the copy was sent to. `(source, id)` identifies the message, every node passes it on once.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BroadcastPacket {
+    pub header: SharedHeader,
+    pub id: u64,
+    pub nickname: String,
+    pub message: String,
+}
+
+#[test]
+fn test_broadcast_packet_round_trip() {
+    let json = r#"{"header":{"source_ip":"::1","source_port":6143,"dest_ip":"127.0.0.1","dest_port":6142,"ttl":16},"id":42,"nickname":"alice","message":"hello everyone"}"#;
+    let packet: BroadcastPacket = serde_json::from_str(json).unwrap();
+    assert_eq!(packet.id, 42);
+    assert_eq!(packet.header.source, "[::1]:6143".parse().unwrap());
+    assert_eq!(packet.message, "hello everyone");
+    assert_eq!(serde_json::to_string(&packet).unwrap(), json);
+}
@@ -1,24 +1,33 @@
 use serde::{Deserialize, Serialize};
 
+use super::PacketType;
+use crate::error::Error;
+
 pub const COMMON_HEADER_LENGTH: usize = 53;
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct CommonHeader {
     pub length: u16,
     pub crc32: u32,
-    pub type_id: u8,
+    pub packet_type: PacketType,
 }
 
 impl CommonHeader {
-    pub fn from_unparsed(header: CommonHeaderUnparsed) -> Self {
-        Self {
-            length: header.length.parse().unwrap(),
-            crc32: header.crc32.parse().unwrap(),
-            type_id: header.type_id.parse().unwrap(),
-        }
+    pub fn from_unparsed(header: CommonHeaderUnparsed) -> Result<Self, Error> {
+        Ok(Self {
+            length: parse_field("length", &header.length)?,
+            crc32: parse_field("crc32", &header.crc32)?,
+            packet_type: PacketType::try_from(parse_field::<u8>("type_id", &header.type_id)?)?,
+        })
     }
 }
 
+fn parse_field<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidHeader(format!("{} is not a valid number: {:?}", name, value)))
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CommonHeaderUnparsed {
     pub length: String,
@@ -31,7 +40,44 @@ impl CommonHeaderUnparsed {
         Self {
             length: format!("{:0>5}", header.length),
             crc32: format!("{:0>10}", header.crc32),
-            type_id: format!("{:0>1}", header.type_id),
+            type_id: format!("{:0>1}", u8::from(header.packet_type)),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::RoutingType;
+
+#[test]
+fn test_from_unparsed_rejects_garbage() {
+    let header = CommonHeader::from_unparsed(CommonHeaderUnparsed {
+        length: "00012".to_string(),
+        crc32: "0000000042".to_string(),
+        type_id: "6".to_string(),
+    })
+    .unwrap();
+    assert_eq!(
+        header,
+        CommonHeader {
+            length: 12,
+            crc32: 42,
+            packet_type: PacketType::Routing(RoutingType::STU),
         }
+    );
+
+    for (length, crc32, type_id) in [("abcde", "0", "1"), ("1", "-1", "1"), ("1", "0", "300")] {
+        let result = CommonHeader::from_unparsed(CommonHeaderUnparsed {
+            length: length.to_string(),
+            crc32: crc32.to_string(),
+            type_id: type_id.to_string(),
+        });
+        assert!(matches!(result, Err(Error::InvalidHeader(_))));
     }
+
+    let result = CommonHeader::from_unparsed(CommonHeaderUnparsed {
+        length: "00000".to_string(),
+        crc32: "0000000000".to_string(),
+        type_id: "0".to_string(),
+    });
+    assert!(matches!(result, Err(Error::UnknownPacketType(0))));
 }
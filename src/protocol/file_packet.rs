@@ -0,0 +1,87 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use super::shared_header::SharedHeader;
+
+/// Part of a file transfer, routed between the sender and the receiver like a message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FilePacket {
+    pub header: SharedHeader,
+    /// Chosen by the sender, unique per sender
+    pub transfer: u64,
+    pub data: FileData,
+}
+
+/// What a file packet says about its transfer
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileData {
+    /// The sender would like to send a file, `sha256` is the hex digest of its content
+    Offer {
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    /// The receiver wants the file, starting at `offset` since it has the bytes before already
+    Accept { offset: u64 },
+    /// The receiver doesn't want the file or couldn't store it
+    Reject { reason: String },
+    /// Bytes of the file starting at `offset`, base64 encoded
+    Chunk {
+        offset: u64,
+        data: String,
+        crc32: u32,
+    },
+    /// The receiver has every byte before `offset`
+    Ack { offset: u64 },
+    /// The receiver has the whole file and its digest matches
+    Done,
+}
+
+impl FileData {
+    pub fn chunk(offset: u64, bytes: &[u8]) -> FileData {
+        FileData::Chunk {
+            offset,
+            data: STANDARD.encode(bytes),
+            crc32: crc32fast::hash(bytes),
+        }
+    }
+
+    /// The bytes of a chunk, `None` if they don't match its checksum
+    pub fn chunk_bytes(&self) -> Option<Vec<u8>> {
+        let FileData::Chunk { data, crc32, .. } = self else {
+            return None;
+        };
+        STANDARD
+            .decode(data)
+            .ok()
+            .filter(|bytes| crc32fast::hash(bytes) == *crc32)
+    }
+}
+
+#[test]
+fn test_file_packet_round_trip() {
+    let json = r#"{"header":{"source_ip":"::1","source_port":6143,"dest_ip":"127.0.0.1","dest_port":6142,"ttl":16},"transfer":42,"data":{"chunk":{"offset":8192,"data":"aGVsbG8=","crc32":907060870}}}"#;
+    let packet: FilePacket = serde_json::from_str(json).unwrap();
+    assert_eq!(packet.transfer, 42);
+    assert_eq!(packet.data, FileData::chunk(8192, b"hello"));
+    assert_eq!(packet.data.chunk_bytes(), Some(b"hello".to_vec()));
+    assert_eq!(serde_json::to_string(&packet).unwrap(), json);
+
+    let done: FileData = serde_json::from_str(r#""done""#).unwrap();
+    assert_eq!(done, FileData::Done);
+}
+
+#[test]
+fn test_corrupted_chunk() {
+    let FileData::Chunk { offset, data, .. } = FileData::chunk(0, b"hello") else {
+        unreachable!();
+    };
+    let corrupted = FileData::Chunk {
+        offset,
+        data,
+        crc32: 0,
+    };
+    assert_eq!(corrupted.chunk_bytes(), None);
+}
@@ -1,12 +1,182 @@
 use serde::{Deserialize, Serialize};
 
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use super::shared_header::SharedHeader;
+use crate::crypto::Sealed;
+use crate::error::Error;
+use crate::node::parse_addr;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct RoutedPacket {
     pub header: SharedHeader,
     pub nickname: String,
     pub message: String,
+    /// Set by senders that want the message acknowledged, see `AckPacket`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// Set if `message` is encrypted to the destination
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<Sealed>,
+    /// Set if the message was said in a room, rather than sent to the destination alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Set if `message` is only one part of a message too large for a single packet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fragment: Option<Fragment>,
+    /// Set if the source is typing a message to the destination, `message` is empty then
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub typing: bool,
+    /// Set if this is a traceroute probe or its answer, `message` is empty then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<Trace>,
+    /// Set if this is a ping or its answer, `message` is empty then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ping: Option<Ping>,
+}
+
+/// Position of a fragment in the message it belongs to.
+///
+/// Fragments are routed on their own and put back together by the destination.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fragment {
+    /// Shared by all fragments of a message, unique per source
+    pub id: u32,
+    pub index: u16,
+    pub count: u16,
+}
+
+impl RoutedPacket {
+    /// A packet without a message, for a typing indicator, trace or ping
+    pub fn control(header: SharedHeader, nickname: String) -> Self {
+        RoutedPacket {
+            header,
+            nickname,
+            message: String::new(),
+            id: None,
+            sealed: None,
+            room: None,
+            fragment: None,
+            typing: false,
+            trace: None,
+            ping: None,
+        }
+    }
+}
+
+/// A ping, the destination sends it back as the answer
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ping {
+    /// Unique per source
+    pub id: u64,
+    /// Set on the way back
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reply: bool,
+}
+
+/// Path a traceroute probe took, every node on the way adds itself to it.
+///
+/// The destination adds itself as well and sends the hops back to the source.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Trace {
+    /// Unique per source
+    pub id: u64,
+    pub hops: Vec<Hop>,
+    /// Set on the way back, the hops don't change anymore then
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reply: bool,
+}
+
+/// A node a traceroute probe passed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(into = "HopWire", try_from = "HopWire")]
+pub struct Hop {
+    /// Listener address of the node
+    pub addr: SocketAddr,
+    /// Milliseconds since the Unix epoch by the clock of the node, when the probe passed it
+    pub time: u64,
+}
+
+/// `Hop` as it is sent, with ip and port in separate fields
+#[derive(Serialize, Deserialize)]
+struct HopWire {
+    ip: String,
+    port: u16,
+    time: u64,
+}
+
+impl From<Hop> for HopWire {
+    fn from(hop: Hop) -> Self {
+        HopWire {
+            ip: hop.addr.ip().to_string(),
+            port: hop.addr.port(),
+            time: hop.time,
+        }
+    }
+}
+
+impl TryFrom<HopWire> for Hop {
+    type Error = Error;
+
+    fn try_from(wire: HopWire) -> Result<Self, Self::Error> {
+        Ok(Hop {
+            addr: parse_addr(&wire.ip, wire.port)?,
+            time: wire.time,
+        })
+    }
+}
+
+impl Trace {
+    /// A probe starting at `source`
+    pub fn new(id: u64, source: SocketAddr) -> Self {
+        let mut trace = Trace {
+            id,
+            hops: Vec::new(),
+            reply: false,
+        };
+        trace.record(source);
+        trace
+    }
+
+    /// Add `addr` as the next hop, unless the probe is on its way back already
+    pub fn record(&mut self, addr: SocketAddr) {
+        if !self.reply {
+            self.hops.push(Hop {
+                addr,
+                time: now_millis(),
+            });
+        }
+    }
+
+    /// Time each hop took after the one before it, zero for the source.
+    ///
+    /// The clocks of the nodes aren't synchronized, so these are estimates and
+    /// a hop whose clock is behind shows up as zero.
+    pub fn latencies(&self) -> Vec<Duration> {
+        let mut previous = self.hops.first().map(|hop| hop.time).unwrap_or_default();
+        self.hops
+            .iter()
+            .map(|hop| {
+                let latency = hop.time.saturating_sub(previous);
+                previous = hop.time;
+                Duration::from_millis(latency)
+            })
+            .collect()
+    }
+
+    /// Time since the probe left the source, only meaningful at the source itself
+    pub fn round_trip(&self) -> Duration {
+        let start = self.hops.first().map(|hop| hop.time).unwrap_or_default();
+        Duration::from_millis(now_millis().saturating_sub(start))
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
 }
 
 #[test]
@@ -23,10 +193,11 @@ fn test_parsing_routed_packet() {
     }"#;
 
     let packet: RoutedPacket = serde_json::from_str(json).unwrap();
-    assert_eq!(packet.header.source_ip, "192.168.123.122");
-    assert_eq!(packet.header.source_port, 6827);
-    assert_eq!(packet.header.dest_ip, "192.168.234.233");
-    assert_eq!(packet.header.dest_port, 234);
+    assert_eq!(
+        packet.header.source,
+        "192.168.123.122:6827".parse().unwrap()
+    );
+    assert_eq!(packet.header.dest, "192.168.234.233:234".parse().unwrap());
     assert_eq!(packet.nickname, "Test");
     assert_eq!(packet.header.ttl, 16);
     assert_eq!(packet.message, "Test Data");
@@ -36,14 +207,19 @@ fn test_parsing_routed_packet() {
 fn test_serializing_routed_packet() {
     let packet = RoutedPacket {
         header: SharedHeader {
-            source_ip: "192.168.101.101".to_string(),
-            source_port: 1234,
-            dest_ip: "153.132.143.121".to_string(),
-            dest_port: 4321,
+            source: "192.168.101.101:1234".parse().unwrap(),
+            dest: "153.132.143.121:4321".parse().unwrap(),
             ttl: 32,
         },
         nickname: "Test".to_string(),
         message: "Testing".to_string(),
+        id: None,
+        sealed: None,
+        room: None,
+        fragment: None,
+        typing: false,
+        trace: None,
+        ping: None,
     };
 
     let json = serde_json::to_string(&packet).unwrap();
@@ -53,3 +229,43 @@ fn test_serializing_routed_packet() {
         r#"{"header":{"source_ip":"192.168.101.101","source_port":1234,"dest_ip":"153.132.143.121","dest_port":4321,"ttl":32},"nickname":"Test","message":"Testing"}"#
     );
 }
+
+#[test]
+fn test_routed_packet_round_trip() {
+    for json in [
+        r#"{"header":{"source_ip":"127.0.0.1","source_port":58471,"dest_ip":"127.0.0.1","dest_port":6143,"ttl":16},"nickname":"TODO","message":"hi"}"#,
+        r#"{"header":{"source_ip":"fd00::1","source_port":6142,"dest_ip":"::1","dest_port":6143,"ttl":1},"nickname":"Test","message":"{\"json\": true}"}"#,
+        r##"{"header":{"source_ip":"::1","source_port":6142,"dest_ip":"::1","dest_port":6143,"ttl":16},"nickname":"Test","message":"part","id":12,"room":"#ops","fragment":{"id":7,"index":1,"count":3}}"##,
+    ] {
+        let packet: RoutedPacket = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_string(&packet).unwrap(), json);
+    }
+}
+
+#[test]
+fn test_trace() {
+    let source = "10.0.0.1:6142".parse().unwrap();
+    let mut trace = Trace::new(7, source);
+    trace.hops.push(Hop {
+        addr: "[fd00::2]:6142".parse().unwrap(),
+        time: trace.hops[0].time + 5,
+    });
+    // A clock that is behind doesn't make the latency negative
+    trace.hops.push(Hop {
+        addr: "10.0.0.3:6142".parse().unwrap(),
+        time: trace.hops[0].time + 2,
+    });
+    assert_eq!(
+        trace.latencies(),
+        vec![Duration::ZERO, Duration::from_millis(5), Duration::ZERO]
+    );
+
+    // The hops are fixed on the way back
+    trace.reply = true;
+    trace.record("10.0.0.4:6142".parse().unwrap());
+    assert_eq!(trace.hops.len(), 3);
+
+    let json = serde_json::to_string(&trace).unwrap();
+    assert!(json.contains(r#"{"ip":"fd00::2","port":6142,"time":"#));
+    assert_eq!(serde_json::from_str::<Trace>(&json).unwrap(), trace);
+}
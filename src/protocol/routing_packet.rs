@@ -1,22 +1,132 @@
 use serde::{Deserialize, Serialize};
 
+use std::net::SocketAddr;
+
 use super::shared_header::SharedHeader;
+use super::{PacketType, RoutingType};
+use crate::crypto::{Identity, NodeKey, Signed};
+use crate::error::Error;
+use crate::node::parse_addr;
+use crate::presence::Presence;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(into = "RoutingEntryWire", try_from = "RoutingEntryWire")]
 pub struct RoutingEntry {
+    pub target: SocketAddr,
+    pub next: SocketAddr,
     //not sure about the int types here, we didn't specify anything in the protocol
-    pub target_ip: String,
-    pub target_port: u16,
-    pub next_ip: String,
-    pub next_port: u16,
     pub hop_count: i32,
+    /// Key of the target, if we know it
+    pub public_key: Option<NodeKey>,
+    /// Rooms the target is a member of
+    pub rooms: Vec<String>,
+    /// Nickname of the target, if we know it
+    pub nickname: Option<String>,
+    /// Presence of the target, if we know it
+    pub presence: Option<Presence>,
+}
+
+/// `RoutingEntry` as it is sent, with ip and port in separate fields
+#[derive(Serialize, Deserialize)]
+struct RoutingEntryWire {
+    target_ip: String,
+    target_port: u16,
+    next_ip: String,
+    next_port: u16,
+    hop_count: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    public_key: Option<NodeKey>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rooms: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nickname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    presence: Option<Presence>,
+}
+
+impl From<RoutingEntry> for RoutingEntryWire {
+    fn from(entry: RoutingEntry) -> Self {
+        RoutingEntryWire {
+            target_ip: entry.target.ip().to_string(),
+            target_port: entry.target.port(),
+            next_ip: entry.next.ip().to_string(),
+            next_port: entry.next.port(),
+            hop_count: entry.hop_count,
+            public_key: entry.public_key,
+            rooms: entry.rooms,
+            nickname: entry.nickname,
+            presence: entry.presence,
+        }
+    }
+}
+
+impl TryFrom<RoutingEntryWire> for RoutingEntry {
+    type Error = Error;
+
+    fn try_from(wire: RoutingEntryWire) -> Result<Self, Self::Error> {
+        Ok(RoutingEntry {
+            target: parse_addr(&wire.target_ip, wire.target_port)?,
+            next: parse_addr(&wire.next_ip, wire.next_port)?,
+            hop_count: wire.hop_count,
+            public_key: wire.public_key,
+            rooms: wire.rooms,
+            nickname: wire.nickname,
+            presence: wire.presence,
+        })
+    }
 }
 
 ///IF I understood this correctly every routing packet looks like this and just has a different type_id to trigger a different reaction
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct RoutingPacket {
     pub header: SharedHeader,
     pub table: Option<Vec<RoutingEntry>>, //works perfectly like this
+    /// Key of the sender, for encrypting messages to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<NodeKey>,
+    /// Rooms the sender is a member of
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rooms: Vec<String>,
+    /// Nickname of the sender
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
+    /// Presence of the sender
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence: Option<Presence>,
+    /// Signature of the sender over everything but the TTL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed: Option<Signed>,
+}
+
+impl RoutingPacket {
+    /// Sign the packet as a `routing_type` with the next sequence number of `identity`
+    pub fn sign(&mut self, routing_type: RoutingType, identity: &Identity) {
+        self.signed = Some(identity.sign(&self.signed_data(routing_type)));
+    }
+
+    /// Check the signature, returns `None` if the packet isn't signed.
+    pub fn verify(&self, routing_type: RoutingType) -> Result<Option<&Signed>, Error> {
+        match &self.signed {
+            Some(signed) => {
+                signed.verify(&self.signed_data(routing_type))?;
+                Ok(Some(signed))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn signed_data(&self, routing_type: RoutingType) -> Vec<u8> {
+        let type_id = u8::from(PacketType::Routing(routing_type));
+        let header = (self.header.source, self.header.dest);
+        let about = (
+            &self.public_key,
+            &self.rooms,
+            &self.nickname,
+            &self.presence,
+        );
+        serde_json::to_vec(&(type_id, header, &self.table, about))
+            .expect("routing packets always serialize")
+    }
 }
 
 #[test]
@@ -46,27 +156,32 @@ fn test_parsing_routing_packet() {
         ]
     }"#;
     let packet: RoutingPacket = serde_json::from_str(json).unwrap();
-    assert_eq!(packet.header.source_ip, "192.168.123.122");
-    assert_eq!(packet.header.source_port, 6827);
-    assert_eq!(packet.header.dest_ip, "192.168.234.233");
-    assert_eq!(packet.header.dest_port, 234);
+    assert_eq!(
+        packet.header.source,
+        "192.168.123.122:6827".parse().unwrap()
+    );
+    assert_eq!(packet.header.dest, "192.168.234.233:234".parse().unwrap());
     assert_eq!(packet.header.ttl, 16);
     assert_eq!(
         packet.table,
         Some(vec![
             RoutingEntry {
-                target_ip: "10.0.0.5".to_string(),
-                target_port: 1234,
-                next_ip: "10.0.0.3".to_string(),
-                next_port: 1234,
-                hop_count: 4
+                target: "10.0.0.5:1234".parse().unwrap(),
+                next: "10.0.0.3:1234".parse().unwrap(),
+                hop_count: 4,
+                public_key: None,
+                rooms: Vec::new(),
+                nickname: None,
+                presence: None,
             },
             RoutingEntry {
-                target_ip: "10.0.0.11".to_string(),
-                target_port: 1234,
-                next_ip: "10.0.0.6".to_string(),
-                next_port: 1234,
-                hop_count: 2
+                target: "10.0.0.11:1234".parse().unwrap(),
+                next: "10.0.0.6:1234".parse().unwrap(),
+                hop_count: 2,
+                public_key: None,
+                rooms: Vec::new(),
+                nickname: None,
+                presence: None,
             }
         ])
     );
@@ -76,29 +191,36 @@ fn test_parsing_routing_packet() {
 fn test_serializing_routing_packet() {
     let table = vec![
         RoutingEntry {
-            target_ip: "10.0.0.5".to_string(),
-            target_port: 1234,
-            next_ip: "10.0.0.3".to_string(),
-            next_port: 1234,
+            target: "10.0.0.5:1234".parse().unwrap(),
+            next: "10.0.0.3:1234".parse().unwrap(),
             hop_count: 4,
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
         },
         RoutingEntry {
-            target_ip: "10.0.0.11".to_string(),
-            target_port: 1234,
-            next_ip: "10.0.0.6".to_string(),
-            next_port: 1234,
+            target: "10.0.0.11:1234".parse().unwrap(),
+            next: "10.0.0.6:1234".parse().unwrap(),
             hop_count: 2,
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
         },
     ];
     let packet = RoutingPacket {
         header: SharedHeader {
-            source_ip: "192.168.101.101".to_string(),
-            source_port: 1234,
-            dest_ip: "153.132.143.121".to_string(),
-            dest_port: 4321,
+            source: "192.168.101.101:1234".parse().unwrap(),
+            dest: "153.132.143.121:4321".parse().unwrap(),
             ttl: 32,
         },
         table: Some(table),
+        public_key: None,
+        rooms: Vec::new(),
+        nickname: None,
+        presence: None,
+        signed: None,
     };
     let json = serde_json::to_string(&packet).unwrap();
 
@@ -107,3 +229,65 @@ fn test_serializing_routing_packet() {
         r#"{"header":{"source_ip":"192.168.101.101","source_port":1234,"dest_ip":"153.132.143.121","dest_port":4321,"ttl":32},"table":[{"target_ip":"10.0.0.5","target_port":1234,"next_ip":"10.0.0.3","next_port":1234,"hop_count":4},{"target_ip":"10.0.0.11","target_port":1234,"next_ip":"10.0.0.6","next_port":1234,"hop_count":2}]}"#
     );
 }
+
+#[test]
+fn test_routing_packet_round_trip() {
+    for json in [
+        r#"{"header":{"source_ip":"10.241.51.185","source_port":46455,"dest_ip":"10.241.51.185","dest_port":50847,"ttl":16},"table":[]}"#,
+        r#"{"header":{"source_ip":"::1","source_port":6142,"dest_ip":"::1","dest_port":6143,"ttl":16},"table":[{"target_ip":"fd00::5","target_port":1234,"next_ip":"::1","next_port":6142,"hop_count":32}]}"#,
+        r#"{"header":{"source_ip":"10.0.0.1","source_port":1,"dest_ip":"10.0.0.2","dest_port":2,"ttl":16},"table":null}"#,
+    ] {
+        let packet: RoutingPacket = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_string(&packet).unwrap(), json);
+    }
+
+    let invalid = r#"{"header":{"source_ip":"10.0.0.1","source_port":1,"dest_ip":"10.0.0.2","dest_port":2,"ttl":16},"table":[{"target_ip":"10.0.0.5:1","target_port":1,"next_ip":"10.0.0.3","next_port":1,"hop_count":1}]}"#;
+    assert!(serde_json::from_str::<RoutingPacket>(invalid).is_err());
+}
+
+#[test]
+fn test_signed_routing_packet() {
+    let identity = Identity::generate();
+    let mut packet = RoutingPacket {
+        header: SharedHeader {
+            source: "10.0.0.1:1234".parse().unwrap(),
+            dest: "[fd00::2]:1234".parse().unwrap(),
+            ttl: 16,
+        },
+        table: Some(vec![RoutingEntry {
+            target: "10.0.0.5:1234".parse().unwrap(),
+            next: "10.0.0.1:1234".parse().unwrap(),
+            hop_count: 1,
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
+        }]),
+        public_key: Some(identity.public_key()),
+        rooms: Vec::new(),
+        nickname: None,
+        presence: None,
+        signed: None,
+    };
+    assert_eq!(packet.verify(RoutingType::STU).unwrap(), None);
+
+    packet.sign(RoutingType::STU, &identity);
+    let json = serde_json::to_string(&packet).unwrap();
+    let mut packet: RoutingPacket = serde_json::from_str(&json).unwrap();
+    assert_eq!(
+        packet.verify(RoutingType::STU).unwrap().unwrap().key,
+        identity.signing_key()
+    );
+    // The TTL isn't covered, the packet type and the table are
+    packet.header.ttl = 1;
+    assert!(packet.verify(RoutingType::STU).is_ok());
+    assert!(matches!(
+        packet.verify(RoutingType::CRR),
+        Err(Error::BadSignature)
+    ));
+    packet.table.as_mut().unwrap()[0].hop_count = 0;
+    assert!(matches!(
+        packet.verify(RoutingType::STU),
+        Err(Error::BadSignature)
+    ));
+}
@@ -1,10 +1,64 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+use std::net::SocketAddr;
+
+use crate::error::Error;
+use crate::node::parse_addr;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(into = "SharedHeaderWire", try_from = "SharedHeaderWire")]
 pub struct SharedHeader {
-    pub source_ip: String,
-    pub source_port: u16,
-    pub dest_ip: String,
-    pub dest_port: u16,
+    /// Listener address of the node that created the packet
+    pub source: SocketAddr,
+    /// Listener address of the node the packet is meant for
+    pub dest: SocketAddr,
     pub ttl: u8,
 }
+
+/// `SharedHeader` as it is sent, with ip and port in separate fields
+#[derive(Serialize, Deserialize)]
+struct SharedHeaderWire {
+    source_ip: String,
+    source_port: u16,
+    dest_ip: String,
+    dest_port: u16,
+    ttl: u8,
+}
+
+impl From<SharedHeader> for SharedHeaderWire {
+    fn from(header: SharedHeader) -> Self {
+        SharedHeaderWire {
+            source_ip: header.source.ip().to_string(),
+            source_port: header.source.port(),
+            dest_ip: header.dest.ip().to_string(),
+            dest_port: header.dest.port(),
+            ttl: header.ttl,
+        }
+    }
+}
+
+impl TryFrom<SharedHeaderWire> for SharedHeader {
+    type Error = Error;
+
+    fn try_from(wire: SharedHeaderWire) -> Result<Self, Self::Error> {
+        Ok(SharedHeader {
+            source: parse_addr(&wire.source_ip, wire.source_port)?,
+            dest: parse_addr(&wire.dest_ip, wire.dest_port)?,
+            ttl: wire.ttl,
+        })
+    }
+}
+
+#[test]
+fn test_shared_header_round_trip() {
+    for json in [
+        r#"{"source_ip":"192.168.101.101","source_port":1234,"dest_ip":"153.132.143.121","dest_port":4321,"ttl":32}"#,
+        r#"{"source_ip":"::1","source_port":1234,"dest_ip":"fe80::1","dest_port":4321,"ttl":0}"#,
+    ] {
+        let header: SharedHeader = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_string(&header).unwrap(), json);
+    }
+
+    let invalid = r#"{"source_ip":"nope","source_port":1,"dest_ip":"::1","dest_port":1,"ttl":1}"#;
+    assert!(serde_json::from_str::<SharedHeader>(invalid).is_err());
+}
@@ -0,0 +1,285 @@
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::channel_events::ChannelEvent;
+
+/// What happens to a chat item that doesn't fit into a full peer queue.
+///
+/// Routing items are never dropped, a routing item of a type that is already
+/// queued is merged into it, since the table is only read when it's sent.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued chat item to make room
+    #[default]
+    DropOldest,
+    /// Drop the item that didn't fit
+    DropNewest,
+    /// The peer is too slow, close the connection
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop_newest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!(
+                "Unknown queue policy: {} (drop_oldest, drop_newest or disconnect)",
+                s
+            )),
+        }
+    }
+}
+
+/// Why an item was not queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// The queue is full and the item was dropped
+    Full,
+    /// The peer has been disconnected
+    Closed,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Full => write!(f, "peer queue is full"),
+            QueueError::Closed => write!(f, "peer has been disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+/// Fill level of the queue of one peer
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueStats {
+    pub queued: usize,
+    pub dropped: u64,
+}
+
+#[derive(Debug)]
+struct PeerQueue {
+    items: Mutex<VecDeque<ChannelEvent>>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+}
+
+/// Sending half of the outbound queue of a peer, cheap to clone.
+#[derive(Clone, Debug)]
+pub struct PeerSender {
+    queue: Arc<PeerQueue>,
+}
+
+/// Receiving half of the outbound queue of a peer, owned by its `process` task.
+#[derive(Debug)]
+pub struct PeerReceiver {
+    queue: Arc<PeerQueue>,
+}
+
+/// Create a bounded queue for a peer.
+pub fn peer_queue(capacity: usize, policy: OverflowPolicy) -> (PeerSender, PeerReceiver) {
+    let queue = Arc::new(PeerQueue {
+        items: Mutex::new(VecDeque::with_capacity(capacity)),
+        notify: Notify::new(),
+        capacity,
+        policy,
+        dropped: AtomicU64::new(0),
+        closed: AtomicBool::new(false),
+    });
+    (
+        PeerSender {
+            queue: queue.clone(),
+        },
+        PeerReceiver { queue },
+    )
+}
+
+/// Routing packets, and the triggered updates that may become one
+fn is_routing(event: &ChannelEvent) -> bool {
+    matches!(
+        event,
+        ChannelEvent::Routing(_) | ChannelEvent::TriggeredUpdate
+    )
+}
+
+/// Whether two routing events would send the same packet
+fn same_routing(a: &ChannelEvent, b: &ChannelEvent) -> bool {
+    match (a, b) {
+        (ChannelEvent::Routing(a), ChannelEvent::Routing(b)) => a == b,
+        (ChannelEvent::TriggeredUpdate, ChannelEvent::TriggeredUpdate) => true,
+        _ => false,
+    }
+}
+
+impl PeerSender {
+    /// Queue an event for the peer without waiting, applying the overflow policy if it's full.
+    pub fn send(&self, event: ChannelEvent) -> Result<(), QueueError> {
+        let queue = &self.queue;
+        if queue.closed.load(Ordering::Acquire) {
+            return Err(QueueError::Closed);
+        }
+
+        {
+            let mut items = queue.items.lock().unwrap();
+            if is_routing(&event) {
+                // Routing is never dropped, but one queued packet per type is enough
+                let queued = items.iter().any(|item| same_routing(item, &event));
+                if !queued {
+                    items.push_back(event);
+                }
+            } else if items.len() < queue.capacity {
+                items.push_back(event);
+            } else {
+                match queue.policy {
+                    OverflowPolicy::DropOldest => {
+                        let oldest = items.iter().position(|item| !is_routing(item));
+                        queue.dropped.fetch_add(1, Ordering::Relaxed);
+                        match oldest {
+                            Some(index) => {
+                                items.remove(index);
+                                items.push_back(event);
+                            }
+                            // Nothing but routing queued, the new item has to go
+                            None => return Err(QueueError::Full),
+                        }
+                    }
+                    OverflowPolicy::DropNewest => {
+                        queue.dropped.fetch_add(1, Ordering::Relaxed);
+                        return Err(QueueError::Full);
+                    }
+                    OverflowPolicy::Disconnect => {
+                        queue.dropped.fetch_add(1, Ordering::Relaxed);
+                        queue.closed.store(true, Ordering::Release);
+                        queue.notify.notify_one();
+                        return Err(QueueError::Closed);
+                    }
+                }
+            }
+        }
+
+        queue.notify.notify_one();
+        Ok(())
+    }
+
+    /// Number of chat items dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            queued: self.queue.items.lock().unwrap().len(),
+            dropped: self.dropped(),
+        }
+    }
+}
+
+impl PeerReceiver {
+    /// Wait for the next event.
+    ///
+    /// Returns `None` once the peer got disconnected for being too slow.
+    pub async fn recv(&mut self) -> Option<ChannelEvent> {
+        loop {
+            if self.queue.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            if let Some(event) = self.queue.items.lock().unwrap().pop_front() {
+                return Some(event);
+            }
+            self.queue.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::protocol::RoutingType;
+
+#[cfg(test)]
+fn drain(receiver: &mut PeerReceiver) -> Vec<String> {
+    receiver
+        .queue
+        .items
+        .lock()
+        .unwrap()
+        .drain(..)
+        .map(|event| match event {
+            ChannelEvent::Message(msg, ..) => msg,
+            ChannelEvent::Routing(routing_type) => format!("{:?}", routing_type),
+            ChannelEvent::TriggeredUpdate => "triggered".to_string(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+#[test]
+fn test_drop_oldest_keeps_routing() {
+    let addr = "127.0.0.1:6142".parse().unwrap();
+    let (tx, mut rx) = peer_queue(3, OverflowPolicy::DropOldest);
+    tx.send(ChannelEvent::Routing(RoutingType::STU)).unwrap();
+    tx.send(ChannelEvent::Message("1".to_string(), addr, 0, None))
+        .unwrap();
+    tx.send(ChannelEvent::Message("2".to_string(), addr, 0, None))
+        .unwrap();
+    tx.send(ChannelEvent::Message("3".to_string(), addr, 0, None))
+        .unwrap();
+    // Merged into the queued one
+    tx.send(ChannelEvent::Routing(RoutingType::STU)).unwrap();
+    // Over capacity, but routing is never dropped
+    tx.send(ChannelEvent::Routing(RoutingType::SCC)).unwrap();
+    tx.send(ChannelEvent::TriggeredUpdate).unwrap();
+    tx.send(ChannelEvent::TriggeredUpdate).unwrap();
+
+    assert_eq!(tx.dropped(), 1);
+    assert_eq!(drain(&mut rx), vec!["STU", "2", "3", "SCC", "triggered"]);
+}
+
+#[test]
+fn test_drop_newest() {
+    let addr = "127.0.0.1:6142".parse().unwrap();
+    let (tx, mut rx) = peer_queue(1, OverflowPolicy::DropNewest);
+    tx.send(ChannelEvent::Message("1".to_string(), addr, 0, None))
+        .unwrap();
+    assert_eq!(
+        tx.send(ChannelEvent::Message("2".to_string(), addr, 0, None)),
+        Err(QueueError::Full)
+    );
+    assert_eq!(
+        tx.stats(),
+        QueueStats {
+            queued: 1,
+            dropped: 1
+        }
+    );
+    assert_eq!(drain(&mut rx), vec!["1"]);
+}
+
+#[tokio::test]
+async fn test_disconnect_slow_peer() {
+    let addr = "127.0.0.1:6142".parse().unwrap();
+    let (tx, mut rx) = peer_queue(1, OverflowPolicy::Disconnect);
+    tx.send(ChannelEvent::Message("1".to_string(), addr, 0, None))
+        .unwrap();
+    assert_eq!(
+        tx.send(ChannelEvent::Message("2".to_string(), addr, 0, None)),
+        Err(QueueError::Closed)
+    );
+    assert_eq!(
+        tx.send(ChannelEvent::Routing(RoutingType::STU)),
+        Err(QueueError::Closed)
+    );
+    assert!(rx.recv().await.is_none());
+}
@@ -0,0 +1,68 @@
+use serde::Serialize;
+
+use std::fmt;
+use std::time::Duration;
+
+/// Round-trip time to a neighbour, smoothed over the SCC/SCCR exchanges like TCP does (RFC 6298)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct RttEstimate {
+    /// Smoothed round-trip time
+    pub srtt: Duration,
+    /// Smoothed deviation of the samples from `srtt`
+    pub jitter: Duration,
+    /// The latest sample
+    pub last: Duration,
+}
+
+impl RttEstimate {
+    /// An estimate from its first sample
+    pub fn new(sample: Duration) -> Self {
+        RttEstimate {
+            srtt: sample,
+            jitter: sample / 2,
+            last: sample,
+        }
+    }
+
+    /// Add a sample, it weighs an eighth in the average and a quarter in the jitter
+    pub fn update(&mut self, sample: Duration) {
+        let deviation = self.srtt.abs_diff(sample);
+        self.jitter = (self.jitter * 3 + deviation) / 4;
+        self.srtt = (self.srtt * 7 + sample) / 8;
+        self.last = sample;
+    }
+}
+
+impl fmt::Display for RttEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}±{:.1} ms",
+            self.srtt.as_secs_f64() * 1000.0,
+            self.jitter.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+#[test]
+fn test_rtt_estimate() {
+    let mut rtt = RttEstimate::new(Duration::from_millis(80));
+    assert_eq!(rtt.jitter, Duration::from_millis(40));
+
+    // A steady link settles down
+    for _ in 0..50 {
+        rtt.update(Duration::from_millis(80));
+    }
+    assert_eq!(rtt.srtt, Duration::from_millis(80));
+    assert!(rtt.jitter < Duration::from_millis(1));
+
+    // A single spike moves the average by an eighth of the difference
+    rtt.update(Duration::from_millis(160));
+    assert_eq!(rtt.srtt, Duration::from_millis(90));
+    assert_eq!(rtt.last, Duration::from_millis(160));
+    assert!(rtt.jitter > Duration::from_millis(20));
+    assert_eq!(
+        RttEstimate::new(Duration::from_micros(1500)).to_string(),
+        "1.5±0.8 ms"
+    );
+}
@@ -1,283 +1,984 @@
-use channel_events::ChannelEvent;
+use channel_events::{ChannelEvent, NodeEvent};
 
-use tokio::sync::mpsc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
 
 use crate::channel_events;
-use crate::protocol::routing_packet::RoutingEntry;
+use crate::config::NodeConfig;
+use crate::crypto::{Identity, NodeKey, SignaturePolicy};
+use crate::delivery::{Inbox, Outbox, BROADCAST_WINDOW};
+use crate::error::Error;
+use crate::fragment::Reassembly;
+use crate::presence::{Presence, TypingLimiter};
+use crate::protocol::routing_packet::{RoutingEntry, RoutingPacket};
+use crate::protocol::RoutingType;
+use crate::queue::PeerSender;
+use crate::rtt::RttEstimate;
+use crate::transfer::Transfers;
 
-/// Shorthand for the transmit half of the message channel.
+/// Shorthand for the transmit half of the user interface channel.
 pub type Tx = mpsc::UnboundedSender<ChannelEvent>;
 
-/// Shorthand for the receive half of the message channel.
+/// Shorthand for the receive half of the user interface channel.
 pub type Rx = mpsc::UnboundedReceiver<ChannelEvent>;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// Number of node events buffered for slow subscribers before they start lagging.
+pub const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct RoutingTableEntry {
     pub next: SocketAddr,
     pub hop_count: i32,
-    pub ttl: bool, //or timestamp? TODO
+    /// When the route was last confirmed by its next hop, or when it became unreachable
+    #[serde(skip)]
+    pub updated: Instant,
+    /// Set for peers we connected to ourselves, their entry is never removed
+    pub dont_expire: bool,
+}
+
+/// What a neighbour hears about the routes that go through it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitHorizon {
+    /// Advertise them like every other route, only useful for debugging routing loops
+    Off,
+    /// Leave them out
+    #[default]
+    Simple,
+    /// Advertise them as unreachable, so the neighbour drops a route back through us right away
+    PoisonReverse,
+}
+
+impl FromStr for SplitHorizon {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(SplitHorizon::Off),
+            "simple" => Ok(SplitHorizon::Simple),
+            "poison_reverse" => Ok(SplitHorizon::PoisonReverse),
+            _ => Err(format!(
+                "Unknown split horizon mode: {} (off, simple or poison_reverse)",
+                s
+            )),
+        }
+    }
+}
+
+impl RoutingTableEntry {
+    /// A route confirmed just now
+    pub fn new(next: SocketAddr, hop_count: i32) -> Self {
+        RoutingTableEntry {
+            next,
+            hop_count,
+            updated: Instant::now(),
+            dont_expire: false,
+        }
+    }
+
+    /// Mark the route as unreachable, it's collected once it stays so for long enough
+    pub fn poison(&mut self, unreachable: i32, now: Instant) {
+        if self.hop_count < unreachable {
+            self.hop_count = unreachable;
+            self.updated = now;
+        }
+    }
+}
+
+/// Turn IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, into plain IPv4 ones.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Whether `addr` reaches one of our listeners
+pub fn is_own_addr(listeners: &[SocketAddr], addr: SocketAddr) -> bool {
+    listeners.iter().any(|listener| {
+        listener.port() == addr.port()
+            && (listener.ip() == addr.ip()
+                // A wildcard listener is reachable at every local address of its family,
+                // on IPv6 dual-stack sockets also at the IPv4 ones
+                || (listener.ip().is_unspecified()
+                    && (listener.is_ipv6() || addr.is_ipv4())))
+    })
+}
+
+/// The address we are known by on a connection whose local end is `local`.
+///
+/// That's the listener bound to the same IP, for wildcard listeners the IP of the connection.
+pub fn own_addr(listeners: &[SocketAddr], local: SocketAddr) -> SocketAddr {
+    let local = canonical(local);
+    if let Some(listener) = listeners.iter().find(|l| l.ip() == local.ip()) {
+        return *listener;
+    }
+    if let Some(listener) = listeners
+        .iter()
+        .find(|l| l.ip().is_unspecified() && (l.is_ipv6() || local.is_ipv4()))
+    {
+        return SocketAddr::new(local.ip(), listener.port());
+    }
+    listeners
+        .iter()
+        .find(|l| l.is_ipv4() == local.is_ipv4())
+        .or(listeners.first())
+        .copied()
+        .unwrap_or(local)
 }
+
 /// Data that is shared between all peers in the chat server.
 ///
-/// This is the set of `Tx` handles for all connected clients. Whenever a
-/// message is received from a client, it is broadcasted to all peers by
-/// iterating over the `peers` entries and sending a copy of the message on each
-/// `Tx`.
+/// This is the set of `PeerSender` handles for all connected clients and the routing table.
+/// It is owned by the state task, everyone else changes it through a `StateHandle`.
 pub struct Shared {
-    pub peers: HashMap<SocketAddr, Tx>, //maybe refactor to maybe channels or streams?
-    pub console_input_sender: Tx,
+    pub peers: HashMap<SocketAddr, PeerSender>, //maybe refactor to maybe channels or streams?
+    pub event_sender: broadcast::Sender<NodeEvent>,
     pub nickname: String,
-    pub listener_addr: String,
-    //                         target    |  next,hop_count,ttl
+    /// Addresses of all our listeners, the first one is the primary address of the node
+    pub listener_addrs: Vec<SocketAddr>,
+    pub config: NodeConfig,
+    //                         target    |  next,hop_count,updated
     pub routing_table: HashMap<SocketAddr, RoutingTableEntry>,
+    /// Fragments of messages for us that haven't completed yet
+    pub reassembly: Reassembly,
+    /// Messages we sent that are waiting for their ACK
+    pub outbox: Outbox,
+    /// Messages we received recently, to drop retransmitted copies
+    pub inbox: Inbox,
+    /// Broadcasts we passed on recently, by origin and id
+    pub broadcasts: Inbox,
+    /// File transfers that are running or offered to us
+    pub transfers: Transfers,
+    /// Our long-term keypair
+    pub identity: Arc<Identity>,
+    /// Keys of other nodes, the first one we learn is pinned
+    pub keys: HashMap<SocketAddr, NodeKey>,
+    /// Signing keys of our neighbours, the first one we learn is pinned
+    pub signers: HashMap<SocketAddr, NodeKey>,
+    /// Sequence number of the last routing update we accepted, by source and connection.
+    /// Only the updates of one connection arrive in order, two to the same node interleave.
+    pub sequence_numbers: HashMap<(SocketAddr, SocketAddr), u64>,
+    /// Rooms we are a member of
+    pub rooms: BTreeSet<String>,
+    /// Rooms of the other nodes, as announced along their routes
+    pub members: HashMap<SocketAddr, BTreeSet<String>>,
+    /// Nicknames of the other nodes, announced like their rooms
+    pub nicknames: HashMap<SocketAddr, String>,
+    /// Our presence, announced in our routing updates
+    pub presence: Presence,
+    /// Presences of the other nodes, announced like their nicknames
+    pub presences: HashMap<SocketAddr, Presence>,
+    /// Typing indicators we sent recently
+    pub typing: TypingLimiter,
+    /// Round-trip times to our neighbours, measured with the SCC/SCCR exchange
+    pub rtts: HashMap<SocketAddr, RttEstimate>,
 }
 
 impl Shared {
     /// Create a new, empty, instance of `Shared`.
-    pub fn new(console_input_sender: Tx) -> Self {
+    pub fn new(event_sender: broadcast::Sender<NodeEvent>, config: NodeConfig) -> Self {
         Shared {
             peers: HashMap::new(),
             routing_table: HashMap::new(),
-            nickname: "Morganite".to_string(), //default nickname "Morganite
-            listener_addr: "127.0.0.1:6142".to_string(),
-            console_input_sender,
+            nickname: config.nickname.clone(),
+            listener_addrs: vec![SocketAddr::from(([127, 0, 0, 1], 6142))],
+            reassembly: Reassembly::new(config.reassembly_timeout()),
+            outbox: Outbox::new(config.ack_timeout(), config.max_retransmits),
+            inbox: Inbox::new(config.retransmit_window()),
+            broadcasts: Inbox::new(BROADCAST_WINDOW),
+            transfers: Transfers::default(),
+            identity: Arc::new(Identity::generate()),
+            keys: HashMap::new(),
+            signers: HashMap::new(),
+            sequence_numbers: HashMap::new(),
+            rooms: BTreeSet::new(),
+            members: HashMap::new(),
+            nicknames: HashMap::new(),
+            presence: Presence::default(),
+            presences: HashMap::new(),
+            typing: TypingLimiter::default(),
+            rtts: HashMap::new(),
+            config,
+            event_sender,
         }
     }
 
-    /// Send a `LineCodec` encoded message to every peer, except
-    /// for the sender.
-    pub async fn broadcast(&mut self, sender: SocketAddr, event: &ChannelEvent) {
-        for peer in self.peers.iter_mut() {
-            if *peer.0 != sender {
-                let _ = peer.1.send(event.clone());
+    /// Publish an event to everyone subscribed to this node.
+    /// Having no subscribers is fine, the event is simply dropped.
+    pub fn emit(&self, event: NodeEvent) {
+        let _ = self.event_sender.send(event);
+    }
+
+    /// Remember the key of `addr` unless we already pinned a different one.
+    pub fn pin_key(&mut self, addr: SocketAddr, key: NodeKey) -> Result<(), Error> {
+        match self.keys.get(&addr) {
+            Some(pinned) if *pinned != key => {
+                let msg = format!(
+                    "Key of {} changed from {} to {}, keeping the pinned one",
+                    addr,
+                    pinned.fingerprint(),
+                    key.fingerprint()
+                );
+                tracing::warn!("{}", msg);
+                self.emit(NodeEvent::Log(msg));
+                Err(Error::KeyMismatch(addr))
+            }
+            Some(_) => Ok(()),
+            None => {
+                tracing::info!("Pinned key {} of {}", key.fingerprint(), addr);
+                self.keys.insert(addr, key);
+                Ok(())
             }
         }
     }
-    /// Return all entries in the routing table besides the ones with target as destination or next as a vector
-    pub async fn get_routing_table(
+
+    /// Check the signature of a routing update that came in on the connection to `sender`
+    /// against the policy, the pinned key and the sequence number of the last update of its
+    /// source on that connection.
+    ///
+    /// Once a source has a pinned key its updates have to be signed, otherwise a neighbour
+    /// could strip the signature of a forged one.
+    ///
+    /// Returns whether the update is signed by the pinned key of its source, only then its
+    /// source is authentic. With the policy off every update is taken at its word.
+    pub fn verify_update(
         &mut self,
-        target: SocketAddr,
-        local: SocketAddr,
-    ) -> Vec<RoutingEntry> {
-        let mut routing_entries: Vec<RoutingEntry> = Vec::new(); //entry is the direct connection or entry is reachable through the target
+        packet: &RoutingPacket,
+        routing_type: RoutingType,
+        sender: SocketAddr,
+    ) -> Result<bool, Error> {
+        let policy = self.config.route_signatures;
+        if policy == SignaturePolicy::Off {
+            return Ok(true);
+        }
+        let Some(signed) = packet.verify(routing_type)? else {
+            return match policy {
+                SignaturePolicy::Strict => Err(Error::Unsigned),
+                _ if self.signers.contains_key(&packet.header.source) => Err(Error::Unsigned),
+                _ => Ok(false),
+            };
+        };
+
+        let source = packet.header.source;
+        if let Some(pinned) = self.signers.get(&source) {
+            if *pinned != signed.key {
+                return Err(Error::KeyMismatch(source));
+            }
+        }
+        if let Some(&last) = self.sequence_numbers.get(&(source, sender)) {
+            if signed.seq <= last {
+                return Err(Error::Replayed {
+                    seq: signed.seq,
+                    last,
+                });
+            }
+        }
+        self.signers.entry(source).or_insert(signed.key);
+        self.sequence_numbers.insert((source, sender), signed.seq);
+        Ok(true)
+    }
+
+    /// Return all entries in the routing table besides the one of target itself as a vector,
+    /// the routes through target are left out or poisoned depending on the split horizon mode
+    pub fn get_routing_table(&self, target: SocketAddr, local: SocketAddr) -> Vec<RoutingEntry> {
+        let mut routing_entries: Vec<RoutingEntry> = Vec::new();
         for entry in self
             .routing_table
             .iter()
-            .filter(|(dest, rt_entry)| **dest != target && rt_entry.next != target)
+            .filter(|(dest, _)| **dest != target)
         {
+            let hop_count = match self.config.split_horizon {
+                // entry is reachable through the target
+                SplitHorizon::Simple if entry.1.next == target => continue,
+                SplitHorizon::PoisonReverse if entry.1.next == target => {
+                    self.config.unreachable_metric
+                }
+                _ => entry.1.hop_count,
+            };
             routing_entries.push(RoutingEntry {
-                target_ip: entry.0.ip().to_string(),
-                target_port: entry.0.port(),
-                next_ip: local.ip().to_string(), //our address since we only add connections through us to the update
-                next_port: local.port(),         //replace with const we set in main?
-                hop_count: entry.1.hop_count
+                target: *entry.0,
+                next: local, //our address since we only add connections through us to the update
+                hop_count,
+                public_key: self.keys.get(entry.0).copied(),
+                rooms: self
+                    .members
+                    .get(entry.0)
+                    .map(|rooms| rooms.iter().cloned().collect())
+                    .unwrap_or_default(),
+                nickname: self.nicknames.get(entry.0).cloned(),
+                presence: self.presences.get(entry.0).cloned(),
             });
         }
+        // In a fixed order, so two advertisements can be compared
+        routing_entries.sort_by_key(|entry| entry.target);
         routing_entries
     }
-    /// updates the routing table with the given information
-    pub async fn update_routing_table(&mut self, update: Vec<RoutingEntry>, sender: SocketAddr) {
-        for new_entry in update.iter() {
-            // get target
-            let target = (new_entry.target_ip.clone() + ":" + &new_entry.target_port.to_string())
-                .parse::<SocketAddr>()
-                .unwrap();
+    /// Remember the rooms `addr` is a member of, replacing what we knew before
+    pub fn set_rooms(&mut self, addr: SocketAddr, rooms: Vec<String>) {
+        if rooms.is_empty() {
+            self.members.remove(&addr);
+        } else {
+            self.members.insert(addr, rooms.into_iter().collect());
+        }
+    }
 
-            let next_hop = (new_entry.next_ip.clone() + ":" + &new_entry.next_port.to_string())
-                .parse::<SocketAddr>()
-                .unwrap();
+    /// Remember the nickname of `addr`, flagging it if another node goes by it as well
+    pub fn set_nickname_of(&mut self, addr: SocketAddr, nickname: Option<String>) {
+        let Some(nickname) = nickname else {
+            self.nicknames.remove(&addr);
+            return;
+        };
+        if self.nicknames.get(&addr) == Some(&nickname) {
+            return;
+        }
+        let taken = self
+            .nicknames
+            .iter()
+            .any(|(other, name)| *other != addr && *name == nickname);
+        if taken || nickname == self.nickname {
+            let msg = format!("{} is not the only node called {}", addr, nickname);
+            tracing::warn!("{}", msg);
+            self.emit(NodeEvent::Log(msg));
+        }
+        self.nicknames.insert(addr, nickname);
+    }
+
+    /// Remember the presence of `addr`, replacing what we knew before
+    pub fn set_presence_of(&mut self, addr: SocketAddr, presence: Option<Presence>) {
+        match presence {
+            Some(presence) => self.presences.insert(addr, presence),
+            None => self.presences.remove(&addr),
+        };
+    }
+
+    /// Poison the routes that weren't confirmed within the route timeout and remove the
+    /// ones that stayed unreachable for the garbage collection interval, unless they are pinned.
+    pub fn expire_routes(&mut self, now: Instant) {
+        let unreachable = self.config.unreachable_metric;
+        let timeout = self.config.route_timeout();
+        for (target, entry) in self.routing_table.iter_mut() {
+            if entry.hop_count < unreachable && now.duration_since(entry.updated) >= timeout {
+                tracing::info!("Route to {} via {} expired", target, entry.next);
+                entry.poison(unreachable, now);
+            }
+        }
+
+        let gc = self.config.route_gc();
+        let collected: Vec<SocketAddr> = self
+            .routing_table
+            .iter()
+            .filter(|(_, entry)| {
+                entry.hop_count >= unreachable
+                    && !entry.dont_expire
+                    && now.duration_since(entry.updated) >= gc
+            })
+            .map(|(target, _)| *target)
+            .collect();
+        for target in collected {
+            tracing::info!("Removing the route to {}, it stayed unreachable", target);
+            self.routing_table.remove(&target);
+            self.members.remove(&target);
+            self.nicknames.remove(&target);
+            self.presences.remove(&target);
+        }
+    }
+
+    /// updates the routing table with the given information
+    ///
+    /// A route through `sender` always takes the metric it advertises, even if it got worse,
+    /// a route through another neighbour is only replaced by a strictly shorter one.
+    pub fn update_routing_table(&mut self, update: Vec<RoutingEntry>, sender: SocketAddr) {
+        let unreachable = self.config.unreachable_metric;
+        for new_entry in update {
+            let target = new_entry.target;
+            let next_hop = new_entry.next;
 
             // Check whether we are the target or the next hop
-            if target == self.listener_addr.parse::<SocketAddr>().unwrap() || next_hop == self.listener_addr.parse::<SocketAddr>().unwrap(){
+            if is_own_addr(&self.listener_addrs, target)
+                || is_own_addr(&self.listener_addrs, next_hop)
+            {
                 // Inform the console about the bad packet
-                let _ = self
-                    .console_input_sender
-                    .send(channel_events::ChannelEvent::LogToTerminal(
-                        "Received packet with own address as target".to_string(),
-                    ));
+                self.emit(NodeEvent::Log(
+                    "Received packet with own address as target".to_string(),
+                ));
                 tracing::warn!("Received packet with own address as target");
                 continue;
             }
 
-            match self.routing_table.get(&target) {
-                // if in Routing Table
-                Some(old_entry) => {
-                    // compare hop_count to target in Routing Table and in update
-                    let hop_count = if new_entry.hop_count == 32 {
-                        new_entry.hop_count
+            if let Some(key) = new_entry.public_key {
+                let _ = self.pin_key(target, key);
+            }
+
+            // The sender is one hop further away from the target
+            let hop_count = if new_entry.hop_count >= unreachable {
+                unreachable
+            } else {
+                new_entry.hop_count + 1
+            };
+            match self.routing_table.get_mut(&target) {
+                // our next hop knows best, even if the route got worse or broke behind it
+                Some(old_entry) if old_entry.next == sender => {
+                    if hop_count >= unreachable {
+                        // Announcing a dead route again doesn't keep it from being collected
+                        old_entry.poison(unreachable, Instant::now());
                     } else {
-                        new_entry.hop_count + 1
-                    };
-                    if hop_count <= old_entry.hop_count {
-                        let next = (sender.ip().to_string() + ":" + &sender.port().to_string())
-                            .parse::<SocketAddr>()
-                            .unwrap();
-                        // if update is shorter: replace/change entry in Routing Table
-                        self.routing_table.insert(
-                            target,
-                            RoutingTableEntry {
-                                next,
-                                hop_count,
-                                ttl: true,
-                            },
-                        );
+                        old_entry.hop_count = hop_count;
+                        old_entry.updated = Instant::now();
+                    }
+                }
+                // another neighbour only wins with a shorter route
+                Some(old_entry) => {
+                    if hop_count < old_entry.hop_count {
+                        let mut entry = RoutingTableEntry::new(sender, hop_count);
+                        entry.dont_expire = old_entry.dont_expire;
+                        *old_entry = entry;
                     }
                 }
-                // if not in Routing Table: create new entry to target through source
+                // if not in Routing Table: create new entry to target through the sender
                 None => {
-                    let hop_count = new_entry.hop_count;
-                    let next = (new_entry.next_ip.clone() + ":" + &new_entry.next_port.to_string())
-                        .parse::<SocketAddr>()
-                        .unwrap();
-                    self.routing_table.insert(
-                        target,
-                        RoutingTableEntry {
-                            next,
-                            hop_count,
-                            ttl: true,
-                        },
-                    );
+                    // Nothing to learn about a target nobody can reach
+                    if hop_count >= unreachable {
+                        continue;
+                    }
+                    self.routing_table
+                        .insert(target, RoutingTableEntry::new(sender, hop_count));
+                }
+            }
+
+            // Only the neighbour we route through knows the current rooms, nickname and presence of the target
+            if let Some(entry) = self.routing_table.get(&target) {
+                if entry.next == sender {
+                    self.set_rooms(target, new_entry.rooms);
+                    self.set_nickname_of(target, new_entry.nickname);
+                    self.set_presence_of(target, new_entry.presence);
                 }
             }
         }
     }
 }
-#[tokio::test]
-pub async fn test_get_routing_table() {
+#[test]
+pub fn test_get_routing_table() {
     let target = "127.0.0.1:6666".parse::<SocketAddr>().unwrap();
     let local = "127.0.0.1:6142".parse::<SocketAddr>().unwrap();
-    let (fake_tx, _) = mpsc::unbounded_channel();
-    let mut shared = Shared::new(fake_tx);
+    let (fake_tx, _) = broadcast::channel(EVENT_CAPACITY);
+    let mut shared = Shared::new(fake_tx, NodeConfig::default());
     shared.routing_table.insert(
         "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
-        RoutingTableEntry {
-            next: "127.0.0.1:12346".parse::<SocketAddr>().unwrap(),
-            hop_count: 2,
-            ttl: true,
-        },
+        RoutingTableEntry::new("127.0.0.1:12346".parse::<SocketAddr>().unwrap(), 2),
     );
     shared.routing_table.insert(
         "127.0.0.1:6666".parse::<SocketAddr>().unwrap(),
-        RoutingTableEntry {
-            next: "127.0.0.1:1236".parse::<SocketAddr>().unwrap(),
-            hop_count: 2,
-            ttl: true,
-        },
+        RoutingTableEntry::new("127.0.0.1:1236".parse::<SocketAddr>().unwrap(), 2),
     );
     shared.routing_table.insert(
         "127.0.0.1:1235".parse::<SocketAddr>().unwrap(),
-        RoutingTableEntry {
-            next: "127.0.0.1:6666".parse::<SocketAddr>().unwrap(),
-            hop_count: 2,
-            ttl: true,
-        },
+        RoutingTableEntry::new("127.0.0.1:6666".parse::<SocketAddr>().unwrap(), 2),
     );
     shared.routing_table.insert(
         "127.0.0.1:12344".parse::<SocketAddr>().unwrap(),
-        RoutingTableEntry {
-            next: "127.0.0.1:6666".parse::<SocketAddr>().unwrap(),
-            hop_count: 2,
-            ttl: true,
-        },
+        RoutingTableEntry::new("127.0.0.1:6666".parse::<SocketAddr>().unwrap(), 2),
     );
 
     assert_eq!(
         vec![RoutingEntry {
-            target_ip: "127.0.0.1".to_string(),
-            target_port: 12345,
-            next_ip: "127.0.0.1".to_string(),
-            next_port: 6142,
-            hop_count: 3
+            target: "127.0.0.1:12345".parse().unwrap(),
+            next: "127.0.0.1:6142".parse().unwrap(),
+            hop_count: 2,
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
         }],
-        shared.get_routing_table(target, local).await
+        shared.get_routing_table(target, local)
     );
 
     let update = vec![
         RoutingEntry {
-            target_ip: "127.0.0.1".to_string(),
-            target_port: 11111,
-            next_ip: "127.0.0.1".to_string(),
-            next_port: 12345,
+            target: "127.0.0.1:11111".parse().unwrap(),
+            next: "127.0.0.1:12345".parse().unwrap(),
             hop_count: 3,
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
         },
         RoutingEntry {
-            target_ip: "127.0.0.1".to_string(),
-            target_port: 11112,
-            next_ip: "127.0.0.1".to_string(),
-            next_port: 12345,
+            target: "127.0.0.1:11112".parse().unwrap(),
+            next: "127.0.0.1:12345".parse().unwrap(),
             hop_count: 4,
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
         },
         RoutingEntry {
-            target_ip: "127.0.0.1".to_string(),
-            target_port: 11113,
-            next_ip: "127.0.0.1".to_string(),
-            next_port: 12345,
+            target: "127.0.0.1:11113".parse().unwrap(),
+            next: "127.0.0.1:12345".parse().unwrap(),
             hop_count: 5,
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
         },
     ];
-    shared.update_routing_table(update, target).await;
+    shared.update_routing_table(update, target);
     //vergleichsmap
     let mut rt: HashMap<SocketAddr, RoutingTableEntry> = HashMap::new();
     rt.insert(
         "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
-        RoutingTableEntry {
-            next: "127.0.0.1:12346".parse::<SocketAddr>().unwrap(),
-            hop_count: 2,
-            ttl: true,
-        },
+        RoutingTableEntry::new("127.0.0.1:12346".parse::<SocketAddr>().unwrap(), 2),
     );
     rt.insert(
         "127.0.0.1:6666".parse::<SocketAddr>().unwrap(),
-        RoutingTableEntry {
-            next: "127.0.0.1:1236".parse::<SocketAddr>().unwrap(),
-            hop_count: 2,
-            ttl: true,
-        },
+        RoutingTableEntry::new("127.0.0.1:1236".parse::<SocketAddr>().unwrap(), 2),
     );
     rt.insert(
         "127.0.0.1:1235".parse::<SocketAddr>().unwrap(),
-        RoutingTableEntry {
-            next: "127.0.0.1:6666".parse::<SocketAddr>().unwrap(),
-            hop_count: 2,
-            ttl: true,
-        },
+        RoutingTableEntry::new("127.0.0.1:6666".parse::<SocketAddr>().unwrap(), 2),
     );
     rt.insert(
         "127.0.0.1:12344".parse::<SocketAddr>().unwrap(),
-        RoutingTableEntry {
-            next: "127.0.0.1:6666".parse::<SocketAddr>().unwrap(),
-            hop_count: 2,
-            ttl: true,
-        },
+        RoutingTableEntry::new("127.0.0.1:6666".parse::<SocketAddr>().unwrap(), 2),
     );
     rt.insert(
         "127.0.0.1:11111".parse::<SocketAddr>().unwrap(),
-        RoutingTableEntry {
-            next: "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
-            hop_count: 3,
-            ttl: true,
-        },
+        RoutingTableEntry::new("127.0.0.1:6666".parse::<SocketAddr>().unwrap(), 4),
     );
     rt.insert(
         "127.0.0.1:11112".parse::<SocketAddr>().unwrap(),
-        RoutingTableEntry {
-            next: "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
-            hop_count: 4,
-            ttl: true,
-        },
+        RoutingTableEntry::new("127.0.0.1:6666".parse::<SocketAddr>().unwrap(), 5),
     );
     rt.insert(
         "127.0.0.1:11113".parse::<SocketAddr>().unwrap(),
-        RoutingTableEntry {
-            next: "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
-            hop_count: 5,
-            ttl: true,
+        RoutingTableEntry::new("127.0.0.1:6666".parse::<SocketAddr>().unwrap(), 6),
+    );
+
+    // When the routes were confirmed doesn't matter here
+    let routes = |table: &HashMap<SocketAddr, RoutingTableEntry>| {
+        table
+            .iter()
+            .map(|(target, entry)| (*target, (entry.next, entry.hop_count)))
+            .collect::<HashMap<_, _>>()
+    };
+    assert_eq!(routes(&shared.routing_table), routes(&rt));
+}
+
+#[test]
+pub fn test_expire_routes() {
+    let (fake_tx, _) = broadcast::channel(EVENT_CAPACITY);
+    let mut shared = Shared::new(fake_tx, NodeConfig::default());
+    let unreachable = shared.config.unreachable_metric;
+    let neighbour = "127.0.0.1:6143".parse::<SocketAddr>().unwrap();
+    let pinned = "127.0.0.1:6144".parse::<SocketAddr>().unwrap();
+    let far = "127.0.0.1:6145".parse::<SocketAddr>().unwrap();
+    shared
+        .routing_table
+        .insert(neighbour, RoutingTableEntry::new(neighbour, 1));
+    let mut entry = RoutingTableEntry::new(pinned, 1);
+    entry.dont_expire = true;
+    shared.routing_table.insert(pinned, entry);
+    shared
+        .routing_table
+        .insert(far, RoutingTableEntry::new(neighbour, 2));
+    shared.nicknames.insert(far, "carol".to_string());
+    let start = Instant::now();
+
+    // Confirmed routes stay as they are
+    shared.expire_routes(start);
+    assert!(shared
+        .routing_table
+        .values()
+        .all(|e| e.hop_count < unreachable));
+
+    // Nobody confirmed them in time
+    let timed_out = start + shared.config.route_timeout();
+    shared.expire_routes(timed_out);
+    assert_eq!(shared.routing_table.len(), 3);
+    assert!(shared
+        .routing_table
+        .values()
+        .all(|e| e.hop_count == unreachable && e.updated == timed_out));
+
+    // Staying unreachable doesn't count as being confirmed
+    shared.update_routing_table(
+        vec![RoutingEntry {
+            target: far,
+            next: neighbour,
+            hop_count: unreachable,
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
+        }],
+        neighbour,
+    );
+    assert_eq!(shared.routing_table[&far].updated, timed_out);
+
+    // Only the pinned peer survives the garbage collection
+    shared.expire_routes(timed_out + shared.config.route_gc());
+    assert_eq!(
+        shared.routing_table.keys().collect::<Vec<_>>(),
+        vec![&pinned]
+    );
+    assert!(shared.nicknames.is_empty());
+}
+
+#[test]
+pub fn test_bellman_ford() {
+    let a = "127.0.0.1:6143".parse::<SocketAddr>().unwrap();
+    let b = "127.0.0.1:6144".parse::<SocketAddr>().unwrap();
+    let target = "127.0.0.1:6666".parse::<SocketAddr>().unwrap();
+    let advertised = |hop_count: i32| RoutingEntry {
+        target,
+        // what the sender calls itself, the route goes through whoever sent it
+        next: "127.0.0.1:9999".parse().unwrap(),
+        hop_count,
+        public_key: None,
+        rooms: Vec::new(),
+        nickname: None,
+        presence: None,
+    };
+
+    // route before, sender, advertised hop count, route after
+    let table = [
+        ("learned", None, a, 2, Some((a, 3))),
+        ("unknown and unreachable", None, a, 32, None),
+        ("saturates", None, a, 31, None),
+        ("shorter elsewhere", Some((a, 5)), b, 2, Some((b, 3))),
+        ("as long elsewhere", Some((a, 3)), b, 2, Some((a, 3))),
+        ("longer elsewhere", Some((a, 3)), b, 4, Some((a, 3))),
+        ("worse at next hop", Some((a, 3)), a, 6, Some((a, 7))),
+        ("better at next hop", Some((a, 7)), a, 1, Some((a, 2))),
+        (
+            "link behind next hop failed",
+            Some((a, 3)),
+            a,
+            32,
+            Some((a, 32)),
+        ),
+        ("dead elsewhere", Some((a, 3)), b, 32, Some((a, 3))),
+        ("recovered elsewhere", Some((a, 32)), b, 4, Some((b, 5))),
+    ];
+    for (case, before, sender, hop_count, after) in table {
+        let (fake_tx, _) = broadcast::channel(EVENT_CAPACITY);
+        let mut shared = Shared::new(fake_tx, NodeConfig::default());
+        if let Some((next, hop_count)) = before {
+            shared
+                .routing_table
+                .insert(target, RoutingTableEntry::new(next, hop_count));
+        }
+        shared.update_routing_table(vec![advertised(hop_count)], sender);
+        let route = shared
+            .routing_table
+            .get(&target)
+            .map(|entry| (entry.next, entry.hop_count));
+        assert_eq!(route, after, "{}", case);
+    }
+}
+
+#[test]
+pub fn test_link_failure_converges() {
+    // a - b - c, a also reaches c through d on a longer path
+    let a = "127.0.0.1:6143".parse::<SocketAddr>().unwrap();
+    let b = "127.0.0.1:6144".parse::<SocketAddr>().unwrap();
+    let c = "127.0.0.1:6145".parse::<SocketAddr>().unwrap();
+    let d = "127.0.0.1:6146".parse::<SocketAddr>().unwrap();
+    let node = |addr: SocketAddr, routes: &[(SocketAddr, SocketAddr, i32)]| {
+        let (fake_tx, _) = broadcast::channel(EVENT_CAPACITY);
+        let mut shared = Shared::new(fake_tx, NodeConfig::default());
+        shared.listener_addrs = vec![addr];
+        for (target, next, hop_count) in routes {
+            shared
+                .routing_table
+                .insert(*target, RoutingTableEntry::new(*next, *hop_count));
+        }
+        shared
+    };
+    let mut at_a = node(a, &[(b, b, 1), (d, d, 1), (c, b, 2)]);
+    let mut at_b = node(b, &[(a, a, 1), (c, c, 1), (d, a, 2)]);
+
+    // The link between b and c breaks, b poisons its route and tells a
+    at_b.routing_table
+        .get_mut(&c)
+        .unwrap()
+        .poison(32, Instant::now());
+    at_a.update_routing_table(at_b.get_routing_table(a, b), b);
+    assert_eq!(at_a.routing_table[&c].hop_count, 32);
+
+    // d still reaches c and a switches over on its next update
+    let from_d = node(d, &[(a, a, 1), (c, c, 1)]);
+    at_a.update_routing_table(from_d.get_routing_table(a, d), d);
+    assert_eq!(at_a.routing_table[&c].next, d);
+    assert_eq!(at_a.routing_table[&c].hop_count, 2);
+
+    // and b learns the new path from a instead of counting to infinity
+    at_b.update_routing_table(at_a.get_routing_table(b, a), a);
+    assert_eq!(at_b.routing_table[&c].next, a);
+    assert_eq!(at_b.routing_table[&c].hop_count, 3);
+}
+
+#[test]
+pub fn test_split_horizon_modes() {
+    let local = "127.0.0.1:6142".parse::<SocketAddr>().unwrap();
+    let neighbour = "127.0.0.1:6143".parse::<SocketAddr>().unwrap();
+    let other = "127.0.0.1:6144".parse::<SocketAddr>().unwrap();
+    let behind_neighbour = "127.0.0.1:6145".parse::<SocketAddr>().unwrap();
+    let behind_other = "127.0.0.1:6146".parse::<SocketAddr>().unwrap();
+    let advertised = |split_horizon: SplitHorizon| {
+        let (fake_tx, _) = broadcast::channel(EVENT_CAPACITY);
+        let mut shared = Shared::new(
+            fake_tx,
+            NodeConfig {
+                split_horizon,
+                ..NodeConfig::default()
+            },
+        );
+        shared
+            .routing_table
+            .insert(neighbour, RoutingTableEntry::new(neighbour, 1));
+        shared
+            .routing_table
+            .insert(other, RoutingTableEntry::new(other, 1));
+        shared
+            .routing_table
+            .insert(behind_neighbour, RoutingTableEntry::new(neighbour, 2));
+        shared
+            .routing_table
+            .insert(behind_other, RoutingTableEntry::new(other, 3));
+        let mut entries = shared
+            .get_routing_table(neighbour, local)
+            .into_iter()
+            .map(|entry| {
+                assert_eq!(entry.next, local);
+                (entry.target, entry.hop_count)
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    };
+
+    // The neighbour never hears about itself
+    let table = [
+        (
+            SplitHorizon::Off,
+            vec![(other, 1), (behind_neighbour, 2), (behind_other, 3)],
+        ),
+        (SplitHorizon::Simple, vec![(other, 1), (behind_other, 3)]),
+        (
+            SplitHorizon::PoisonReverse,
+            vec![(other, 1), (behind_neighbour, 32), (behind_other, 3)],
+        ),
+    ];
+    for (mode, expected) in table {
+        assert_eq!(advertised(mode), expected, "{:?}", mode);
+    }
+    assert_eq!("poison_reverse".parse(), Ok(SplitHorizon::PoisonReverse));
+    assert!("poison".parse::<SplitHorizon>().is_err());
+}
+
+#[test]
+pub fn test_own_addr() {
+    let v4 = "127.0.0.1:6142".parse::<SocketAddr>().unwrap();
+    let v6 = "[::1]:6143".parse::<SocketAddr>().unwrap();
+    let both = [v4, v6];
+    assert_eq!(own_addr(&both, "127.0.0.1:50000".parse().unwrap()), v4);
+    assert_eq!(own_addr(&both, "[::1]:50000".parse().unwrap()), v6);
+    assert_eq!(
+        own_addr(&both, "[::ffff:127.0.0.1]:50000".parse().unwrap()),
+        v4
+    );
+    assert!(is_own_addr(&both, v6));
+    assert!(!is_own_addr(&both, "[::1]:6142".parse().unwrap()));
+
+    // Wildcard listeners answer on the address of the connection
+    let wildcard = ["[::]:6142".parse::<SocketAddr>().unwrap()];
+    assert_eq!(
+        own_addr(&wildcard, "[::ffff:10.0.0.1]:50000".parse().unwrap()),
+        "10.0.0.1:6142".parse().unwrap()
+    );
+    assert_eq!(
+        own_addr(&wildcard, "[fe80::1]:50000".parse().unwrap()),
+        "[fe80::1]:6142".parse().unwrap()
+    );
+    assert!(is_own_addr(&wildcard, "10.0.0.1:6142".parse().unwrap()));
+    assert!(!is_own_addr(
+        &["0.0.0.0:6142".parse().unwrap()],
+        "[::1]:6142".parse().unwrap()
+    ));
+}
+
+#[test]
+pub fn test_pin_key() {
+    let addr = "127.0.0.1:6143".parse::<SocketAddr>().unwrap();
+    let (fake_tx, _) = broadcast::channel(EVENT_CAPACITY);
+    let mut shared = Shared::new(fake_tx, NodeConfig::default());
+    let key = Identity::generate().public_key();
+    shared.pin_key(addr, key).unwrap();
+    shared.pin_key(addr, key).unwrap();
+
+    // A different key for the same node is refused, the first one stays pinned
+    assert!(matches!(
+        shared.pin_key(addr, Identity::generate().public_key()),
+        Err(Error::KeyMismatch(_))
+    ));
+    assert_eq!(shared.keys[&addr], key);
+}
+
+#[test]
+pub fn test_verify_update() {
+    let identity = Identity::generate();
+    let packet = |identity: &Identity| {
+        let mut packet = RoutingPacket {
+            header: crate::protocol::shared_header::SharedHeader {
+                source: "127.0.0.1:6143".parse().unwrap(),
+                dest: "127.0.0.1:6142".parse().unwrap(),
+                ttl: 16,
+            },
+            table: Some(Vec::new()),
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
+            signed: None,
+        };
+        packet.sign(RoutingType::STU, identity);
+        packet
+    };
+    let connection = "127.0.0.1:40000".parse::<SocketAddr>().unwrap();
+    let (fake_tx, _) = broadcast::channel(EVENT_CAPACITY);
+    let mut shared = Shared::new(fake_tx, NodeConfig::default());
+
+    let first = packet(&identity);
+    let second = packet(&identity);
+    assert!(shared
+        .verify_update(&second, RoutingType::STU, connection)
+        .unwrap());
+    // Older and repeated updates are replays
+    assert!(matches!(
+        shared.verify_update(&first, RoutingType::STU, connection),
+        Err(Error::Replayed { .. })
+    ));
+    assert!(matches!(
+        shared.verify_update(&second, RoutingType::STU, connection),
+        Err(Error::Replayed { .. })
+    ));
+    // Nobody else can sign for a neighbour once its key is pinned
+    assert!(matches!(
+        shared.verify_update(&packet(&Identity::generate()), RoutingType::STU, connection),
+        Err(Error::KeyMismatch(_))
+    ));
+    shared
+        .verify_update(&packet(&identity), RoutingType::STU, connection)
+        .unwrap();
+
+    let unsigned = RoutingPacket {
+        signed: None,
+        ..packet(&identity)
+    };
+    // Its key is pinned, so it can't fall back to unsigned updates
+    assert!(matches!(
+        shared.verify_update(&unsigned, RoutingType::STU, connection),
+        Err(Error::Unsigned)
+    ));
+    // Other sources may until they sign one
+    let stranger = RoutingPacket {
+        header: crate::protocol::shared_header::SharedHeader {
+            source: "127.0.0.1:6144".parse().unwrap(),
+            ..unsigned.header
         },
+        ..unsigned
+    };
+    // but they aren't authentic
+    assert!(!shared
+        .verify_update(&stranger, RoutingType::STU, connection)
+        .unwrap());
+    shared.config.route_signatures = SignaturePolicy::Strict;
+    assert!(matches!(
+        shared.verify_update(&stranger, RoutingType::STU, connection),
+        Err(Error::Unsigned)
+    ));
+
+    // Updates of two connections to the same node interleave, each is only ordered in itself
+    let other = "127.0.0.1:40001".parse::<SocketAddr>().unwrap();
+    let (a1, b1, a2, b2) = (
+        packet(&identity),
+        packet(&identity),
+        packet(&identity),
+        packet(&identity),
+    );
+    shared.verify_update(&b1, RoutingType::STU, other).unwrap();
+    shared
+        .verify_update(&a1, RoutingType::STU, connection)
+        .unwrap();
+    shared
+        .verify_update(&a2, RoutingType::STU, connection)
+        .unwrap();
+    shared.verify_update(&b2, RoutingType::STU, other).unwrap();
+    assert!(matches!(
+        shared.verify_update(&a2, RoutingType::STU, other),
+        Err(Error::Replayed { .. })
+    ));
+}
+
+#[test]
+pub fn test_room_membership() {
+    let local = "127.0.0.1:6142".parse::<SocketAddr>().unwrap();
+    let bob = "127.0.0.1:6143".parse::<SocketAddr>().unwrap();
+    let dave = "127.0.0.1:6145".parse::<SocketAddr>().unwrap();
+    let carol = "127.0.0.1:6144".parse::<SocketAddr>().unwrap();
+    let (fake_tx, _) = broadcast::channel(EVENT_CAPACITY);
+    let mut shared = Shared::new(fake_tx, NodeConfig::default());
+    for neighbour in [bob, dave] {
+        shared
+            .routing_table
+            .insert(neighbour, RoutingTableEntry::new(neighbour, 1));
+    }
+    let entry = |next, hop_count, rooms: &[&str]| RoutingEntry {
+        target: carol,
+        next,
+        hop_count,
+        public_key: None,
+        rooms: rooms.iter().map(|room| room.to_string()).collect(),
+        nickname: None,
+        presence: None,
+    };
+
+    shared.update_routing_table(vec![entry(bob, 1, &["#ops"])], bob);
+    assert!(shared.members[&carol].contains("#ops"));
+    // Only the neighbour on the route to carol is up to date
+    shared.update_routing_table(vec![entry(dave, 3, &["#dev"])], dave);
+    assert!(!shared.members[&carol].contains("#dev"));
+    let advertised = shared.get_routing_table(dave, local);
+    let advertised = advertised.iter().find(|entry| entry.target == carol);
+    assert_eq!(advertised.unwrap().rooms, vec!["#ops".to_string()]);
+
+    shared.update_routing_table(vec![entry(bob, 1, &[])], bob);
+    assert!(!shared.members.contains_key(&carol));
+}
+
+#[test]
+pub fn test_duplicate_nickname() {
+    let alice = "127.0.0.1:6143".parse::<SocketAddr>().unwrap();
+    let mallory = "127.0.0.1:6144".parse::<SocketAddr>().unwrap();
+    let (fake_tx, mut events) = broadcast::channel(EVENT_CAPACITY);
+    let mut shared = Shared::new(fake_tx, NodeConfig::default());
+
+    shared.set_nickname_of(alice, Some("alice".to_string()));
+    assert!(events.try_recv().is_err());
+    shared.set_nickname_of(mallory, Some("alice".to_string()));
+    assert_eq!(
+        events.try_recv().unwrap(),
+        NodeEvent::Log("127.0.0.1:6144 is not the only node called alice".to_string())
     );
+    // Flagged once, not with every update
+    shared.set_nickname_of(mallory, Some("alice".to_string()));
+    assert!(events.try_recv().is_err());
 
-    assert_eq!(shared.routing_table, rt);
+    shared.set_nickname_of(mallory, None);
+    assert_eq!(shared.nicknames.len(), 1);
 }
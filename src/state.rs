@@ -0,0 +1,751 @@
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Notify};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::channel_events::NodeEvent;
+use crate::channel_events::{ChannelEvent, DeliveryStatus};
+use crate::config::NodeConfig;
+use crate::crypto::{Identity, NodeKey};
+use crate::delivery::Outgoing;
+use crate::error::Error;
+use crate::fragment::FragmentIds;
+use crate::presence::Presence;
+use crate::protocol::file_packet::{FileData, FilePacket};
+use crate::protocol::routed_packet::RoutedPacket;
+use crate::protocol::routing_packet::{RoutingEntry, RoutingPacket};
+use crate::protocol::RoutingType;
+use crate::queue::PeerSender;
+use crate::rtt::RttEstimate;
+use crate::shared::{RoutingTableEntry, Shared};
+
+/// Read-only copy of the node state, published after every change.
+///
+/// Forwarding and the heartbeat only ever look at the latest snapshot,
+/// so they never wait for the state task.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    pub peers: HashMap<SocketAddr, PeerSender>,
+    pub routing_table: HashMap<SocketAddr, RoutingTableEntry>,
+    pub nickname: String,
+    /// Pinned keys of other nodes
+    pub keys: HashMap<SocketAddr, NodeKey>,
+    /// Rooms we are a member of
+    pub rooms: BTreeSet<String>,
+    /// Rooms of the other nodes
+    pub members: HashMap<SocketAddr, BTreeSet<String>>,
+    /// Nicknames of the other nodes
+    pub nicknames: HashMap<SocketAddr, String>,
+    /// Our presence
+    pub presence: Presence,
+    /// Presences of the other nodes
+    pub presences: HashMap<SocketAddr, Presence>,
+    /// Round-trip times to our neighbours
+    pub rtts: HashMap<SocketAddr, RttEstimate>,
+}
+
+impl Snapshot {
+    /// Members of `room` we have a route to, we are not one of them
+    pub fn room_members(&self, room: &str, unreachable: i32) -> Vec<SocketAddr> {
+        self.members
+            .iter()
+            .filter(|(_, rooms)| rooms.contains(room))
+            .filter(|(addr, _)| {
+                self.routing_table
+                    .get(addr)
+                    .is_some_and(|entry| entry.hop_count < unreachable)
+            })
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Nodes going by `nickname` we have a route to, more than one if it's taken twice
+    pub fn lookup(&self, nickname: &str, unreachable: i32) -> Vec<SocketAddr> {
+        let mut found: Vec<SocketAddr> = self
+            .nicknames
+            .iter()
+            .filter(|(_, name)| *name == nickname)
+            .filter(|(addr, _)| {
+                self.routing_table
+                    .get(addr)
+                    .is_some_and(|entry| entry.hop_count < unreachable)
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+        found.sort();
+        found
+    }
+
+    /// The channel of the neighbour packets to `dest` have to be handed to
+    pub fn next_hop(
+        &self,
+        dest: SocketAddr,
+        local: SocketAddr,
+    ) -> Option<(SocketAddr, &PeerSender)> {
+        let entry = self.routing_table.get(&dest)?;
+        let next = if entry.next == local {
+            dest
+        } else {
+            entry.next
+        };
+        self.peers.get(&next).map(|tx| (next, tx))
+    }
+}
+
+/// Changes and queries handled by the state task, in the order they were sent.
+#[derive(Debug)]
+pub enum StateRequest {
+    AddPeer(SocketAddr, PeerSender, oneshot::Sender<()>),
+    /// Remove a disconnected peer and poison every route through it
+    RemovePeer(SocketAddr),
+    InsertRoute(SocketAddr, RoutingTableEntry),
+    UpdateRoutingTable(Vec<RoutingEntry>, SocketAddr, oneshot::Sender<()>),
+    /// Whether a routing update of a neighbour may be applied, judging by its signature:
+    /// (update, its type, the connection it came in on)
+    VerifyUpdate(
+        RoutingPacket,
+        RoutingType,
+        SocketAddr,
+        oneshot::Sender<Result<bool, Error>>,
+    ),
+    /// Routing table to advertise to a neighbour: (neighbour, local address of that connection)
+    Advertisement(SocketAddr, SocketAddr, oneshot::Sender<Vec<RoutingEntry>>),
+    /// A neighbour answered our SCC
+    MarkAlive(SocketAddr),
+    /// Time a neighbour took to answer our SCC
+    RecordRtt(SocketAddr, Duration),
+    /// Poison the routes that timed out and remove the ones unreachable for too long
+    ExpireRoutes,
+    /// Poison every route, we are leaving the network
+    PoisonAll(oneshot::Sender<()>),
+    SetNickname(String, oneshot::Sender<()>),
+    JoinRoom(String, oneshot::Sender<()>),
+    LeaveRoom(String, oneshot::Sender<()>),
+    /// The rooms a neighbour announced to be a member of
+    SetRooms(SocketAddr, Vec<String>),
+    /// The nickname a neighbour announced
+    SetNicknameOf(SocketAddr, Option<String>),
+    SetPresence(Presence, oneshot::Sender<()>),
+    /// The presence a neighbour announced
+    SetPresenceOf(SocketAddr, Option<Presence>),
+    /// Whether a typing indicator may be sent to a destination now
+    AllowTyping(SocketAddr, oneshot::Sender<bool>),
+    /// Pin the key of a node unless it has a different one already
+    PinKey(SocketAddr, NodeKey, oneshot::Sender<()>),
+    /// A fragment addressed to us, answered with the whole message once it's complete
+    Reassemble(RoutedPacket, oneshot::Sender<Option<RoutedPacket>>),
+    /// Wait for the ACK of a message: (destination, our address it was sent from, message, room),
+    /// answered with the id of the message
+    TrackMessage(
+        SocketAddr,
+        SocketAddr,
+        String,
+        Option<String>,
+        oneshot::Sender<u64>,
+    ),
+    /// The ACK of a message arrived: (source of the ACK, id)
+    Acknowledge(SocketAddr, u64),
+    /// Whether a message of (source, id) arrived for the first time
+    FirstDelivery(SocketAddr, u64, oneshot::Sender<bool>),
+    /// Id for a broadcast or file transfer of ours
+    NextId(oneshot::Sender<u64>),
+    /// Whether a broadcast of (origin, id) arrived for the first time
+    FirstBroadcast(SocketAddr, u64, oneshot::Sender<bool>),
+    /// Hand the packets of a transfer with (peer, id) to its task from now on
+    OpenTransfer(SocketAddr, u64, mpsc::UnboundedSender<FileData>),
+    CloseTransfer(SocketAddr, u64),
+    /// A file packet addressed to us
+    File(FilePacket),
+    /// Remove the offer of a file, to accept or decline it
+    TakeOffer(u64, oneshot::Sender<Option<FilePacket>>),
+    /// Messages to send again, the ones given up on are reported as failed
+    DueRetransmits(oneshot::Sender<Vec<(u64, Outgoing)>>),
+    /// Forget the ids of messages and broadcasts that can't arrive again anymore
+    ExpireDeliveries,
+}
+
+/// Handle to the state task, cheap to clone.
+#[derive(Clone, Debug)]
+pub struct StateHandle {
+    requests: mpsc::UnboundedSender<StateRequest>,
+    snapshot: watch::Receiver<Arc<Snapshot>>,
+    events: broadcast::Sender<NodeEvent>,
+    pub config: Arc<NodeConfig>,
+    pub listener_addrs: Arc<[SocketAddr]>,
+    /// Our long-term keypair
+    pub identity: Arc<Identity>,
+    /// Ids for the messages we split into fragments, on any connection
+    pub fragment_ids: Arc<FragmentIds>,
+    /// Cancelled once the node shuts down
+    pub shutdown: CancellationToken,
+    /// Background tasks of the node, waited for on shutdown
+    pub tasks: TaskTracker,
+    /// Notified whenever a route appears, goes away or changes its next hop or hop count
+    pub routes_changed: Arc<Notify>,
+}
+
+impl StateHandle {
+    /// Move `shared` into a new state task and return a handle to it.
+    pub fn spawn(shared: Shared) -> StateHandle {
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let (snapshot_sender, snapshot) = watch::channel(Arc::new(snapshot_of(&shared)));
+        let events = shared.event_sender.clone();
+        let config = Arc::new(shared.config.clone());
+        let listener_addrs = shared.listener_addrs.clone().into();
+        let identity = shared.identity.clone();
+        let routes_changed = Arc::new(Notify::new());
+        let notify = routes_changed.clone();
+
+        tokio::spawn(async move {
+            tracing::debug!("created state task");
+            run(shared, receiver, snapshot_sender, notify).await;
+            tracing::debug!("state task finished");
+        });
+
+        StateHandle {
+            requests,
+            snapshot,
+            events,
+            config,
+            listener_addrs,
+            identity,
+            fragment_ids: Arc::new(FragmentIds::new()),
+            shutdown: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+            routes_changed,
+        }
+    }
+
+    /// The address we are known by on a connection whose local end is `local`
+    pub fn own_addr(&self, local: SocketAddr) -> SocketAddr {
+        crate::shared::own_addr(&self.listener_addrs, local)
+    }
+
+    /// Whether packets to `addr` are meant for us
+    pub fn is_own_addr(&self, addr: SocketAddr) -> bool {
+        crate::shared::is_own_addr(&self.listener_addrs, addr)
+    }
+
+    /// The latest published state
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.borrow().clone()
+    }
+
+    /// Publish an event to everyone subscribed to this node.
+    pub fn emit(&self, event: NodeEvent) {
+        let _ = self.events.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<NodeEvent> {
+        self.events.subscribe()
+    }
+
+    fn send(&self, request: StateRequest) {
+        if let Err(e) = self.requests.send(request) {
+            tracing::error!("State task is gone, dropping request: {:?}", e.0);
+        }
+    }
+
+    /// Send a request and wait for the state task to answer it.
+    async fn ask<T>(&self, request: impl FnOnce(oneshot::Sender<T>) -> StateRequest) -> Option<T> {
+        let (reply, response) = oneshot::channel();
+        self.send(request(reply));
+        response.await.ok()
+    }
+
+    /// Register a peer, it is part of the snapshot once this returns.
+    pub async fn add_peer(&self, addr: SocketAddr, tx: PeerSender) {
+        self.ask(|reply| StateRequest::AddPeer(addr, tx, reply))
+            .await;
+    }
+
+    pub fn remove_peer(&self, addr: SocketAddr) {
+        self.send(StateRequest::RemovePeer(addr));
+    }
+
+    pub fn insert_route(&self, target: SocketAddr, entry: RoutingTableEntry) {
+        self.send(StateRequest::InsertRoute(target, entry));
+    }
+
+    /// Apply a routing update of `sender`, it is part of the snapshot once this returns.
+    pub async fn update_routing_table(&self, update: Vec<RoutingEntry>, sender: SocketAddr) {
+        self.ask(|reply| StateRequest::UpdateRoutingTable(update, sender, reply))
+            .await;
+    }
+
+    /// Check the signature of a routing update, the sequence number is used up if it's accepted.
+    /// Returns whether it's signed by the pinned key of its source.
+    pub async fn verify_update(
+        &self,
+        packet: RoutingPacket,
+        routing_type: RoutingType,
+        sender: SocketAddr,
+    ) -> Result<bool, Error> {
+        self.ask(|reply| StateRequest::VerifyUpdate(packet, routing_type, sender, reply))
+            .await
+            .unwrap_or(Ok(false))
+    }
+
+    pub async fn advertisement(&self, target: SocketAddr, local: SocketAddr) -> Vec<RoutingEntry> {
+        self.ask(|reply| StateRequest::Advertisement(target, local, reply))
+            .await
+            .unwrap_or_default()
+    }
+
+    pub fn mark_alive(&self, addr: SocketAddr) {
+        self.send(StateRequest::MarkAlive(addr));
+    }
+
+    pub fn record_rtt(&self, addr: SocketAddr, sample: Duration) {
+        self.send(StateRequest::RecordRtt(addr, sample));
+    }
+
+    pub fn expire_routes(&self) {
+        self.send(StateRequest::ExpireRoutes);
+    }
+
+    pub async fn poison_all(&self) {
+        self.ask(StateRequest::PoisonAll).await;
+    }
+
+    /// Add a fragment of a message for us, returns the message once all fragments arrived.
+    pub async fn reassemble(&self, packet: RoutedPacket) -> Option<RoutedPacket> {
+        self.ask(|reply| StateRequest::Reassemble(packet, reply))
+            .await
+            .flatten()
+    }
+
+    /// Wait for the ACK of a message to `dest` sent from `local`, returns the id to send it with.
+    pub async fn track_message(
+        &self,
+        dest: SocketAddr,
+        local: SocketAddr,
+        message: String,
+        room: Option<String>,
+    ) -> u64 {
+        self.ask(|reply| StateRequest::TrackMessage(dest, local, message, room, reply))
+            .await
+            .unwrap_or_default()
+    }
+
+    pub fn acknowledge(&self, source: SocketAddr, id: u64) {
+        self.send(StateRequest::Acknowledge(source, id));
+    }
+
+    pub async fn first_delivery(&self, source: SocketAddr, id: u64) -> bool {
+        self.ask(|reply| StateRequest::FirstDelivery(source, id, reply))
+            .await
+            .unwrap_or(true)
+    }
+
+    pub async fn next_id(&self) -> u64 {
+        self.ask(StateRequest::NextId).await.unwrap_or_default()
+    }
+
+    /// Whether the broadcast `id` of `origin` is new, every node passes it on only once
+    pub async fn first_broadcast(&self, origin: SocketAddr, id: u64) -> bool {
+        self.ask(|reply| StateRequest::FirstBroadcast(origin, id, reply))
+            .await
+            .unwrap_or(false)
+    }
+
+    pub fn open_transfer(&self, peer: SocketAddr, id: u64, tx: mpsc::UnboundedSender<FileData>) {
+        self.send(StateRequest::OpenTransfer(peer, id, tx));
+    }
+
+    pub fn close_transfer(&self, peer: SocketAddr, id: u64) {
+        self.send(StateRequest::CloseTransfer(peer, id));
+    }
+
+    /// Pass a file packet for us on to its transfer, offers are kept until they are taken
+    pub fn file_packet(&self, packet: FilePacket) {
+        self.send(StateRequest::File(packet));
+    }
+
+    pub async fn take_offer(&self, id: u64) -> Option<FilePacket> {
+        self.ask(|reply| StateRequest::TakeOffer(id, reply))
+            .await
+            .flatten()
+    }
+
+    pub fn expire_deliveries(&self) {
+        self.send(StateRequest::ExpireDeliveries);
+    }
+
+    pub async fn due_retransmits(&self) -> Vec<(u64, Outgoing)> {
+        self.ask(StateRequest::DueRetransmits)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Queue `event` for the neighbour on the route to `dest`.
+    ///
+    /// `local` is our address on the connection the route was learned from.
+    pub fn route(
+        &self,
+        dest: SocketAddr,
+        local: SocketAddr,
+        event: ChannelEvent,
+    ) -> Result<(), Error> {
+        let snapshot = self.snapshot();
+
+        // Check the routing table for the destination
+        if !snapshot.routing_table.contains_key(&dest) {
+            return Err(Error::NoRoute(dest));
+        }
+
+        // Get the channel to the next client/destination on the route
+        let (_, peer) = snapshot
+            .next_hop(dest, local)
+            .ok_or(Error::NoChannel(dest))?;
+
+        // A full queue pushes back to the caller
+        Ok(peer.send(event)?)
+    }
+
+    /// Pin the key of `addr`, returns false if a different one is pinned already.
+    pub async fn pin_key(&self, addr: SocketAddr, key: NodeKey) -> bool {
+        self.ask(|reply| StateRequest::PinKey(addr, key, reply))
+            .await;
+        self.snapshot().keys.get(&addr) == Some(&key)
+    }
+
+    pub async fn set_nickname(&self, nickname: String) {
+        self.ask(|reply| StateRequest::SetNickname(nickname, reply))
+            .await;
+    }
+
+    pub async fn join_room(&self, room: String) {
+        self.ask(|reply| StateRequest::JoinRoom(room, reply)).await;
+    }
+
+    pub async fn leave_room(&self, room: String) {
+        self.ask(|reply| StateRequest::LeaveRoom(room, reply)).await;
+    }
+
+    pub fn set_rooms(&self, addr: SocketAddr, rooms: Vec<String>) {
+        self.send(StateRequest::SetRooms(addr, rooms));
+    }
+
+    pub fn set_nickname_of(&self, addr: SocketAddr, nickname: Option<String>) {
+        self.send(StateRequest::SetNicknameOf(addr, nickname));
+    }
+
+    pub async fn set_presence(&self, presence: Presence) {
+        self.ask(|reply| StateRequest::SetPresence(presence, reply))
+            .await;
+    }
+
+    pub fn set_presence_of(&self, addr: SocketAddr, presence: Option<Presence>) {
+        self.send(StateRequest::SetPresenceOf(addr, presence));
+    }
+
+    /// Whether a typing indicator to `dest` is due, they are rate limited per destination
+    pub async fn allow_typing(&self, dest: SocketAddr) -> bool {
+        self.ask(|reply| StateRequest::AllowTyping(dest, reply))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+/// Whether the neighbours have to hear about the difference between the tables,
+/// a route being confirmed again doesn't count
+fn routes_differ(
+    old: &HashMap<SocketAddr, RoutingTableEntry>,
+    new: &HashMap<SocketAddr, RoutingTableEntry>,
+) -> bool {
+    old.len() != new.len()
+        || new.iter().any(|(target, entry)| {
+            old.get(target).is_none_or(|old_entry| {
+                old_entry.next != entry.next || old_entry.hop_count != entry.hop_count
+            })
+        })
+}
+
+fn snapshot_of(shared: &Shared) -> Snapshot {
+    Snapshot {
+        peers: shared.peers.clone(),
+        routing_table: shared.routing_table.clone(),
+        nickname: shared.nickname.clone(),
+        keys: shared.keys.clone(),
+        rooms: shared.rooms.clone(),
+        members: shared.members.clone(),
+        nicknames: shared.nicknames.clone(),
+        presence: shared.presence.clone(),
+        presences: shared.presences.clone(),
+        rtts: shared.rtts.clone(),
+    }
+}
+
+/// The state task: the only place that owns and changes `Shared`
+async fn run(
+    mut shared: Shared,
+    mut requests: mpsc::UnboundedReceiver<StateRequest>,
+    snapshot: watch::Sender<Arc<Snapshot>>,
+    routes_changed: Arc<Notify>,
+) {
+    let unreachable = shared.config.unreachable_metric;
+
+    while let Some(request) = requests.recv().await {
+        // Requests that don't touch the snapshot are done right away, everything else publishes one
+        let reply = match request {
+            StateRequest::Advertisement(target, local, reply) => {
+                let _ = reply.send(shared.get_routing_table(target, local));
+                continue;
+            }
+            StateRequest::VerifyUpdate(packet, routing_type, sender, reply) => {
+                let _ = reply.send(shared.verify_update(&packet, routing_type, sender));
+                continue;
+            }
+            StateRequest::Reassemble(packet, reply) => {
+                let _ = reply.send(shared.reassembly.push(packet, Instant::now()));
+                continue;
+            }
+            StateRequest::TrackMessage(dest, local, message, room, reply) => {
+                let _ = reply.send(
+                    shared
+                        .outbox
+                        .track(dest, local, message, room, Instant::now()),
+                );
+                continue;
+            }
+            StateRequest::Acknowledge(source, id) => {
+                if let Some(outgoing) = shared.outbox.acknowledge(source, id) {
+                    shared.emit(NodeEvent::Delivery {
+                        id,
+                        dest: outgoing.dest,
+                        status: DeliveryStatus::Delivered,
+                    });
+                }
+                continue;
+            }
+            StateRequest::FirstDelivery(source, id, reply) => {
+                let _ = reply.send(shared.inbox.first_delivery(source, id, Instant::now()));
+                continue;
+            }
+            StateRequest::NextId(reply) => {
+                let _ = reply.send(shared.outbox.next_id());
+                continue;
+            }
+            StateRequest::FirstBroadcast(origin, id, reply) => {
+                let _ = reply.send(shared.broadcasts.first_delivery(origin, id, Instant::now()));
+                continue;
+            }
+            StateRequest::OpenTransfer(peer, id, tx) => {
+                shared.transfers.open(peer, id, tx);
+                continue;
+            }
+            StateRequest::CloseTransfer(peer, id) => {
+                shared.transfers.close(peer, id);
+                continue;
+            }
+            StateRequest::File(packet) => {
+                if let Some(event) = shared.transfers.deliver(packet) {
+                    shared.emit(event);
+                }
+                continue;
+            }
+            StateRequest::TakeOffer(id, reply) => {
+                let _ = reply.send(shared.transfers.take_offer(id));
+                continue;
+            }
+            StateRequest::AllowTyping(dest, reply) => {
+                let _ = reply.send(shared.typing.allow(dest, Instant::now()));
+                continue;
+            }
+            StateRequest::ExpireDeliveries => {
+                let now = Instant::now();
+                shared.inbox.expire(now);
+                shared.broadcasts.expire(now);
+                continue;
+            }
+            StateRequest::DueRetransmits(reply) => {
+                let due = shared.outbox.due(Instant::now());
+                for (id, outgoing) in due.failed {
+                    tracing::info!("Giving up on message {} to {}", id, outgoing.dest);
+                    shared.emit(NodeEvent::Delivery {
+                        id,
+                        dest: outgoing.dest,
+                        status: DeliveryStatus::Failed,
+                    });
+                }
+                let _ = reply.send(due.retransmit);
+                continue;
+            }
+            StateRequest::AddPeer(addr, tx, reply) => {
+                shared.peers.insert(addr, tx);
+                Some(reply)
+            }
+            StateRequest::RemovePeer(addr) => {
+                shared.peers.remove(&addr);
+                shared.rtts.remove(&addr);
+                shared
+                    .sequence_numbers
+                    .retain(|(_, connection), _| *connection != addr);
+                // Poise reverse routing table
+                let now = Instant::now();
+                for rt_entry in shared.routing_table.values_mut() {
+                    if rt_entry.next == addr {
+                        rt_entry.poison(unreachable, now);
+                    }
+                }
+                None
+            }
+            StateRequest::InsertRoute(target, entry) => {
+                shared.routing_table.insert(target, entry);
+                None
+            }
+            StateRequest::UpdateRoutingTable(update, sender, reply) => {
+                shared.update_routing_table(update, sender);
+                Some(reply)
+            }
+            StateRequest::MarkAlive(addr) => {
+                shared.routing_table.entry(addr).and_modify(|rt_entry| {
+                    // A dead route only comes back through a routing update
+                    if rt_entry.hop_count < unreachable {
+                        rt_entry.updated = Instant::now();
+                    }
+                });
+                None
+            }
+            StateRequest::RecordRtt(addr, sample) => {
+                shared
+                    .rtts
+                    .entry(addr)
+                    .and_modify(|rtt| rtt.update(sample))
+                    .or_insert_with(|| RttEstimate::new(sample));
+                None
+            }
+            StateRequest::ExpireRoutes => {
+                shared.expire_routes(Instant::now());
+                None
+            }
+            StateRequest::PoisonAll(reply) => {
+                let now = Instant::now();
+                for entry in shared.routing_table.values_mut() {
+                    entry.poison(unreachable, now);
+                }
+                Some(reply)
+            }
+            StateRequest::PinKey(addr, key, reply) => {
+                let _ = shared.pin_key(addr, key);
+                Some(reply)
+            }
+            StateRequest::JoinRoom(room, reply) => {
+                tracing::info!("Joining {}", room);
+                shared.rooms.insert(room);
+                Some(reply)
+            }
+            StateRequest::LeaveRoom(room, reply) => {
+                tracing::info!("Leaving {}", room);
+                shared.rooms.remove(&room);
+                Some(reply)
+            }
+            StateRequest::SetRooms(addr, rooms) => {
+                shared.set_rooms(addr, rooms);
+                None
+            }
+            StateRequest::SetNicknameOf(addr, nickname) => {
+                shared.set_nickname_of(addr, nickname);
+                None
+            }
+            StateRequest::SetPresenceOf(addr, presence) => {
+                shared.set_presence_of(addr, presence);
+                None
+            }
+            StateRequest::SetPresence(presence, reply) => {
+                tracing::debug!("Setting presence to: {}", presence);
+                shared.presence = presence;
+                Some(reply)
+            }
+            StateRequest::SetNickname(nickname, reply) => {
+                tracing::debug!("Setting nickname to: {}", nickname);
+                if shared.nicknames.values().any(|name| *name == nickname) {
+                    shared.emit(NodeEvent::Log(format!(
+                        "Another node is called {} as well",
+                        nickname
+                    )));
+                }
+                shared.nickname = nickname;
+                Some(reply)
+            }
+        };
+
+        // Publish before answering, so the caller already sees its change
+        let previous = snapshot.send_replace(Arc::new(snapshot_of(&shared)));
+        if routes_differ(&previous.routing_table, &shared.routing_table) {
+            routes_changed.notify_one();
+        }
+        if let Some(reply) = reply {
+            let _ = reply.send(());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_state_task_publishes_changes() {
+    let (event_sender, _) = broadcast::channel(crate::shared::EVENT_CAPACITY);
+    let local = "127.0.0.1:6142".parse::<SocketAddr>().unwrap();
+    let state = StateHandle::spawn(Shared::new(event_sender, NodeConfig::default()));
+
+    let neighbour = "127.0.0.1:6143".parse::<SocketAddr>().unwrap();
+    let behind = "127.0.0.1:6144".parse::<SocketAddr>().unwrap();
+    let (tx, _rx) = crate::queue::peer_queue(1, Default::default());
+    state.add_peer(neighbour, tx).await;
+    assert!(state.snapshot().peers.contains_key(&neighbour));
+
+    state.insert_route(neighbour, RoutingTableEntry::new(neighbour, 1));
+    state
+        .update_routing_table(
+            vec![RoutingEntry {
+                target: "127.0.0.1:6144".parse().unwrap(),
+                next: "127.0.0.1:6143".parse().unwrap(),
+                hop_count: 1,
+                public_key: None,
+                rooms: Vec::new(),
+                nickname: None,
+                presence: None,
+            }],
+            neighbour,
+        )
+        .await;
+    let snapshot = state.snapshot();
+    assert_eq!(snapshot.routing_table.len(), 2);
+    assert_eq!(snapshot.next_hop(behind, local).unwrap().0, neighbour);
+
+    // Requests are handled in order, so the query sees the removal
+    state.remove_peer(neighbour);
+    assert!(state
+        .advertisement(behind, local)
+        .await
+        .iter()
+        .all(|entry| entry.hop_count == 32));
+    let snapshot = state.snapshot();
+    assert!(snapshot.peers.is_empty());
+    assert!(snapshot.next_hop(behind, local).is_none());
+}
+
+#[tokio::test]
+async fn test_route_changes_are_notified() {
+    let (event_sender, _) = broadcast::channel(crate::shared::EVENT_CAPACITY);
+    let state = StateHandle::spawn(Shared::new(event_sender, NodeConfig::default()));
+    let neighbour = "127.0.0.1:6143".parse::<SocketAddr>().unwrap();
+    let changed =
+        || tokio::time::timeout(Duration::from_millis(100), state.routes_changed.notified());
+
+    state.insert_route(neighbour, RoutingTableEntry::new(neighbour, 1));
+    assert!(changed().await.is_ok());
+
+    // Being confirmed again isn't news to the neighbours
+    state.mark_alive(neighbour);
+    state.expire_routes();
+    assert!(changed().await.is_err());
+
+    // Losing the neighbour is
+    state.remove_peer(neighbour);
+    assert!(changed().await.is_ok());
+}
@@ -1,49 +1,65 @@
 use std::cmp::Ordering;
+use std::sync::Arc;
 
 use tokio_util::{
     bytes::BytesMut,
     codec::{Decoder, Encoder},
 };
 
+use crate::error::Error;
+use crate::fragment::{self, FragmentIds};
 use crate::protocol::{
+    ack_packet::AckPacket,
+    broadcast_packet::BroadcastPacket,
     common_header::{CommonHeader, CommonHeaderUnparsed, COMMON_HEADER_LENGTH},
+    file_packet::FilePacket,
     routed_packet::RoutedPacket,
     routing_packet::RoutingPacket,
-    Packet, CR, CRR, MESSAGE, SCC, SCCR, STU,
+    Packet, PacketType,
 };
 
 #[cfg(test)]
-use crate::protocol::shared_header::SharedHeader;
+use crate::protocol::{shared_header::SharedHeader, RoutingType};
 
 // Swag Decoder is a custom decoder for the SWAG protocol
 pub struct SwagCoder {
     has_common_header: bool,
     last_common_header: Option<CommonHeader>,
+    /// Ids for messages too large for a single packet, shared by all connections of a node
+    fragment_ids: Arc<FragmentIds>,
 }
 
 const MAX_ACCEPTED_LEN: usize = 8 * 1024 * 1024;
 
 impl SwagCoder {
     pub fn new() -> Self {
+        Self::with_fragment_ids(Arc::new(FragmentIds::new()))
+    }
+
+    pub fn with_fragment_ids(fragment_ids: Arc<FragmentIds>) -> Self {
         SwagCoder {
             has_common_header: false,
             last_common_header: None,
+            fragment_ids,
         }
     }
 }
 
+impl Default for SwagCoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Decoder for SwagCoder {
     type Item = Packet;
 
-    type Error = std::io::Error;
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         // Check whether the buffer is too large
         if src.len() > MAX_ACCEPTED_LEN {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Buffer too large: {} - Exiting as a protection", src.len()),
-            ));
+            return Err(Error::FrameTooLarge(src.len()));
         }
 
         // If the common header hasn't arrived yet we need to read it
@@ -59,16 +75,11 @@ impl Decoder for SwagCoder {
             let header_unparsed: CommonHeaderUnparsed = match serde_json::from_slice(&header_bytes)
             {
                 Ok(header) => header,
-                Err(e) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Error deserializing common header: {}", e),
-                    ));
-                }
+                Err(e) => return Err(Error::InvalidHeader(e.to_string())),
             };
 
             tracing::debug!("Received header unparsed: {:?}", header_unparsed);
-            let header = CommonHeader::from_unparsed(header_unparsed);
+            let header = CommonHeader::from_unparsed(header_unparsed)?;
             tracing::debug!("Received header: {:?}", header);
 
             self.last_common_header = Some(header);
@@ -88,47 +99,48 @@ impl Decoder for SwagCoder {
             // Verify the checksum
             let checksum = crc32fast::hash(&packet_bytes);
             if checksum != header.crc32 {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Checksum mismatch: {} != {}", checksum, header.crc32),
-                ));
+                return Err(Error::ChecksumMismatch {
+                    expected: header.crc32,
+                    actual: checksum,
+                });
             }
 
             // Deserialize the packet
-            let packet = match header.type_id {
-                CR | CRR | SCC | SCCR | STU => {
-                    let packet: RoutingPacket = match serde_json::from_slice(&packet_bytes) {
-                        Ok(packet) => packet,
-                        Err(e) => {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                format!("Error deserializing routing packet: {}", e),
-                            ));
-                        }
-                    };
+            let packet = match header.packet_type {
+                PacketType::Routing(routing_type) => {
+                    let packet: RoutingPacket =
+                        serde_json::from_slice(&packet_bytes).map_err(Error::InvalidPayload)?;
 
                     self.has_common_header = false;
-                    Packet::RoutingPacket(packet, header.type_id)
+                    Packet::RoutingPacket(packet, routing_type)
                 }
-                MESSAGE => {
-                    let packet: RoutedPacket = match serde_json::from_slice(&packet_bytes) {
-                        Ok(packet) => packet,
-                        Err(e) => {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                format!("Error deserializing routed packet: {}", e),
-                            ));
-                        }
-                    };
+                PacketType::Message => {
+                    let packet: RoutedPacket =
+                        serde_json::from_slice(&packet_bytes).map_err(Error::InvalidPayload)?;
 
                     self.has_common_header = false;
                     Packet::RoutedPacket(packet)
                 }
-                _ => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Unknown packet type: {}", header.type_id),
-                    ));
+                PacketType::Ack => {
+                    let packet: AckPacket =
+                        serde_json::from_slice(&packet_bytes).map_err(Error::InvalidPayload)?;
+
+                    self.has_common_header = false;
+                    Packet::Ack(packet)
+                }
+                PacketType::Broadcast => {
+                    let packet: BroadcastPacket =
+                        serde_json::from_slice(&packet_bytes).map_err(Error::InvalidPayload)?;
+
+                    self.has_common_header = false;
+                    Packet::Broadcast(packet)
+                }
+                PacketType::File => {
+                    let packet: FilePacket =
+                        serde_json::from_slice(&packet_bytes).map_err(Error::InvalidPayload)?;
+
+                    self.has_common_header = false;
+                    Packet::File(packet)
                 }
             };
 
@@ -139,90 +151,111 @@ impl Decoder for SwagCoder {
 }
 
 impl Encoder<Packet> for SwagCoder {
-    type Error = std::io::Error;
+    type Error = Error;
 
     fn encode(&mut self, item: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let payload_bytes = match item.clone() {
+        let packet_type = item.packet_type();
+        match item {
             Packet::RoutingPacket(packet, _) => {
+                let bytes = serde_json::to_vec(&packet).map_err(Error::Serialize)?;
                 tracing::debug!(
                     "Encoding routing packet: {:?}",
-                    serde_json::to_string(&packet).unwrap()
+                    String::from_utf8_lossy(&bytes)
                 );
+                // Routing tables can't be fragmented
+                encode_frame(packet_type, &bytes, dst)
+            }
+            // Chunks of a file are small enough to never need fragments
+            Packet::File(packet) => {
+                let bytes = serde_json::to_vec(&packet).map_err(Error::Serialize)?;
+                encode_frame(packet_type, &bytes, dst)
+            }
+            Packet::Ack(packet) => {
+                let bytes = serde_json::to_vec(&packet).map_err(Error::Serialize)?;
+                encode_frame(packet_type, &bytes, dst)
+            }
+            // Broadcasts aren't fragmented, every node would have to reassemble them
+            Packet::Broadcast(packet) => {
+                let bytes = serde_json::to_vec(&packet).map_err(Error::Serialize)?;
+                encode_frame(packet_type, &bytes, dst)
+            }
+            Packet::RoutedPacket(packet) => {
+                let bytes = serde_json::to_vec(&packet).map_err(Error::Serialize)?;
+                if bytes.len() <= u16::MAX as usize || packet.fragment.is_some() {
+                    return encode_frame(packet_type, &bytes, dst);
+                }
 
-                match serde_json::to_vec(&packet) {
-                    Ok(bytes) => bytes,
-                    Err(e) => {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!("Error serializing routing packet: {}", e),
-                        ));
+                // Too large for a single packet, send the message in fragments
+                let fragments = fragment::split(packet, &self.fragment_ids)
+                    .ok_or(Error::PayloadTooLarge(bytes.len()))?;
+                tracing::debug!("Splitting message into {} fragments", fragments.len());
+                let start = dst.len();
+                for fragment in fragments {
+                    let result = serde_json::to_vec(&fragment)
+                        .map_err(Error::Serialize)
+                        .and_then(|bytes| encode_frame(packet_type, &bytes, dst));
+                    if let Err(e) = result {
+                        // Don't leave the first fragments of a message that won't complete
+                        dst.truncate(start);
+                        return Err(e);
                     }
                 }
+                Ok(())
             }
-            Packet::RoutedPacket(packet) => match serde_json::to_vec(&packet) {
-                Ok(bytes) => bytes,
-                Err(e) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Error serializing routed packet: {}", e),
-                    ));
-                }
-            },
-        };
+        }
+    }
+}
 
-        // Calculate the checksum
-        tracing::debug!("Payload bytes: {:?}", payload_bytes.as_slice());
-        let checksum = crc32fast::hash(&payload_bytes.as_slice());
-        // Create the common header
-        let header = CommonHeader {
-            length: payload_bytes.len() as u16,
-            crc32: checksum,
-            type_id: match item {
-                Packet::RoutingPacket(_, type_id) => type_id,
-                Packet::RoutedPacket(_) => MESSAGE,
-            },
-        };
-        let header_stringify = CommonHeaderUnparsed::new(header);
-        let header_string = serde_json::to_string(&header_stringify).unwrap();
-        let header_bytes = header_string.as_bytes();
-
-        match header_bytes.len().cmp(&COMMON_HEADER_LENGTH) {
-            Ordering::Less => {
-                let mut new_header_bytes = [0; COMMON_HEADER_LENGTH];
-                new_header_bytes[..header_bytes.len()].copy_from_slice(header_bytes);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!(
-                        "Common header too small: {} - Should be {}",
-                        header_bytes.len(),
-                        COMMON_HEADER_LENGTH
-                    ),
-                ));
-            }
-            Ordering::Greater => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!(
-                        "Common header too large: {} - Should be {}",
-                        header_bytes.len(),
-                        COMMON_HEADER_LENGTH
-                    ),
-                ));
-            }
-            Ordering::Equal => {
-                // Reserve space for the common header & packet
-                dst.reserve(COMMON_HEADER_LENGTH + payload_bytes.len());
+/// Write the common header for `payload_bytes` followed by the payload itself.
+fn encode_frame(
+    packet_type: PacketType,
+    payload_bytes: &[u8],
+    dst: &mut BytesMut,
+) -> Result<(), Error> {
+    let length = u16::try_from(payload_bytes.len())
+        .map_err(|_| Error::PayloadTooLarge(payload_bytes.len()))?;
+
+    // Calculate the checksum
+    tracing::debug!("Payload bytes: {:?}", payload_bytes);
+    let checksum = crc32fast::hash(payload_bytes);
+    // Create the common header
+    let header = CommonHeader {
+        length,
+        crc32: checksum,
+        packet_type,
+    };
+    let header_stringify = CommonHeaderUnparsed::new(header);
+    let header_string = serde_json::to_string(&header_stringify).map_err(Error::Serialize)?;
+    let header_bytes = header_string.as_bytes();
+
+    match header_bytes.len().cmp(&COMMON_HEADER_LENGTH) {
+        Ordering::Less => {
+            return Err(Error::InvalidHeader(format!(
+                "too small: {} - Should be {}",
+                header_bytes.len(),
+                COMMON_HEADER_LENGTH
+            )));
+        }
+        Ordering::Greater => {
+            return Err(Error::InvalidHeader(format!(
+                "too large: {} - Should be {}",
+                header_bytes.len(),
+                COMMON_HEADER_LENGTH
+            )));
+        }
+        Ordering::Equal => {
+            // Reserve space for the common header & packet
+            dst.reserve(COMMON_HEADER_LENGTH + payload_bytes.len());
 
-                // Write the common header
-                dst.extend_from_slice(header_bytes);
+            // Write the common header
+            dst.extend_from_slice(header_bytes);
 
-                // Write the packet
-                dst.extend_from_slice(&payload_bytes);
-            }
+            // Write the packet
+            dst.extend_from_slice(payload_bytes);
         }
-
-        Ok(())
     }
+
+    Ok(())
 }
 
 #[test]
@@ -230,14 +263,19 @@ pub fn test_weird_decode() {
     let mut coder = SwagCoder::new();
     let packet = RoutedPacket {
         header: SharedHeader {
-            source_ip: "127.0.0.1".to_string(),
-            source_port: 58471,
-            dest_ip: "127.0.0.1".to_string(),
-            dest_port: 6143,
+            source: "127.0.0.1:58471".parse().unwrap(),
+            dest: "127.0.0.1:6143".parse().unwrap(),
             ttl: 16,
         },
         nickname: "TODO".to_string(),
         message: "hi".to_string(),
+        id: None,
+        sealed: None,
+        room: None,
+        fragment: None,
+        typing: false,
+        trace: None,
+        ping: None,
     };
     let mut encoded = BytesMut::new();
     coder
@@ -252,14 +290,19 @@ pub fn test_recoding() {
     let mut coder = SwagCoder::new();
     let original_packet = RoutedPacket {
         header: SharedHeader {
-            source_ip: "127.0.0.1".to_string(),
-            source_port: 58471,
-            dest_ip: "127.0.0.1".to_string(),
-            dest_port: 6143,
+            source: "127.0.0.1:58471".parse().unwrap(),
+            dest: "127.0.0.1:6143".parse().unwrap(),
             ttl: 16,
         },
         nickname: "test_nickname".to_string(),
         message: "hello".to_string(),
+        id: None,
+        sealed: None,
+        room: None,
+        fragment: None,
+        typing: false,
+        trace: None,
+        ping: None,
     };
 
     // Encode the packet
@@ -282,6 +325,26 @@ pub fn test_recoding() {
     assert!(encoded.is_empty());
 }
 
+#[test]
+pub fn test_malformed_header_is_rejected() {
+    let mut coder = SwagCoder::new();
+    let mut garbage =
+        BytesMut::from(&br#"{"length":"xxxxx","crc32":"0000000000","type_id":"1"}"#[..]);
+    assert_eq!(garbage.len(), COMMON_HEADER_LENGTH);
+    assert!(matches!(
+        coder.decode(&mut garbage),
+        Err(Error::InvalidHeader(_))
+    ));
+
+    let mut coder = SwagCoder::new();
+    let mut unknown =
+        BytesMut::from(&br#"{"length":"00000","crc32":"0000000000","type_id":"0"}"#[..]);
+    assert!(matches!(
+        coder.decode(&mut unknown),
+        Err(Error::UnknownPacketType(0))
+    ));
+}
+
 #[test]
 pub fn test_checksum_creation() {
     static EXPECTED_CHECKSUM: u32 = 593877371; // Generated via https://crc32.online/
@@ -289,3 +352,153 @@ pub fn test_checksum_creation() {
     let checksum = crc32fast::hash(example_data);
     assert_eq!(checksum, EXPECTED_CHECKSUM);
 }
+
+#[test]
+pub fn test_wire_format_unchanged() {
+    // A STU as it was sent before the packet model was typed
+    let wire = concat!(
+        r#"{"length":"00124","crc32":"0593877371","type_id":"6"}"#,
+        r#"{"header":{"source_ip":"10.241.51.185","source_port":46455,"dest_ip":"10.241.51.185","dest_port":50847,"ttl":16},"table":[]}"#
+    );
+    let packet = Packet::RoutingPacket(
+        RoutingPacket {
+            header: SharedHeader {
+                source: "10.241.51.185:46455".parse().unwrap(),
+                dest: "10.241.51.185:50847".parse().unwrap(),
+                ttl: 16,
+            },
+            table: Some(Vec::new()),
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
+            signed: None,
+        },
+        RoutingType::STU,
+    );
+
+    let mut encoded = BytesMut::new();
+    SwagCoder::new()
+        .encode(packet.clone(), &mut encoded)
+        .unwrap();
+    assert_eq!(std::str::from_utf8(&encoded).unwrap(), wire);
+
+    let mut wire = BytesMut::from(wire.as_bytes());
+    assert_eq!(SwagCoder::new().decode(&mut wire).unwrap(), Some(packet));
+}
+
+#[test]
+pub fn test_large_message_is_fragmented() {
+    let mut coder = SwagCoder::new();
+    let original_packet = RoutedPacket {
+        header: SharedHeader {
+            source: "127.0.0.1:58471".parse().unwrap(),
+            dest: "127.0.0.1:6143".parse().unwrap(),
+            ttl: 16,
+        },
+        nickname: "test_nickname".to_string(),
+        // Every byte is escaped to six on the wire
+        message: "\u{1}".repeat(20 * 1024),
+        id: None,
+        sealed: None,
+        room: None,
+        fragment: None,
+        typing: false,
+        trace: None,
+        ping: None,
+    };
+
+    let mut encoded = BytesMut::new();
+    coder
+        .encode(Packet::RoutedPacket(original_packet.clone()), &mut encoded)
+        .unwrap();
+
+    let mut reassembly = crate::fragment::Reassembly::new(std::time::Duration::from_secs(30));
+    let mut fragments = 0;
+    let mut reassembled = None;
+    while let Some(packet) = coder.decode(&mut encoded).unwrap() {
+        let Packet::RoutedPacket(fragment) = packet else {
+            panic!("Decoded packet is not of type RoutedPacket");
+        };
+        assert!(fragment.fragment.is_some());
+        fragments += 1;
+        reassembled = reassembly.push(fragment, std::time::Instant::now());
+    }
+    assert_eq!(fragments, 3);
+    assert_eq!(reassembled, Some(original_packet));
+    assert!(encoded.is_empty());
+}
+
+#[test]
+pub fn test_oversized_routing_packet_is_refused() {
+    let entry = crate::protocol::routing_packet::RoutingEntry {
+        target: "[fd00::1]:6142".parse().unwrap(),
+        next: "[fd00::2]:6142".parse().unwrap(),
+        hop_count: 1,
+        public_key: None,
+        rooms: Vec::new(),
+        nickname: None,
+        presence: None,
+    };
+    let packet = Packet::RoutingPacket(
+        RoutingPacket {
+            header: SharedHeader {
+                source: "10.241.51.185:46455".parse().unwrap(),
+                dest: "10.241.51.185:50847".parse().unwrap(),
+                ttl: 16,
+            },
+            table: Some(vec![entry; 1024]),
+            public_key: None,
+            rooms: Vec::new(),
+            nickname: None,
+            presence: None,
+            signed: None,
+        },
+        RoutingType::STU,
+    );
+
+    let mut encoded = BytesMut::new();
+    assert!(matches!(
+        SwagCoder::new().encode(packet, &mut encoded),
+        Err(Error::PayloadTooLarge(_))
+    ));
+    assert!(encoded.is_empty());
+}
+
+#[test]
+pub fn test_ack_recoding() {
+    let mut coder = SwagCoder::new();
+    let ack = Packet::Ack(AckPacket {
+        header: SharedHeader {
+            source: "[::1]:6143".parse().unwrap(),
+            dest: "127.0.0.1:6142".parse().unwrap(),
+            ttl: 16,
+        },
+        id: 7,
+    });
+
+    let mut encoded = BytesMut::new();
+    coder.encode(ack.clone(), &mut encoded).unwrap();
+    assert!(encoded[..COMMON_HEADER_LENGTH].ends_with(br#""type_id":"7"}"#));
+    assert_eq!(coder.decode(&mut encoded).unwrap(), Some(ack));
+}
+
+#[test]
+pub fn test_broadcast_recoding() {
+    let mut coder = SwagCoder::new();
+    let broadcast = Packet::Broadcast(BroadcastPacket {
+        header: SharedHeader {
+            source: "[::1]:6143".parse().unwrap(),
+            dest: "127.0.0.1:6142".parse().unwrap(),
+            ttl: 16,
+        },
+        id: 7,
+        nickname: "alice".to_string(),
+        message: "hello everyone".to_string(),
+    });
+
+    let mut encoded = BytesMut::new();
+    coder.encode(broadcast.clone(), &mut encoded).unwrap();
+    assert!(encoded[..COMMON_HEADER_LENGTH].ends_with(br#""type_id":"8"}"#));
+    assert_eq!(coder.decode(&mut encoded).unwrap(), Some(broadcast));
+}
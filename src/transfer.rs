@@ -0,0 +1,446 @@
+use sha2::{Digest, Sha256};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::channel_events::{ChannelEvent, FileStatus, NodeEvent};
+use crate::protocol::file_packet::{FileData, FilePacket};
+use crate::protocol::shared_header::SharedHeader;
+use crate::protocol::Packet;
+use crate::state::StateHandle;
+
+/// Bytes of the file carried by a single chunk, base64 keeps it well below the u16 length field.
+pub const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Largest file we send or accept, both ends keep it in memory to compute its digest.
+pub const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Chunks sent ahead of the last ACK
+const WINDOW: u64 = 8;
+
+/// Time the receiver gets to accept an offer
+const OFFER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// File transfers of this node and the offers it hasn't answered yet.
+///
+/// A transfer is known by the node at the other end and the id its sender picked.
+#[derive(Debug, Default)]
+pub struct Transfers {
+    running: HashMap<(SocketAddr, u64), mpsc::UnboundedSender<FileData>>,
+    offers: HashMap<u64, FilePacket>,
+}
+
+impl Transfers {
+    /// Hand the packets of a transfer to its task from now on
+    pub fn open(&mut self, peer: SocketAddr, id: u64, tx: mpsc::UnboundedSender<FileData>) {
+        self.running.insert((peer, id), tx);
+    }
+
+    pub fn close(&mut self, peer: SocketAddr, id: u64) {
+        self.running.remove(&(peer, id));
+    }
+
+    /// Hand a packet to its transfer, returns the event announcing it if it's a new offer.
+    pub fn deliver(&mut self, packet: FilePacket) -> Option<NodeEvent> {
+        let source = packet.header.source;
+        if let Some(tx) = self.running.get(&(source, packet.transfer)) {
+            let _ = tx.send(packet.data);
+            return None;
+        }
+        let FileData::Offer { name, size, .. } = &packet.data else {
+            tracing::debug!(
+                "Dropping file packet of unknown transfer {} of {}",
+                packet.transfer,
+                source
+            );
+            return None;
+        };
+        let event = NodeEvent::FileOffer {
+            source,
+            id: packet.transfer,
+            name: name.clone(),
+            size: *size,
+        };
+        match self.offers.entry(packet.transfer) {
+            // Either the same offer again or a clash of ids, the first one stays
+            Entry::Occupied(_) => None,
+            Entry::Vacant(entry) => {
+                entry.insert(packet);
+                Some(event)
+            }
+        }
+    }
+
+    /// Remove the offer `id`, to accept or decline it
+    pub fn take_offer(&mut self, id: u64) -> Option<FilePacket> {
+        self.offers.remove(&id)
+    }
+}
+
+/// Publishes the progress of a transfer whenever another percent is done
+struct Progress {
+    peer: SocketAddr,
+    id: u64,
+    name: String,
+    size: u64,
+    percent: Option<u64>,
+}
+
+impl Progress {
+    fn new(peer: SocketAddr, id: u64, name: &str, size: u64) -> Self {
+        Progress {
+            peer,
+            id,
+            name: name.to_string(),
+            size,
+            percent: None,
+        }
+    }
+
+    fn update(&mut self, state: &StateHandle, bytes: u64) {
+        let percent = match self.size {
+            0 => 100,
+            size => bytes * 100 / size,
+        };
+        if self.percent == Some(percent) {
+            return;
+        }
+        self.percent = Some(percent);
+        state.emit(NodeEvent::FileProgress {
+            peer: self.peer,
+            id: self.id,
+            name: self.name.clone(),
+            bytes,
+            size: self.size,
+        });
+    }
+}
+
+/// Offer `content` to `dest` and send it once the receiver accepts it.
+pub(crate) async fn send(
+    state: StateHandle,
+    local: SocketAddr,
+    dest: SocketAddr,
+    id: u64,
+    name: String,
+    content: Vec<u8>,
+) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    state.open_transfer(dest, id, tx);
+    let status = sending(&state, local, dest, id, &name, &content, &mut rx).await;
+    state.close_transfer(dest, id);
+    finish(&state, dest, id, name, status);
+}
+
+async fn sending(
+    state: &StateHandle,
+    local: SocketAddr,
+    dest: SocketAddr,
+    id: u64,
+    name: &str,
+    content: &[u8],
+    rx: &mut mpsc::UnboundedReceiver<FileData>,
+) -> FileStatus {
+    let size = content.len() as u64;
+    let sha256 = format!("{:x}", Sha256::digest(content));
+    let offer = FileData::Offer {
+        name: name.to_string(),
+        size,
+        sha256,
+    };
+    send_packet(state, local, dest, id, offer);
+
+    // The receiver may already have the beginning of the file from an earlier try
+    let offset = match timeout(OFFER_TIMEOUT, accepted(rx)).await {
+        Ok(Ok(offset)) => offset.min(size),
+        Ok(Err(reason)) => return FileStatus::Failed(reason),
+        Err(_) => return FileStatus::Failed("the offer was not accepted in time".to_string()),
+    };
+
+    let mut progress = Progress::new(dest, id, name, size);
+    progress.update(state, offset);
+    let mut acked = offset;
+    let mut next = offset;
+    let mut retries = 0;
+    loop {
+        while next < size && next - acked < WINDOW * CHUNK_SIZE as u64 {
+            let end = (next + CHUNK_SIZE as u64).min(size);
+            let chunk = FileData::chunk(next, &content[next as usize..end as usize]);
+            send_packet(state, local, dest, id, chunk);
+            next = end;
+        }
+
+        match timeout(state.config.ack_timeout(), rx.recv()).await {
+            Ok(Some(FileData::Ack { offset })) if offset > acked => {
+                acked = offset.min(size);
+                retries = 0;
+                progress.update(state, acked);
+            }
+            Ok(Some(FileData::Done)) => return FileStatus::Sent,
+            Ok(Some(FileData::Reject { reason })) => return FileStatus::Failed(reason),
+            Ok(Some(_)) => {}
+            Ok(None) => return FileStatus::Failed("the transfer was closed".to_string()),
+            Err(_) => {
+                retries += 1;
+                if retries > state.config.max_retransmits {
+                    return FileStatus::Failed("the receiver stopped answering".to_string());
+                }
+                if acked < size {
+                    // Start over at the first byte the receiver is missing
+                    next = acked;
+                } else {
+                    // Only the Done got lost, the last chunk asks for it again
+                    let start = size.saturating_sub(1) / CHUNK_SIZE as u64 * CHUNK_SIZE as u64;
+                    let chunk = FileData::chunk(start, &content[start as usize..]);
+                    send_packet(state, local, dest, id, chunk);
+                }
+            }
+        }
+    }
+}
+
+/// Wait for the answer to our offer, the offset to start at if it's accepted
+async fn accepted(rx: &mut mpsc::UnboundedReceiver<FileData>) -> Result<u64, String> {
+    while let Some(data) = rx.recv().await {
+        match data {
+            FileData::Accept { offset } => return Ok(offset),
+            FileData::Reject { reason } => return Err(reason),
+            _ => {}
+        }
+    }
+    Err("the transfer was closed".to_string())
+}
+
+/// Receive the offered file and write it to the download directory.
+pub(crate) async fn receive(state: StateHandle, offer: FilePacket) {
+    let FileData::Offer { name, size, sha256 } = offer.data else {
+        return;
+    };
+    let source = offer.header.source;
+    let local = offer.header.dest;
+    let id = offer.transfer;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    state.open_transfer(source, id, tx);
+    let status = match receiving(&state, local, source, id, &name, size, &sha256, &mut rx).await {
+        Ok(path) => {
+            send_packet(&state, local, source, id, FileData::Done);
+            FileStatus::Received(path)
+        }
+        Err(reason) => {
+            let reject = FileData::Reject {
+                reason: reason.clone(),
+            };
+            send_packet(&state, local, source, id, reject);
+            FileStatus::Failed(reason)
+        }
+    };
+    let received = matches!(status, FileStatus::Received(_));
+    finish(&state, source, id, name, status);
+
+    // Our Done may get lost, answer the sender until it stops asking
+    if received {
+        while let Ok(Some(data)) = timeout(state.config.retransmit_window(), rx.recv()).await {
+            if let FileData::Chunk { .. } = data {
+                send_packet(&state, local, source, id, FileData::Done);
+            }
+        }
+    }
+    state.close_transfer(source, id);
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn receiving(
+    state: &StateHandle,
+    local: SocketAddr,
+    source: SocketAddr,
+    id: u64,
+    name: &str,
+    size: u64,
+    sha256: &str,
+    rx: &mut mpsc::UnboundedReceiver<FileData>,
+) -> Result<PathBuf, String> {
+    let Some(file_name) = Path::new(name).file_name() else {
+        return Err(format!("{} is not a file name", name));
+    };
+    if size > MAX_FILE_SIZE {
+        return Err(format!("{} bytes are too many", size));
+    }
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("the digest of the file is invalid".to_string());
+    }
+
+    let dir = &state.config.download_dir;
+    fs::create_dir_all(dir).await.map_err(|e| e.to_string())?;
+    // Named after the content, so offering the same file again resumes it
+    let part_path = dir.join(format!(
+        ".{}.{}.part",
+        file_name.to_string_lossy(),
+        &sha256[..16]
+    ));
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&part_path)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut received = file.metadata().await.map_err(|e| e.to_string())?.len();
+    if received > size {
+        file.set_len(0).await.map_err(|e| e.to_string())?;
+        received = 0;
+    }
+    file.seek(SeekFrom::Start(received))
+        .await
+        .map_err(|e| e.to_string())?;
+    send_packet(
+        state,
+        local,
+        source,
+        id,
+        FileData::Accept { offset: received },
+    );
+
+    let mut progress = Progress::new(source, id, name, size);
+    progress.update(state, received);
+    while received < size {
+        let data = match timeout(state.config.retransmit_window(), rx.recv()).await {
+            Ok(Some(data)) => data,
+            Ok(None) => return Err("the transfer was closed".to_string()),
+            Err(_) => return Err("the sender stopped sending".to_string()),
+        };
+        let FileData::Chunk { offset, .. } = &data else {
+            continue;
+        };
+        if *offset == received {
+            if let Some(bytes) = data.chunk_bytes() {
+                if received + bytes.len() as u64 > size {
+                    return Err("the file is larger than offered".to_string());
+                }
+                file.write_all(&bytes).await.map_err(|e| e.to_string())?;
+                received += bytes.len() as u64;
+                progress.update(state, received);
+            }
+        }
+        // Tell the sender where to continue, also after a chunk that was lost or out of order
+        send_packet(state, local, source, id, FileData::Ack { offset: received });
+    }
+    file.flush().await.map_err(|e| e.to_string())?;
+    drop(file);
+
+    let content = fs::read(&part_path).await.map_err(|e| e.to_string())?;
+    if format!("{:x}", Sha256::digest(&content)) != sha256 {
+        // Something went wrong on an earlier try, start from scratch next time
+        let _ = fs::remove_file(&part_path).await;
+        return Err("the file doesn't match its digest".to_string());
+    }
+    let path = free_path(dir, file_name).await;
+    fs::rename(&part_path, &path)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(path)
+}
+
+/// Tell the sender of the offer `id` that we don't want the file
+pub(crate) fn decline(state: &StateHandle, offer: FilePacket) {
+    let reject = FileData::Reject {
+        reason: "declined".to_string(),
+    };
+    send_packet(
+        state,
+        offer.header.dest,
+        offer.header.source,
+        offer.transfer,
+        reject,
+    );
+}
+
+/// `name` in `dir`, numbered if a file of that name exists already
+async fn free_path(dir: &Path, name: &OsStr) -> PathBuf {
+    let mut path = dir.join(name);
+    let mut number = 1;
+    while fs::try_exists(&path).await.unwrap_or(false) {
+        path = dir.join(format!("{}.{}", name.to_string_lossy(), number));
+        number += 1;
+    }
+    path
+}
+
+fn finish(state: &StateHandle, peer: SocketAddr, id: u64, name: String, status: FileStatus) {
+    tracing::info!("Transfer {} of {} with {}: {:?}", id, name, peer, status);
+    state.emit(NodeEvent::FileDone {
+        peer,
+        id,
+        name,
+        status,
+    });
+}
+
+/// Route a packet of the transfer `id` to the other end
+fn send_packet(state: &StateHandle, local: SocketAddr, dest: SocketAddr, id: u64, data: FileData) {
+    let packet = FilePacket {
+        header: SharedHeader {
+            source: local,
+            dest,
+            ttl: state.config.default_ttl,
+        },
+        transfer: id,
+        data,
+    };
+    // Not getting it queued is no different from losing it on the way, it's sent again
+    if let Err(e) = state.route(dest, local, ChannelEvent::Forward(Packet::File(packet))) {
+        tracing::info!("Error sending packet of transfer {} to {}: {}", id, dest, e);
+    }
+}
+
+#[test]
+fn test_transfers_deliver() {
+    let source = "127.0.0.1:6143".parse().unwrap();
+    let offer = FilePacket {
+        header: SharedHeader {
+            source,
+            dest: "127.0.0.1:6142".parse().unwrap(),
+            ttl: 16,
+        },
+        transfer: 7,
+        data: FileData::Offer {
+            name: "log.txt".to_string(),
+            size: 3,
+            sha256: String::new(),
+        },
+    };
+    let mut transfers = Transfers::default();
+    assert_eq!(
+        transfers.deliver(offer.clone()),
+        Some(NodeEvent::FileOffer {
+            source,
+            id: 7,
+            name: "log.txt".to_string(),
+            size: 3,
+        })
+    );
+    // Announced once
+    assert_eq!(transfers.deliver(offer.clone()), None);
+    assert_eq!(transfers.take_offer(7), Some(offer.clone()));
+
+    // Once accepted, the packets go to the task of the transfer
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    transfers.open(source, 7, tx);
+    let chunk = FilePacket {
+        data: FileData::chunk(0, b"abc"),
+        ..offer
+    };
+    assert_eq!(transfers.deliver(chunk), None);
+    assert_eq!(rx.try_recv().unwrap(), FileData::chunk(0, b"abc"));
+    transfers.close(source, 7);
+    assert!(rx.try_recv().is_err());
+}
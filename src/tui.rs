@@ -1,17 +1,19 @@
 use std::collections::HashMap;
 use std::io::Result;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Instant;
 use std::{io::stdout, time::Duration};
 
 use crossterm::{
-    event::{self, Event, KeyCode, KeyEventKind},
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
     ExecutableCommand,
 };
 use ratatui::layout::Margin;
 
 use ratatui::widgets::{
-    List, ListDirection, Scrollbar, ScrollbarOrientation, ScrollbarState, Wrap,
+    List, ListDirection, Scrollbar, ScrollbarOrientation, ScrollbarState, Tabs, Wrap,
 };
 use ratatui::{
     layout::{Constraint, Direction, Layout},
@@ -19,12 +21,14 @@ use ratatui::{
     widgets::{Block, Borders, Paragraph},
     Frame,
 };
-use std::sync::mpsc::Sender;
-
-use crate::shared::RoutingTableEntry;
-use crate::{
-    channel_events::{ChannelEvent, Commands},
-    shared::Rx,
+use tokio::sync::mpsc::error::TryRecvError;
+
+use rnp2::presence::{Presence, PresenceState, TypingLimiter};
+use rnp2::rtt::RttEstimate;
+use rnp2::shared::RoutingTableEntry;
+use rnp2::{
+    channel_events::{ChannelEvent, Commands, DeliveryStatus, FileStatus},
+    shared::{Rx, Tx},
 };
 
 #[allow(clippy::upper_case_acronyms)]
@@ -37,15 +41,74 @@ struct TUI {
     chat_room: Vec<String>,
     exit: bool,
     receiver: Rx,
-    sender: Sender<ChannelEvent>,
+    sender: Tx,
     contacts: HashMap<SocketAddr, RoutingTableEntry>,
+    // id of a sent message => (line in the chat room, line without its status)
+    sent: HashMap<u64, (usize, String)>,
+    fingerprint: String,
+    // Fingerprints of the contacts messages are encrypted to
+    keys: HashMap<SocketAddr, String>,
+    nicknames: HashMap<SocketAddr, String>,
+    // Joined rooms in the order of their tabs, the first tab is the chat room
+    rooms: Vec<String>,
+    room_chats: HashMap<String, Vec<String>>,
+    tab: usize,
+    // (other end, id) of a file transfer => its line in the chat room
+    transfers: HashMap<(SocketAddr, u64), usize>,
+    presence: Presence,
+    presences: HashMap<SocketAddr, Presence>,
+    // Contacts typing to us => (nickname, when they last told us)
+    typing: HashMap<SocketAddr, (String, Instant)>,
+    // Contacts we told we are typing to them, so not every keystroke becomes a command
+    typing_sent: TypingLimiter,
+    // Round-trip times to our neighbours, by the address of their connection
+    rtts: HashMap<SocketAddr, RttEstimate>,
 }
 
+/// How long "is typing" is shown after the last indicator, they arrive every few seconds
+const TYPING_SHOWN: Duration = Duration::from_secs(5);
+
 fn string_to_socketaddr(ip: &str, port: &str) -> Option<SocketAddr> {
-    let addr = format!("{}:{}", ip, port);
-    match addr.parse::<SocketAddr>() {
-        Ok(socketaddr) => Some(socketaddr),
-        Err(_) => None,
+    // IPv6 literals may come with or without brackets
+    let ip = ip.trim_start_matches('[').trim_end_matches(']');
+    Some(SocketAddr::new(
+        ip.parse::<IpAddr>().ok()?,
+        port.parse().ok()?,
+    ))
+}
+
+/// Show the state of a file transfer on its line in the chat room
+fn transfer_line(tui: &mut TUI, addr: SocketAddr, id: u64, line: String) {
+    match tui.transfers.get(&(addr, id)) {
+        Some(index) => tui.chat_room[*index] = line,
+        None => {
+            tui.chat_room.push(line);
+            tui.transfers.insert((addr, id), tui.chat_room.len() - 1);
+        }
+    }
+}
+
+/// The node a message is being typed to, once the input got as far as the message itself
+fn typing_target(input: &str, nicknames: &HashMap<SocketAddr, String>) -> Option<SocketAddr> {
+    match command_to_event(input) {
+        Commands::Message(addr, msg) if !msg.is_empty() => Some(addr),
+        Commands::MessageByNick(nick, msg) if !msg.is_empty() => {
+            // Only a unique nickname tells who it is
+            let mut found = nicknames.iter().filter(|(_, name)| **name == nick);
+            match (found.next(), found.next()) {
+                (Some((addr, _)), None) => Some(*addr),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Room names start with a `#`, e.g. `#ops`
+fn room_name(word: &str) -> Option<String> {
+    match word.strip_prefix('#') {
+        Some(name) if !name.is_empty() => Some(word.to_string()),
+        _ => None,
     }
 }
 
@@ -56,7 +119,7 @@ fn command_to_event(cmd: &str) -> Commands {
         "quit" => Commands::Quit,
         "help" => Commands::Help,
         "contacts" => Commands::Contacts,
-        "broadcast" => Commands::Broadcast(format!("@everyone: {}", words[1..].join(" "))),
+        "broadcast" => Commands::Broadcast(words[1..].join(" ")),
         "setnick" => {
             if words.len() < 2 {
                 Commands::Unknown("Invalid number of arguments".to_string())
@@ -65,8 +128,42 @@ fn command_to_event(cmd: &str) -> Commands {
                 Commands::SetOwnNick(name)
             }
         }
+        "status" => match words.get(1).map(|word| word.parse::<PresenceState>()) {
+            Some(Ok(state)) => {
+                // Anything after the state is the status text
+                let text = words[2..].join(" ");
+                Commands::SetPresence(Presence {
+                    state,
+                    text: (!text.is_empty()).then_some(text),
+                })
+            }
+            Some(Err(e)) => Commands::Unknown(e),
+            None => Commands::Unknown("Invalid number of arguments".to_string()),
+        },
+        "join" | "leave" => match words.get(1).and_then(|word| room_name(word)) {
+            Some(room) if words[0] == "join" => Commands::JoinRoom(room),
+            Some(room) => Commands::LeaveRoom(room),
+            None => Commands::Unknown("Room names start with #".to_string()),
+        },
+        "say" => {
+            if words.len() < 3 {
+                Commands::Unknown("Invalid number of arguments".to_string())
+            } else {
+                match room_name(words[1]) {
+                    Some(room) => Commands::Say(room, words[2..].join(" ")),
+                    None => Commands::Unknown("Room names start with #".to_string()),
+                }
+            }
+        }
         "msg" => {
-            if words.len() < 4 {
+            // Either an address or a nickname, e.g. `msg alice hello`
+            let addr = match (words.get(1), words.get(2)) {
+                (Some(ip), Some(port)) => string_to_socketaddr(ip, port),
+                _ => None,
+            };
+            if addr.is_none() && words.len() >= 3 {
+                Commands::MessageByNick(words[1].to_string(), words[2..].join(" "))
+            } else if words.len() < 4 {
                 Commands::Unknown("Invalid number of arguments".to_string())
             } else {
                 let ip = words.get(1).unwrap_or(&"").to_string();
@@ -80,6 +177,30 @@ fn command_to_event(cmd: &str) -> Commands {
                 Commands::Message(addr, msg.to_string())
             }
         }
+        "sendfile" => {
+            if words.len() < 4 {
+                Commands::Unknown("Invalid number of arguments".to_string())
+            } else {
+                match string_to_socketaddr(words[1], words[2]) {
+                    // The path may contain spaces
+                    Some(addr) => Commands::SendFile(addr, PathBuf::from(words[3..].join(" "))),
+                    None => Commands::Unknown("Invalid IP or Port".to_string()),
+                }
+            }
+        }
+        "accept" | "decline" => match words.get(1).and_then(|word| word.parse().ok()) {
+            Some(id) if words[0] == "accept" => Commands::AcceptFile(id),
+            Some(id) => Commands::DeclineFile(id),
+            None => Commands::Unknown("Invalid transfer id".to_string()),
+        },
+        "trace" | "ping" => match (words.get(1), words.get(2)) {
+            (Some(ip), Some(port)) => match string_to_socketaddr(ip, port) {
+                Some(addr) if words[0] == "trace" => Commands::Trace(addr),
+                Some(addr) => Commands::Ping(addr),
+                None => Commands::Unknown("Invalid IP or Port".to_string()),
+            },
+            _ => Commands::Unknown("Invalid number of arguments".to_string()),
+        },
         "connect" => {
             if words.len() < 3 {
                 Commands::Unknown("Invalid number of arguments".to_string())
@@ -103,7 +224,7 @@ pub fn tui(receiver: Rx) -> Result<()> {
     terminal.clear()?;
 
     // Not used, just to satisfy the compiler
-    let (fake_tx, _) = std::sync::mpsc::channel::<ChannelEvent>();
+    let (fake_tx, _) = tokio::sync::mpsc::unbounded_channel();
 
     let mut tui = TUI {
         receiver,
@@ -116,6 +237,19 @@ pub fn tui(receiver: Rx) -> Result<()> {
         sender: fake_tx,
         exit: false,
         contacts: HashMap::new(),
+        sent: HashMap::new(),
+        fingerprint: String::new(),
+        keys: HashMap::new(),
+        nicknames: HashMap::new(),
+        rooms: Vec::new(),
+        room_chats: HashMap::new(),
+        tab: 0,
+        transfers: HashMap::new(),
+        presence: Presence::default(),
+        presences: HashMap::new(),
+        typing: HashMap::new(),
+        typing_sent: TypingLimiter::default(),
+        rtts: HashMap::new(),
     };
 
     // Create a timer that fires a tick every 3s
@@ -136,9 +270,15 @@ pub fn tui(receiver: Rx) -> Result<()> {
         }
 
         // Check for received messages
-        if let Ok(event) = tui.receiver.try_recv() {
+        let event = match tui.receiver.try_recv() {
+            Ok(event) => Some(event),
+            // The application is shutting down
+            Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => None,
+        };
+        if let Some(event) = event {
             match event {
-                ChannelEvent::Message(msg, addr) => {
+                ChannelEvent::Message(msg, addr, ..) => {
                     tui.chat_room.push(format!("{}: {}", addr, msg));
                 }
                 ChannelEvent::Command(cmd) => {
@@ -158,8 +298,101 @@ pub fn tui(receiver: Rx) -> Result<()> {
                 ChannelEvent::Leave(addr) => {
                     tui.chat_room.push(format!("User left @ {}", addr));
                 }
-                ChannelEvent::MessageToTUI(msg, name, addr) => {
-                    tui.chat_room.push(format!("{}@{}: {}", name, addr, msg));
+                ChannelEvent::MessageToTUI(msg, name, addr, encrypted) => {
+                    tui.typing.remove(&addr);
+                    tui.chat_room
+                        .push(format!("{}{}@{}: {}", lock(encrypted), name, addr, msg));
+                }
+                ChannelEvent::RoomMessage(room, msg, name, addr, encrypted) => {
+                    let line = format!("{}{}@{}: {}", lock(encrypted), name, addr, msg);
+                    room_chat(&mut tui, &room).push(line);
+                }
+                ChannelEvent::Broadcast(msg, name, addr) => {
+                    tui.chat_room
+                        .push(format!("{}@{} => @everyone: {}", name, addr, msg));
+                }
+                ChannelEvent::MessageSent(id, addr, msg, encrypted) => {
+                    let line = format!("{}You => {}: {}", lock(encrypted), addr, msg);
+                    tui.chat_room.push(format!("{} [pending]", line));
+                    tui.sent.insert(id, (tui.chat_room.len() - 1, line));
+                }
+                ChannelEvent::Delivery(id, status) => {
+                    let status = match status {
+                        DeliveryStatus::Delivered => "delivered",
+                        DeliveryStatus::Failed => "failed",
+                    };
+                    match tui.sent.remove(&id) {
+                        Some((index, line)) => {
+                            tui.chat_room[index] = format!("{} [{}]", line, status)
+                        }
+                        // A copy of a room message, those are only listed in the log
+                        None => tui.log.push(format!("Message {} {}", id, status)),
+                    }
+                }
+                ChannelEvent::Trace(id, addr, hops, round_trip) => {
+                    tui.chat_room.push(format!(
+                        "Route to {} (trace {}), {} ms round trip:",
+                        addr,
+                        id,
+                        round_trip.as_millis()
+                    ));
+                    for (i, hop) in hops.iter().enumerate() {
+                        tui.chat_room.push(format!(
+                            "  {}. {} +{} ms",
+                            i,
+                            hop.addr,
+                            hop.latency.as_millis()
+                        ));
+                    }
+                }
+                ChannelEvent::Ping(addr, result) => {
+                    tui.chat_room.push(match result {
+                        Ok(rtt) => {
+                            format!("Ping to {}: {:.1} ms", addr, rtt.as_secs_f64() * 1000.0)
+                        }
+                        Err(e) => format!("Ping to {} failed: {}", addr, e),
+                    });
+                }
+                ChannelEvent::Rtts(rtts) => {
+                    tui.rtts = rtts;
+                }
+                ChannelEvent::FileOffer(id, name, size, addr) => {
+                    tui.chat_room.push(format!(
+                        "{} offers {} ({} bytes), accept {} or decline {}",
+                        addr, name, size, id, id
+                    ));
+                }
+                ChannelEvent::FileProgress(id, name, bytes, size, addr) => {
+                    let percent = (bytes * 100).checked_div(size).unwrap_or(100);
+                    let line = format!(
+                        "File {} with {}: {}% ({}/{} bytes)",
+                        name, addr, percent, bytes, size
+                    );
+                    transfer_line(&mut tui, addr, id, line);
+                }
+                ChannelEvent::FileDone(id, name, status, addr) => {
+                    let status = match status {
+                        FileStatus::Sent => "sent".to_string(),
+                        FileStatus::Received(path) => format!("saved to {}", path.display()),
+                        FileStatus::Failed(reason) => format!("failed: {}", reason),
+                    };
+                    let line = format!("File {} with {}: {}", name, addr, status);
+                    transfer_line(&mut tui, addr, id, line);
+                    tui.transfers.remove(&(addr, id));
+                }
+                ChannelEvent::Nicknames(nicknames) => {
+                    tui.nicknames = nicknames;
+                }
+                ChannelEvent::Presences(presence, presences) => {
+                    tui.presence = presence;
+                    tui.presences = presences;
+                }
+                ChannelEvent::Typing(name, addr) => {
+                    tui.typing.insert(addr, (name, Instant::now()));
+                }
+                ChannelEvent::Keys(fingerprint, keys) => {
+                    tui.fingerprint = fingerprint;
+                    tui.keys = keys;
                 }
                 ChannelEvent::LogToTerminal(msg) => {
                     tui.log.push(msg);
@@ -178,8 +411,23 @@ pub fn tui(receiver: Rx) -> Result<()> {
                 // crossterm also emits key release and repeat events on Windows.
                 Event::Key(key_event) if key_event.kind == KeyEventKind::Press => {
                     match key_event.code {
+                        // Raw mode swallows SIGINT, treat Ctrl+C like the quit command
+                        KeyCode::Char('c')
+                            if key_event.modifiers.contains(KeyModifiers::CONTROL) =>
+                        {
+                            let _ = tui.sender.send(ChannelEvent::Command(Commands::Quit));
+                            tui.exit = true;
+                        }
                         KeyCode::Char(c) => {
                             tui.input.push(c);
+                            // Let the one we write to know, once per interval like the node sends them
+                            if let Some(addr) = typing_target(&tui.input, &tui.nicknames) {
+                                if tui.typing_sent.allow(addr, Instant::now()) {
+                                    let _ = tui
+                                        .sender
+                                        .send(ChannelEvent::Command(Commands::Typing(addr)));
+                                }
+                            }
                         }
                         KeyCode::Up => {
                             tui.input_history_index = tui.input_history_index.saturating_sub(1);
@@ -197,6 +445,10 @@ pub fn tui(receiver: Rx) -> Result<()> {
                                 .unwrap_or(&"".to_string())
                                 .to_string();
                         }
+                        KeyCode::Tab => {
+                            tui.tab = (tui.tab + 1) % (tui.rooms.len() + 1);
+                        }
+                        #[allow(clippy::collapsible_match)]
                         KeyCode::Left => {
                             if tui.log_index < tui.log.len() {
                                 tui.log_index = tui.log_index.saturating_add(1);
@@ -219,11 +471,29 @@ pub fn tui(receiver: Rx) -> Result<()> {
                                 Commands::SetOwnNick(ref name) => {
                                     tui.chat_room.push(format!("Set own nick to: {}", name));
                                 }
-                                Commands::Message(ref addr, ref message) => {
-                                    tui.chat_room.push(format!("You => {}: {}", addr, message));
+                                Commands::Trace(addr) => {
+                                    tui.chat_room.push(format!("Tracing route to {}", addr));
+                                }
+                                Commands::SetPresence(ref presence) => {
+                                    tui.chat_room
+                                        .push(format!("Set own status to: {}", presence));
+                                    tui.presence = presence.clone();
                                 }
                                 Commands::Broadcast(ref message) => {
-                                    tui.chat_room.push(format!("@everyone: {}", message));
+                                    tui.chat_room.push(format!("You => @everyone: {}", message));
+                                }
+                                Commands::JoinRoom(ref room) => {
+                                    room_chat(&mut tui, room);
+                                    tui.tab =
+                                        tui.rooms.iter().position(|r| r == room).unwrap_or(0) + 1;
+                                }
+                                Commands::LeaveRoom(ref room) => {
+                                    tui.rooms.retain(|r| r != room);
+                                    tui.room_chats.remove(room);
+                                    tui.tab = 0;
+                                }
+                                Commands::Say(ref room, ref message) => {
+                                    room_chat(&mut tui, room).push(format!("You: {}", message));
                                 }
                                 _ => {}
                             }
@@ -262,6 +532,22 @@ pub fn tui(receiver: Rx) -> Result<()> {
     Ok(())
 }
 
+/// Lines of the tab of `room`, the tab is opened if it doesn't exist yet
+fn room_chat<'a>(tui: &'a mut TUI, room: &str) -> &'a mut Vec<String> {
+    if !tui.rooms.iter().any(|r| r == room) {
+        tui.rooms.push(room.to_string());
+    }
+    tui.room_chats.entry(room.to_string()).or_default()
+}
+
+/// Shown in front of the chat lines of messages that were encrypted
+fn lock(encrypted: bool) -> &'static str {
+    match encrypted {
+        true => "🔒 ",
+        false => "",
+    }
+}
+
 fn help_cmd(tui: &mut TUI) {
     tui.log.push(
         "Available commands: \n\
@@ -270,9 +556,20 @@ fn help_cmd(tui: &mut TUI) {
         help => Get this message again\n\
         contacts => Retrieve routing table (Also in the right block)\n\
         msg <IP> <port> <message> => Send a message to somebody\n\
+        msg <nickname> <message> => Send a message to somebody by their nickname\n\
+        status <online|away|busy> [text] => Tell everyone whether you are around\n\
         connect <IP> <port> => Connect to a new peer\n\
-        broadcast <message> => Broadcast a message to all peers\n\
+        trace <IP> <port> => Show the path to somebody, with the latency of every hop\n\
+        ping <IP> <port> => Measure the round-trip time to somebody\n\
+        broadcast <message> => Send a message to every node in reach\n\
+        join <#room> => Join a room, it gets its own tab\n\
+        leave <#room> => Leave a room\n\
+        say <#room> <message> => Send a message to everyone in a room\n\
+        sendfile <IP> <port> <path> => Offer a file to somebody\n\
+        accept <id> => Accept a file offered to you\n\
+        decline <id> => Decline a file offered to you\n\
         setnick <name> => Set your own nickname\n\
+        Tab => Next chat tab\n\
         ↑ => Previous command\n\
         ↓ => Next command\n\
         ← => Go back in log\n\
@@ -333,33 +630,224 @@ fn draw_ui(frame: &mut Frame, tui: &TUI) -> Result<()> {
         &mut scrollbar_state,
     );
 
-    // Display the messages / join / leave
-    // Display the log
-    // Only showcase the last 10 logs
-    let mut chat: Vec<String> = tui.chat_room.clone();
+    // Display the messages / join / leave, every room has its own tab
+    let chat_layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(vec![Constraint::Length(3), Constraint::Min(0)])
+        .split(top_inner_layout[1]);
+    // Whoever told us recently that they are typing
+    let mut typing: Vec<&str> = tui
+        .typing
+        .values()
+        .filter(|(_, since)| since.elapsed() < TYPING_SHOWN)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    typing.sort();
+    let mut titles = match typing.is_empty() {
+        true => vec!["Chat".to_string()],
+        false => vec![format!("Chat - {} typing…", typing.join(", "))],
+    };
+    titles.extend(tui.rooms.iter().cloned());
+    frame.render_widget(
+        Tabs::new(titles)
+            .block(Block::new().borders(Borders::ALL))
+            .select(tui.tab),
+        chat_layout[0],
+    );
+
+    let mut chat: Vec<String> = match tui.tab {
+        0 => tui.chat_room.clone(),
+        tab => tui
+            .rooms
+            .get(tab - 1)
+            .and_then(|room| tui.room_chats.get(room))
+            .cloned()
+            .unwrap_or_default(),
+    };
     chat.reverse();
 
     frame.render_widget(
         List::new(chat)
-            .block(Block::new().borders(Borders::ALL).title("Chat"))
+            .block(Block::new().borders(Borders::ALL))
             .direction(ListDirection::BottomToTop),
-        top_inner_layout[1],
+        chat_layout[1],
     );
 
     // Display Routing Entries
     let mut rounting_entries =
-        "Node Addr: | Hops | Via Addr:\n =============================\n".to_string();
+        "Node Addr: | Nick | Status | Hops | Link RTT | Via Addr: | Key\n =============================\n"
+            .to_string();
     for (addr, entry) in tui.contacts.iter() {
-        let entry = format!("{:?} | {:?} | {:?} \n", addr, entry.hop_count, entry.next);
+        // A nickname taken by several nodes can't be used to message them
+        let nick = match tui.nicknames.get(addr) {
+            Some(nick) if tui.nicknames.values().filter(|n| *n == nick).count() > 1 => {
+                format!("{} (duplicate)", nick)
+            }
+            Some(nick) => nick.clone(),
+            None => "-".to_string(),
+        };
+        let status = match tui.presences.get(addr) {
+            Some(presence) => presence.to_string(),
+            None => "-".to_string(),
+        };
+        // Round-trip time to the neighbour the route goes through
+        let rtt = match tui.rtts.get(&entry.next) {
+            Some(rtt) => rtt.to_string(),
+            None => "-".to_string(),
+        };
+        // Compare the fingerprint with your contact before trusting the lock
+        let key = match tui.keys.get(addr) {
+            Some(fingerprint) => format!("🔒 {}", fingerprint),
+            None => "🔓".to_string(),
+        };
+        let entry = format!(
+            "{:?} | {} | {} | {:?} | {} | {:?} | {} \n",
+            addr, nick, status, entry.hop_count, rtt, entry.next, key
+        );
         rounting_entries.push_str(&entry);
     }
 
     frame.render_widget(
         Paragraph::new(rounting_entries)
-            .block(Block::new().borders(Borders::ALL).title("Routing Table"))
+            .block(Block::new().borders(Borders::ALL).title(format!(
+                "Routing Table - you are {} - your key: {}",
+                tui.presence, tui.fingerprint
+            )))
             .wrap(Wrap { trim: true }),
         top_inner_layout[2],
     );
 
     Ok(())
 }
+
+#[test]
+fn test_string_to_socketaddr() {
+    assert_eq!(
+        string_to_socketaddr("127.0.0.1", "6142"),
+        Some("127.0.0.1:6142".parse().unwrap())
+    );
+    assert_eq!(
+        string_to_socketaddr("::1", "6142"),
+        Some("[::1]:6142".parse().unwrap())
+    );
+    assert_eq!(
+        string_to_socketaddr("[fe80::1]", "6142"),
+        Some("[fe80::1]:6142".parse().unwrap())
+    );
+    assert_eq!(string_to_socketaddr("::1", "port"), None);
+    assert_eq!(string_to_socketaddr("localhost", "6142"), None);
+}
+
+#[test]
+fn test_room_commands() {
+    assert_eq!(
+        command_to_event("join #ops"),
+        Commands::JoinRoom("#ops".to_string())
+    );
+    assert_eq!(
+        command_to_event("leave #ops"),
+        Commands::LeaveRoom("#ops".to_string())
+    );
+    assert_eq!(
+        command_to_event("say #ops deploy is done"),
+        Commands::Say("#ops".to_string(), "deploy is done".to_string())
+    );
+    assert!(matches!(command_to_event("join ops"), Commands::Unknown(_)));
+    assert!(matches!(command_to_event("join #"), Commands::Unknown(_)));
+    assert!(matches!(command_to_event("say #ops"), Commands::Unknown(_)));
+}
+
+#[test]
+fn test_file_commands() {
+    assert_eq!(
+        command_to_event("sendfile 127.0.0.1 6143 logs/my run.log"),
+        Commands::SendFile(
+            "127.0.0.1:6143".parse().unwrap(),
+            PathBuf::from("logs/my run.log")
+        )
+    );
+    assert_eq!(command_to_event("accept 42"), Commands::AcceptFile(42));
+    assert_eq!(command_to_event("decline 42"), Commands::DeclineFile(42));
+    assert!(matches!(
+        command_to_event("sendfile 127.0.0.1 6143"),
+        Commands::Unknown(_)
+    ));
+    assert!(matches!(
+        command_to_event("accept it"),
+        Commands::Unknown(_)
+    ));
+}
+
+#[test]
+fn test_message_by_nickname() {
+    assert_eq!(
+        command_to_event("msg alice hello there"),
+        Commands::MessageByNick("alice".to_string(), "hello there".to_string())
+    );
+    assert_eq!(
+        command_to_event("msg 127.0.0.1 6143 hello"),
+        Commands::Message("127.0.0.1:6143".parse().unwrap(), "hello".to_string())
+    );
+    assert!(matches!(
+        command_to_event("msg alice"),
+        Commands::Unknown(_)
+    ));
+}
+
+#[test]
+fn test_presence_commands() {
+    assert_eq!(
+        command_to_event("status away back at 2"),
+        Commands::SetPresence(Presence {
+            state: PresenceState::Away,
+            text: Some("back at 2".to_string()),
+        })
+    );
+    assert_eq!(
+        command_to_event("status busy"),
+        Commands::SetPresence(Presence {
+            state: PresenceState::Busy,
+            text: None,
+        })
+    );
+    assert!(matches!(
+        command_to_event("status asleep"),
+        Commands::Unknown(_)
+    ));
+}
+
+#[test]
+fn test_typing_target() {
+    let alice = "127.0.0.1:6143".parse().unwrap();
+    let mut nicknames = HashMap::new();
+    nicknames.insert(alice, "alice".to_string());
+
+    assert_eq!(typing_target("msg alice h", &nicknames), Some(alice));
+    assert_eq!(
+        typing_target("msg 127.0.0.1 6143 h", &nicknames),
+        Some(alice)
+    );
+    // Nothing of the message typed yet
+    assert_eq!(typing_target("msg alice", &nicknames), None);
+    assert_eq!(typing_target("msg bob hi", &nicknames), None);
+    assert_eq!(typing_target("broadcast hi", &nicknames), None);
+
+    nicknames.insert("127.0.0.1:6144".parse().unwrap(), "alice".to_string());
+    assert_eq!(typing_target("msg alice hi", &nicknames), None);
+}
+
+#[test]
+fn test_trace_command() {
+    assert_eq!(
+        command_to_event("trace ::1 6143"),
+        Commands::Trace("[::1]:6143".parse().unwrap())
+    );
+    assert!(matches!(
+        command_to_event("trace 127.0.0.1"),
+        Commands::Unknown(_)
+    ));
+    assert_eq!(
+        command_to_event("ping 127.0.0.1 6143"),
+        Commands::Ping("127.0.0.1:6143".parse().unwrap())
+    );
+}